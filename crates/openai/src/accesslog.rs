@@ -0,0 +1,221 @@
+use base64::{engine::general_purpose, Engine};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+
+use crate::context::WORKER_DIR;
+use crate::homedir::home_dir;
+use crate::{generate_random_string, warn};
+
+tokio::task_local! {
+    /// Access record of the request served by the current task
+    static RECORD: Arc<Mutex<AccessRecord>>;
+}
+
+/// One line of the access log
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct AccessRecord {
+    /// Unix timestamp in milliseconds
+    pub timestamp: u128,
+    pub request_id: String,
+    pub method: String,
+    pub route: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub stream: bool,
+    pub toapi: bool,
+    /// Proxy or interface of the requesting client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// Arkose token generation time in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arkose_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_status: Option<u16>,
+    /// Time to the first response body byte in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttfb_ms: Option<u128>,
+    pub duration_ms: u128,
+}
+
+/// Run the request future with the access record in scope
+pub async fn scope<F: Future>(record: Arc<Mutex<AccessRecord>>, f: F) -> F::Output {
+    RECORD.scope(record, f).await
+}
+
+/// Update the access record of the current request, does nothing outside a request
+pub fn record(f: impl FnOnce(&mut AccessRecord)) {
+    let _ = RECORD.try_with(|record| f(&mut record.lock().expect("access record lock poisoned")));
+}
+
+/// Access log writer, the lines are written to a size rotated file by a background thread
+pub struct AccessLog {
+    tx: mpsc::Sender<String>,
+    /// Per-install secret the emails are hashed with
+    email_key: Option<Vec<u8>>,
+}
+
+impl AccessLog {
+    pub fn new(
+        path: PathBuf,
+        max_size: u64,
+        keep: usize,
+        hash_email: bool,
+    ) -> anyhow::Result<Self> {
+        let email_key = hash_email.then(email_key).transpose()?;
+        let mut file = RotatingFile::open(path, max_size, keep)?;
+        let (tx, rx) = mpsc::channel::<String>();
+        std::thread::spawn(move || {
+            for line in rx {
+                if let Err(err) = file.write_line(&line) {
+                    warn!("Access log write error: {err}")
+                }
+            }
+        });
+        Ok(Self { tx, email_key })
+    }
+
+    /// The email as written to the log, its keyed hash if the emails are hashed
+    pub fn mask_email(&self, email: String) -> String {
+        match self.email_key {
+            Some(ref key) => hash_email(key, &email),
+            None => email,
+        }
+    }
+
+    /// Write the record as a JSON line
+    pub fn write(&self, record: &AccessRecord) {
+        match serde_json::to_string(record) {
+            Ok(line) => {
+                let _ = self.tx.send(line);
+            }
+            Err(err) => warn!("Access log serialize error: {err}"),
+        }
+    }
+}
+
+/// Load the secret the emails are hashed with, generated on the first run
+fn email_key() -> anyhow::Result<Vec<u8>> {
+    let path = home_dir()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get home directory"))?
+        .join(WORKER_DIR)
+        .join("accesslog.key");
+    if let Ok(key) = std::fs::read_to_string(&path) {
+        if !key.trim().is_empty() {
+            return Ok(key.trim().as_bytes().to_vec());
+        }
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let key = generate_random_string(32);
+    std::fs::write(&path, &key)?;
+    Ok(key.into_bytes())
+}
+
+/// HMAC-SHA256 of the email, the hash can not be reversed without the secret
+fn hash_email(key: &[u8], email: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes a key of any size");
+    mac.update(email.as_bytes());
+    general_purpose::URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+/// Log file rotated to `<path>.1` .. `<path>.<keep>` once it exceeds the max size
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, keep: usize) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.keep > 0 {
+            for i in (1..self.keep).rev() {
+                let from = rotated(&self.path, i);
+                if from.exists() {
+                    std::fs::rename(from, rotated(&self.path, i + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{index}"));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("ninja-access-{}", std::process::id()));
+        let path = dir.join("access.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["aaaaaaaa", "bbbbbbbb", "cccccccc", "dddddddd"] {
+            file.write_line(line).unwrap();
+        }
+        let read = |p: PathBuf| std::fs::read_to_string(p).unwrap();
+        assert_eq!(read(path.clone()), "dddddddd\n");
+        assert_eq!(read(rotated(&path, 1)), "cccccccc\n");
+        assert_eq!(read(rotated(&path, 2)), "bbbbbbbb\n");
+        assert!(!rotated(&path, 3).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_hash_email() {
+        let email = "user@example.com";
+        let hash = hash_email(b"secret", email);
+        assert_eq!(hash, hash_email(b"secret", email));
+        assert_ne!(hash, hash_email(b"other", email));
+        assert!(!hash.contains(email));
+    }
+}
@@ -10,8 +10,9 @@ pub async fn get_blob(typed: Type, identifier: Option<String>) -> anyhow::Result
             struct Blob {
                 data: String,
             }
-            let resp = with_context!(arkose_client)
-                .post("https://chat.openai.com/backend-api/sentinel/arkose/dx")
+            let url = "https://chat.openai.com/backend-api/sentinel/arkose/dx";
+            let resp = with_context!(arkose_client_for, url)
+                .post(url)
                 .bearer_auth(identifier)
                 .send()
                 .await?
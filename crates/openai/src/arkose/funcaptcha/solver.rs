@@ -69,6 +69,11 @@ impl ArkoseSolver {
             limit,
         }
     }
+
+    /// Solver task endpoint
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
 }
 
 #[derive(Deserialize, Default, Debug)]
@@ -180,7 +185,8 @@ pub async fn submit_task(submit_task: SubmitSolver<'_>) -> anyhow::Result<Vec<i3
         }
     };
 
-    let resp = with_context!(arkose_client)
+    let client = with_context!(arkose_client_for, &submit_task.arkose_solver.endpoint);
+    let resp = client
         .post(&submit_task.arkose_solver.endpoint)
         .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
         .body(body)
@@ -27,6 +27,7 @@ use crate::gpt_model::GPTModel;
 use crate::now_duration;
 use crate::warn;
 use crate::with_context;
+use crate::{accesslog, metrics};
 pub use blob::get_blob;
 use error::ArkoseError;
 
@@ -402,7 +403,26 @@ impl ArkoseToken {
 
     /// Get ArkoseLabs token from context (Support ChatGPT, Platform, Auth)
     #[inline]
-    pub async fn new_from_context(mut ctx: ArkoseContext) -> anyhow::Result<Self> {
+    pub async fn new_from_context(ctx: ArkoseContext) -> anyhow::Result<Self> {
+        let typed = ctx.typed;
+        let solver = with_context!(arkose_solver)
+            .map(|s| format!("{:?}", s.solver).to_lowercase())
+            .unwrap_or_else(|| "none".to_owned());
+        let start = std::time::Instant::now();
+        let result = Self::generate(ctx).await;
+        metrics::arkose_token(
+            typed,
+            &solver,
+            result.as_ref().map_or(false, |t| t.success()),
+            start.elapsed(),
+        );
+        accesslog::record(|r| {
+            *r.arkose_ms.get_or_insert(0) += start.elapsed().as_millis();
+        });
+        result
+    }
+
+    async fn generate(mut ctx: ArkoseContext) -> anyhow::Result<Self> {
         // If enable gpt3 arkoselabs experiment
         if ctx.typed.eq(&Type::GPT3)
             && with_context!(arkose_gpt3_experiment)
@@ -440,7 +460,7 @@ impl ArkoseToken {
                 .arkose_token(arkose_token)
                 .client(ctx.client)
                 .build();
-            return Ok(valid_arkose_token(arkose_solver, solver_context).await);
+            return Ok(valid_arkose_token(arkose_solver.as_ref(), solver_context).await);
         }
 
         // If arkose solver is not empty, use bx
@@ -452,7 +472,7 @@ impl ArkoseToken {
                 .arkose_token(arkose_token)
                 .client(ctx.client)
                 .build();
-            return Ok(valid_arkose_token(arkose_solver, solver_context).await);
+            return Ok(valid_arkose_token(arkose_solver.as_ref(), solver_context).await);
         }
 
         Err(ArkoseError::NoSolverAvailable.into())
@@ -497,7 +517,7 @@ impl ArkoseToken {
             callback_data.push(format!("data[site]={}", typed.site_url()));
             let callback_query = callback_data.join("&");
 
-            let result = with_context!(arkose_client)
+            let result = with_context!(arkose_client_for, typed.origin_url())
                 .get(format!("{}/fc/a/?{callback_query}", typed.origin_url()))
                 .timeout(std::time::Duration::from_secs(5))
                 .send()
@@ -514,7 +534,7 @@ impl ArkoseToken {
 }
 
 async fn valid_arkose_token(
-    arkose_solver: Option<&'static ArkoseSolver>,
+    arkose_solver: Option<&ArkoseSolver>,
     ctx: ArkoseSolverContext,
 ) -> ArkoseToken {
     // If success, return token
@@ -537,7 +557,7 @@ async fn valid_arkose_token(
 }
 
 async fn submit_funcaptcha(
-    arkose_solver: Option<&'static ArkoseSolver>,
+    arkose_solver: Option<&ArkoseSolver>,
     ctx: &ArkoseSolverContext,
 ) -> ArkoseResult<ArkoseToken> {
     // Try get arkose solver
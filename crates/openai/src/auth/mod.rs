@@ -36,9 +36,9 @@ use self::provide::web::WebAuthProvider;
 use self::provide::{AuthProvider, AuthResult};
 
 const OPENAI_API_URL: &str = "https://api.openai.com";
-const OPENAI_OAUTH_URL: &str = "https://auth0.openai.com";
-const OPENAI_OAUTH_TOKEN_URL: &str = "https://auth0.openai.com/oauth/token";
-const OPENAI_OAUTH_REVOKE_URL: &str = "https://auth0.openai.com/oauth/revoke";
+pub(crate) const OPENAI_OAUTH_URL: &str = "https://auth0.openai.com";
+pub(crate) const OPENAI_OAUTH_TOKEN_URL: &str = "https://auth0.openai.com/oauth/token";
+pub(crate) const OPENAI_OAUTH_REVOKE_URL: &str = "https://auth0.openai.com/oauth/revoke";
 
 static EMAIL_REGEX: OnceCell<Regex> = OnceCell::const_new();
 
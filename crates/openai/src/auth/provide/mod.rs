@@ -114,7 +114,7 @@ impl<'a> RequestContext<'a> {
             Some(arkose_token) => ArkoseToken::from(arkose_token),
             None => arkose::ArkoseToken::new_from_context(
                 ArkoseContext::builder()
-                    .client(with_context!(arkose_client))
+                    .client(with_context!(arkose_client_for, Type::Auth.origin_url()).clone())
                     .typed(Type::Auth)
                     .build(),
             )
@@ -386,6 +386,7 @@ impl ChatGPT {
 
 pub struct ChatGPTBuilder {
     builder: reqwest::ClientBuilder,
+    client: Option<reqwest::Client>,
     api_prefix: String,
     access_token: RwLock<String>,
 }
@@ -401,6 +402,12 @@ impl ChatGPTBuilder {
         self
     }
 
+    /// Reuse an existing client, the client options of this builder are ignored.
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
     pub fn proxy(mut self, proxy: Proxy) -> Self {
         self.builder = self.builder.proxy(proxy);
         self
@@ -441,7 +448,9 @@ impl ChatGPTBuilder {
     pub fn build(self) -> ChatGPT {
         ChatGPT {
             api_prefix: self.api_prefix,
-            client: self.builder.build().expect("ClientBuilder::build()"),
+            client: self
+                .client
+                .unwrap_or_else(|| self.builder.build().expect("ClientBuilder::build()")),
             access_token: self.access_token,
         }
     }
@@ -456,6 +465,7 @@ impl ChatGPTBuilder {
 
         ChatGPTBuilder {
             builder,
+            client: None,
             api_prefix: format!("{URL_CHATGPT_API}/backend-api"),
             access_token: RwLock::default(),
         }
@@ -7,13 +7,39 @@ use typed_builder::TypedBuilder;
 #[derive(Serialize, TypedBuilder)]
 pub struct Content<'a> {
     content_type: ContentText,
-    parts: Vec<&'a str>,
+    parts: Vec<Part<'a>>,
 }
 
 #[derive(Serialize)]
-#[serde(rename_all = "lowercase")]
+#[serde(rename_all = "snake_case")]
 pub enum ContentText {
     Text,
+    MultimodalText,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Part<'a> {
+    Text(&'a str),
+    Image(ImageAssetPointer),
+}
+
+impl<'a> From<&'a str> for Part<'a> {
+    fn from(value: &'a str) -> Self {
+        Part::Text(value)
+    }
+}
+
+/// An uploaded image referenced by a `multimodal_text` message
+#[derive(Serialize, TypedBuilder)]
+pub struct ImageAssetPointer {
+    #[builder(default = "image_asset_pointer")]
+    content_type: &'static str,
+    /// `file-service://{file_id}`
+    asset_pointer: String,
+    size_bytes: usize,
+    width: u32,
+    height: u32,
 }
 
 #[derive(Serialize, TypedBuilder)]
@@ -24,8 +50,23 @@ pub struct Messages<'a> {
     metadata: Metadata,
 }
 
-#[derive(Serialize)]
-pub struct Metadata {}
+#[derive(Serialize, TypedBuilder, Default)]
+pub struct Metadata {
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>,
+}
+
+#[derive(Serialize, TypedBuilder)]
+pub struct Attachment {
+    id: String,
+    name: String,
+    size: usize,
+    #[serde(rename = "mimeType")]
+    mime_type: String,
+    width: u32,
+    height: u32,
+}
 
 #[derive(Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -124,10 +165,10 @@ impl<'a> From<PostNextConvoRequest<'a>> for PostConvoRequest<'a> {
                 .content(
                     Content::builder()
                         .content_type(ContentText::Text)
-                        .parts(vec![value.prompt])
+                        .parts(vec![value.prompt.into()])
                         .build(),
                 )
-                .metadata(Metadata {})
+                .metadata(Metadata::default())
                 .build()])
             .model(value.model)
             .conversation_id(value.conversation_id)
@@ -161,10 +202,10 @@ impl<'a> From<PostVaraintConvoRequest<'a>> for PostConvoRequest<'a> {
                 .content(
                     Content::builder()
                         .content_type(ContentText::Text)
-                        .parts(vec![value.prompt])
+                        .parts(vec![value.prompt.into()])
                         .build(),
                 )
-                .metadata(Metadata {})
+                .metadata(Metadata::default())
                 .build()])
             .model(value.model)
             .arkose_token(value.arkose_token)
@@ -0,0 +1,257 @@
+use crate::homedir::home_dir;
+use crate::{info, now_duration, upstream};
+use native_db::*;
+use native_model::{native_model, Model};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+/// Prefix of the ninja issued api keys
+pub const API_KEY_PREFIX: &str = "ninja-";
+
+static DATABASE_BUILDER: OnceLock<DatabaseBuilder> = OnceLock::new();
+
+/// Api key issued by the admin API
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+#[native_model(id = 1, version = 1)]
+#[native_db]
+pub struct ApiKey {
+    #[primary_key]
+    pub key: String,
+    /// Model name patterns the key may use, `*` matches any characters, empty allows any model
+    pub models: Vec<String>,
+    /// Requests allowed per window
+    pub requests: Option<u64>,
+    /// Tokens allowed per window
+    pub tokens: Option<u64>,
+    /// Quota window in seconds
+    pub window: u64,
+    /// Expiry unix timestamp
+    pub expires: Option<i64>,
+    pub note: Option<String>,
+    pub created: i64,
+}
+
+impl ApiKey {
+    /// Generate a new random key
+    pub fn generate() -> String {
+        format!(
+            "{API_KEY_PREFIX}{}",
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 48)
+        )
+    }
+
+    /// Check if the key may use the model
+    pub fn allows(&self, model: &str) -> bool {
+        self.models.is_empty() || self.models.iter().any(|p| upstream::glob_match(p, model))
+    }
+
+    /// Check if the key has expired
+    pub fn is_expired(&self) -> anyhow::Result<bool> {
+        let now = now_duration()?.as_secs() as i64;
+        Ok(self.expires.map_or(false, |expires| now > expires))
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Usage {
+    // Start of the current window
+    start: u64,
+    requests: u64,
+    tokens: u64,
+}
+
+impl Usage {
+    /// Count a request of the key if the quota of the window is not used up
+    fn take(&mut self, key: &ApiKey, now: u64) -> QuotaStatus {
+        if now >= self.start + key.window {
+            *self = Usage {
+                start: now,
+                ..Default::default()
+            };
+        }
+
+        let allowed = !(key.requests.map_or(false, |max| self.requests >= max)
+            || key.tokens.map_or(false, |max| self.tokens >= max));
+        if allowed {
+            self.requests += 1;
+        }
+        QuotaStatus {
+            allowed,
+            requests: key
+                .requests
+                .map(|max| (max, max.saturating_sub(self.requests))),
+            tokens: key.tokens.map(|max| (max, max.saturating_sub(self.tokens))),
+            reset: (self.start + key.window).saturating_sub(now),
+        }
+    }
+}
+
+/// Quota state of a key after counting a request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuotaStatus {
+    /// The request is counted
+    pub allowed: bool,
+    /// Requests allowed and left in the window
+    pub requests: Option<(u64, u64)>,
+    /// Tokens allowed and left in the window
+    pub tokens: Option<(u64, u64)>,
+    /// Seconds until the window resets
+    pub reset: u64,
+}
+
+/// Api key state reported by the admin API
+#[derive(Serialize)]
+pub struct ApiKeyStatus {
+    #[serde(flatten)]
+    pub key: ApiKey,
+    pub used_requests: u64,
+    pub used_tokens: u64,
+}
+
+/// Api keys and their usage in the current quota window
+pub struct ApiKeyStore {
+    db: Arc<Database<'static>>,
+    keys: RwLock<HashMap<String, ApiKey>>,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl ApiKeyStore {
+    pub(super) fn new() -> anyhow::Result<Self> {
+        let builder = DATABASE_BUILDER.get_or_init(|| {
+            let mut builder = DatabaseBuilder::new();
+            builder.define::<ApiKey>().expect("define table failed");
+            builder
+        });
+
+        let db = builder.create(
+            home_dir()
+                .ok_or_else(|| anyhow::anyhow!("Failed to get home directory"))?
+                .join(super::WORKER_DIR)
+                .join("apikey.db"),
+        )?;
+
+        let keys = {
+            let r = db.r_transaction()?;
+            let scan = r.scan().primary::<ApiKey>()?;
+            scan.all()
+                .map(|v| (v.key.clone(), v))
+                .collect::<HashMap<_, _>>()
+        };
+        info!("Api key store loaded {} keys", keys.len());
+
+        Ok(Self {
+            db: Arc::new(db),
+            keys: RwLock::new(keys),
+            usage: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Get the key
+    pub fn get(&self, key: &str) -> Option<ApiKey> {
+        let keys = self.keys.read().expect("api key store lock poisoned");
+        keys.get(key).cloned()
+    }
+
+    /// Add or replace the key
+    pub fn insert(&self, key: ApiKey) -> anyhow::Result<()> {
+        let rw = self.db.rw_transaction()?;
+        rw.insert(key.clone())?;
+        rw.commit()?;
+
+        let mut keys = self.keys.write().expect("api key store lock poisoned");
+        keys.insert(key.key.clone(), key);
+        Ok(())
+    }
+
+    /// Remove the key, returns false if it does not exist
+    pub fn remove(&self, key: &str) -> anyhow::Result<bool> {
+        let rw = self.db.rw_transaction()?;
+        let value: Option<ApiKey> = rw.get().primary(key.to_owned())?;
+        if let Some(value) = value {
+            rw.remove(value)?;
+            rw.commit()?;
+        }
+
+        self.usage
+            .lock()
+            .expect("api key store lock poisoned")
+            .remove(key);
+        let mut keys = self.keys.write().expect("api key store lock poisoned");
+        Ok(keys.remove(key).is_some())
+    }
+
+    /// List the keys state
+    pub fn status(&self) -> Vec<ApiKeyStatus> {
+        let keys = self.keys.read().expect("api key store lock poisoned");
+        let usage = self.usage.lock().expect("api key store lock poisoned");
+        let now = now_duration().map_or(0, |d| d.as_secs());
+        keys.values()
+            .map(|key| {
+                let used = usage
+                    .get(&key.key)
+                    .filter(|u| now < u.start + key.window)
+                    .copied()
+                    .unwrap_or_default();
+                ApiKeyStatus {
+                    key: key.clone(),
+                    used_requests: used.requests,
+                    used_tokens: used.tokens,
+                }
+            })
+            .collect()
+    }
+
+    /// Count a request of the key, not allowed if the quota of the window is used up
+    pub fn acquire(&self, key: &ApiKey) -> anyhow::Result<QuotaStatus> {
+        let now = now_duration()?.as_secs();
+        let mut usage = self.usage.lock().expect("api key store lock poisoned");
+        Ok(usage.entry(key.key.clone()).or_default().take(key, now))
+    }
+
+    /// Count the tokens used by a request of the key
+    pub fn consume(&self, key: &str, tokens: u64) {
+        let mut usage = self.usage.lock().expect("api key store lock poisoned");
+        if let Some(used) = usage.get_mut(key) {
+            used.tokens += tokens;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_usage_take() {
+        let key = ApiKey {
+            key: ApiKey::generate(),
+            models: vec![],
+            requests: Some(2),
+            tokens: Some(100),
+            window: 60,
+            expires: None,
+            note: None,
+            created: 0,
+        };
+        let mut usage = Usage::default();
+        let status = usage.take(&key, 1000);
+        assert!(status.allowed);
+        assert_eq!(status.requests, Some((2, 1)));
+        assert_eq!(status.reset, 60);
+
+        let status = usage.take(&key, 1030);
+        assert_eq!(status.requests, Some((2, 0)));
+        let status = usage.take(&key, 1040);
+        assert!(!status.allowed);
+        assert_eq!(status.reset, 20);
+
+        // A used up token quota rejects as well, until the window resets
+        usage.tokens = 100;
+        assert!(!usage.take(&key, 1059).allowed);
+        let status = usage.take(&key, 1060);
+        assert!(status.allowed);
+        assert_eq!(status.tokens, Some((100, 100)));
+    }
+}
@@ -1,4 +1,5 @@
-use crate::{arkose::funcaptcha::solver::ArkoseSolver, proxy};
+use super::clearance::HttpSolver;
+use crate::{arkose::funcaptcha::solver::ArkoseSolver, proxy, upstream};
 use reqwest::impersonate::Impersonate;
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
@@ -24,6 +25,38 @@ pub struct Args {
     #[builder(default = false)]
     pub(crate) fastest_dns: bool,
 
+    /// Re-benchmark the fastest DNS resolver interval (seconds)
+    #[builder(default)]
+    pub(crate) fastest_dns_interval: Option<u64>,
+
+    /// Upstream DNS servers, `[udp|tcp|tls|https]://<ip>[:port][#<server name>]`
+    #[builder(default)]
+    pub(crate) dns_servers: Vec<String>,
+
+    /// Static DNS host overrides, `<host>=<ip>`
+    #[builder(default)]
+    pub(crate) dns_hosts: Vec<String>,
+
+    /// DNS servers of the host patterns, `<host|*.domain>=<server>`
+    #[builder(default)]
+    pub(crate) dns_rules: Vec<String>,
+
+    /// DNS cache size
+    #[builder(default)]
+    pub(crate) dns_cache_size: Option<usize>,
+
+    /// DNS cache minimum ttl (seconds)
+    #[builder(default)]
+    pub(crate) dns_min_ttl: Option<u64>,
+
+    /// DNS cache maximum ttl (seconds)
+    #[builder(default)]
+    pub(crate) dns_max_ttl: Option<u64>,
+
+    /// DNS cache ttl of failed lookups (seconds)
+    #[builder(default)]
+    pub(crate) dns_negative_ttl: Option<u64>,
+
     /// Server/Client TCP keepalive (second)
     #[builder(setter(into), default = 75)]
     pub(crate) tcp_keepalive: usize,
@@ -52,6 +85,46 @@ pub struct Args {
     #[builder(setter(into), default)]
     pub(crate) proxies: Vec<proxy::Proxy>,
 
+    /// Client pool selection strategy (round-robin/weighted/least-in-flight/latency)
+    #[builder(setter(into), default = "round-robin".to_string())]
+    pub(crate) proxy_strategy: String,
+
+    /// Client pool weights, `<proxy>=<weight>`
+    #[builder(setter(into), default)]
+    pub(crate) proxy_weights: Vec<String>,
+
+    /// Url the client pool clients are probed with
+    #[builder(setter(into), default)]
+    pub(crate) proxy_probe_url: Option<String>,
+
+    /// Client pool probe interval (second)
+    #[builder(setter(into), default = 60)]
+    pub(crate) proxy_probe_interval: u64,
+
+    /// Pin each account to a pool client, its proxy, impersonation profile and IPv6
+    #[builder(default = false)]
+    pub(crate) proxy_affinity: bool,
+
+    /// Named proxy groups of the proxy rules
+    #[builder(setter(into), default)]
+    pub(crate) proxy_groups: Vec<proxy::ProxyGroup>,
+
+    /// Proxy rules, `host:<glob>=group`, `path:<prefix>=group` or `model:<glob>=group`
+    #[builder(setter(into), default)]
+    pub(crate) proxy_rules: Vec<String>,
+
+    /// Retries of idempotent and conversation requests through the next pool client
+    #[builder(setter(into), default = 0)]
+    pub(crate) retry: usize,
+
+    /// Consecutive failures after which a pool client is taken out of rotation
+    #[builder(setter(into), default = 0)]
+    pub(crate) breaker_threshold: u32,
+
+    /// Probe interval of a pool client taken out of rotation (second)
+    #[builder(setter(into), default = 30)]
+    pub(crate) breaker_probe_interval: u64,
+
     /// Random User-Agent
     #[builder(setter(into), default = Some(vec![Impersonate::OkHttp4_9]))]
     pub(crate) impersonate_uas: Option<Vec<Impersonate>>,
@@ -66,11 +139,11 @@ pub struct Args {
 
     /// Visitor email whitelist
     #[builder(setter(into), default)]
-    pub(super) visitor_email_whitelist: Option<Vec<String>>,
+    pub(crate) visitor_email_whitelist: Option<Vec<String>>,
 
     /// Login auth key
     #[builder(setter(into), default)]
-    pub(super) auth_key: Option<String>,
+    pub(crate) auth_key: Option<String>,
 
     /// Enable webui
     #[builder(setter(into), default = false)]
@@ -92,6 +165,22 @@ pub struct Args {
     #[builder(setter(into), default)]
     pub(crate) cf_secret_key: Option<String>,
 
+    /// Cloudflare challenge solver endpoint, speaking the FlareSolverr api
+    #[builder(setter(into), default)]
+    pub(crate) cf_solver_endpoint: Option<String>,
+
+    /// Cloudflare challenge solver timeout (seconds)
+    #[builder(default)]
+    pub(crate) cf_solver_timeout: Option<u64>,
+
+    /// Cloudflare challenge solver of the endpoint
+    #[builder(setter(into), default)]
+    pub(crate) cf_solver: Option<HttpSolver>,
+
+    /// Cloudflare clearance cache expiration (seconds)
+    #[builder(default)]
+    pub(crate) cf_clearance_ttl: Option<u64>,
+
     /// Arkose endpoint
     #[builder(setter(into), default)]
     pub(crate) arkose_endpoint: Option<String>,
@@ -120,6 +209,70 @@ pub struct Args {
     #[builder(setter(into), default)]
     pub(crate) arkose_solver_image_dir: Option<PathBuf>,
 
+    /// Enable conversation reuse for the chat completions API
+    #[builder(setter(into), default = false)]
+    pub(crate) conv_reuse: bool,
+
+    /// Keep the chat completions API conversations in the ChatGPT history
+    #[builder(setter(into), default = false)]
+    pub(crate) conv_history: bool,
+
+    /// Conversation reuse store strategy
+    #[builder(setter(into), default = "mem".to_string())]
+    pub(crate) conv_store: String,
+
+    /// Conversation reuse expired (second)
+    #[builder(setter(into), default = 3600)]
+    pub(crate) conv_ttl: u32,
+
+    /// Enable the ChatGPT account pool
+    #[builder(setter(into), default = false)]
+    pub(crate) pool_enable: bool,
+
+    /// Account pool selection strategy
+    #[builder(setter(into), default = "round-robin".to_string())]
+    pub(crate) pool_strategy: String,
+
+    /// Account pool quarantine cool-down (second)
+    #[builder(setter(into), default = 300)]
+    pub(crate) pool_cooldown: u32,
+
+    /// Enable the GPT-4 message cap
+    #[builder(setter(into), default = false)]
+    pub(crate) gpt4_cap: bool,
+
+    /// GPT-4 message cap wait for a free slot (second)
+    #[builder(setter(into), default = 0)]
+    pub(crate) gpt4_cap_wait: u32,
+
+    /// Downgrade capped GPT-4 messages to GPT-3.5
+    #[builder(setter(into), default = false)]
+    pub(crate) gpt4_cap_downgrade: bool,
+
+    /// Access log file path
+    #[builder(setter(into), default)]
+    pub(crate) access_log: Option<PathBuf>,
+
+    /// Access log size before it is rotated (MiB)
+    #[builder(setter(into), default = 100)]
+    pub(crate) access_log_max_size: u64,
+
+    /// Rotated access log files to keep
+    #[builder(setter(into), default = 5)]
+    pub(crate) access_log_keep: usize,
+
+    /// Hash the account email in the access log
+    #[builder(setter(into), default = false)]
+    pub(crate) access_log_hash_email: bool,
+
+    /// Arkose types the readiness check requires HAR files for
+    #[builder(setter(into), default)]
+    pub(crate) readyz_har: Vec<String>,
+
+    /// Upstream backends of the `/v1` API routed by model name
+    #[builder(setter(into), default)]
+    pub(crate) upstreams: Vec<upstream::Upstream>,
+
     /// Enable Tokenbucket
     #[cfg(feature = "limit")]
     #[builder(setter(into), default = false)]
@@ -130,6 +283,11 @@ pub struct Args {
     #[builder(setter(into), default = "mem".to_string())]
     pub(crate) tb_strategy: String,
 
+    /// Tokenbucket redis url
+    #[cfg(feature = "limit")]
+    #[builder(setter(into), default)]
+    pub(crate) tb_redis_url: Option<String>,
+
     /// Tokenbucket capacity
     #[cfg(feature = "limit")]
     #[builder(setter(into), default = 60)]
@@ -145,6 +303,26 @@ pub struct Args {
     #[builder(setter(into), default = 86400)]
     pub(crate) tb_expired: u32,
 
+    /// Tokenbucket limit keys (ip/email/bearer/header:<name>)
+    #[cfg(feature = "limit")]
+    #[builder(setter(into), default = vec!["ip".to_owned()])]
+    pub(crate) tb_keys: Vec<String>,
+
+    /// Tokenbucket limit keys of the path prefixes, e.g. /v1=bearer,ip
+    #[cfg(feature = "limit")]
+    #[builder(setter(into), default)]
+    pub(crate) tb_routes: Vec<String>,
+
+    /// Tokenbucket trusted proxies, the forwarded client ip headers are read from
+    #[cfg(feature = "limit")]
+    #[builder(setter(into), default)]
+    pub(crate) tb_trusted_proxies: Vec<cidr::IpCidr>,
+
+    /// Tokenbucket of each IPv6 derived for an account or api key
+    #[cfg(feature = "limit")]
+    #[builder(default = false)]
+    pub(crate) tb_egress: bool,
+
     /// Preauth MITM server bind address
     #[cfg(feature = "preauth")]
     #[builder(setter(into), default)]
@@ -165,3 +343,49 @@ pub struct Args {
     #[builder(setter(into), default)]
     pub(crate) pkey: PathBuf,
 }
+
+impl Args {
+    /// Current config with the reloadable settings taken from the new config,
+    /// every other setting keeps its startup value
+    pub(crate) fn reloaded(&self, new: &Args) -> Args {
+        let mut args = self.clone();
+        // The admin routes and the api key store are built at startup, only the key itself can change
+        if self.auth_key.is_some() == new.auth_key.is_some() {
+            args.auth_key = new.auth_key.clone();
+        }
+        args.visitor_email_whitelist = new.visitor_email_whitelist.clone();
+        args.enable_direct = new.enable_direct;
+        args.proxies = new.proxies.clone();
+        args.breaker_threshold = new.breaker_threshold;
+        args.proxy_strategy = new.proxy_strategy.clone();
+        args.proxy_weights = new.proxy_weights.clone();
+        args.proxy_probe_url = new.proxy_probe_url.clone();
+        args.proxy_probe_interval = new.proxy_probe_interval;
+        args.proxy_affinity = new.proxy_affinity;
+        args.proxy_groups = new.proxy_groups.clone();
+        args.proxy_rules = new.proxy_rules.clone();
+        args.breaker_probe_interval = new.breaker_probe_interval;
+        args.impersonate_uas = new.impersonate_uas.clone();
+        args.arkose_solver = new.arkose_solver.clone();
+        args.cf_site_key = new.cf_site_key.clone();
+        args.cf_secret_key = new.cf_secret_key.clone();
+
+        #[cfg(feature = "limit")]
+        {
+            // The redb bucket database stays open for the process lifetime
+            if self.tb_strategy.ne("redb") && new.tb_strategy.ne("redb") {
+                args.tb_enable = new.tb_enable;
+                args.tb_strategy = new.tb_strategy.clone();
+                args.tb_redis_url = new.tb_redis_url.clone();
+                args.tb_capacity = new.tb_capacity;
+                args.tb_fill_rate = new.tb_fill_rate;
+                args.tb_expired = new.tb_expired;
+            }
+            args.tb_keys = new.tb_keys.clone();
+            args.tb_routes = new.tb_routes.clone();
+            args.tb_trusted_proxies = new.tb_trusted_proxies.clone();
+            args.tb_egress = new.tb_egress;
+        }
+        args
+    }
+}
@@ -198,6 +198,14 @@ fn get_har_path(_type: &Type) -> anyhow::Result<HarPath> {
         .ok_or_else(|| anyhow!("Failed to get har pool"))
 }
 
+/// Number of HAR files in the pool of the type
+pub fn har_count(_type: &Type) -> usize {
+    HAR.get()
+        .and_then(|s| s.read().ok())
+        .and_then(|lock| lock.get(_type).map(|h| h.pool.1.len()))
+        .unwrap_or(0)
+}
+
 // valid har data
 #[inline]
 pub fn valid(s: &[u8]) -> anyhow::Result<RequestEntry> {
@@ -71,6 +71,20 @@ impl ArkoseVersionContext<'_> {
         None
     }
 
+    /// Types the latest version has not been fetched for
+    pub fn missing_versions(&self) -> Vec<Type> {
+        [
+            Type::Auth,
+            Type::GPT4,
+            Type::GPT3,
+            Type::Platform,
+            Type::SignUp,
+        ]
+        .into_iter()
+        .filter(|t| self.version(*t).is_none())
+        .collect()
+    }
+
     /// Run a periodic task to upgrade the arkose version
     pub async fn periodic_upgrade(&self) {
         info!("Arkose Periodic task is running");
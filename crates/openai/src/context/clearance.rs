@@ -0,0 +1,385 @@
+use crate::client::ClientProfile;
+use crate::constant::CF_CLEARANCE;
+use crate::{debug, now_duration};
+use moka::sync::Cache;
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::impersonate::Impersonate;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use url::Url;
+
+const DEFAULT_MAX_CAPACITY: u64 = 10_000;
+/// Chrome versions with an impersonation profile
+const CHROME_PROFILES: [u32; 15] = [
+    99, 100, 101, 104, 105, 106, 107, 108, 109, 114, 116, 117, 118, 119, 120,
+];
+
+/// A solved Cloudflare challenge, the cookie only passes with the user agent it was solved with
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clearance {
+    pub cf_clearance: String,
+    pub user_agent: Option<String>,
+    /// Unix timestamp the cookie expires at
+    pub expires: Option<u64>,
+}
+
+impl Clearance {
+    fn expired(&self) -> bool {
+        match (self.expires, now_duration()) {
+            (Some(expires), Ok(now)) => expires <= now.as_secs(),
+            _ => false,
+        }
+    }
+
+    /// Impersonation profile of the browser the challenge was solved with, the cookie is bound
+    /// to its TLS fingerprint. None unless the solver reported a Chrome user agent
+    pub fn impersonate(&self) -> Option<Impersonate> {
+        let version = chrome_profile(self.user_agent.as_deref()?)?;
+        Impersonate::from_str(&format!("chrome{version}")).ok()
+    }
+
+    /// Attach the cookie and the user agent to the request headers, replacing the cf_clearance of the caller
+    pub fn apply(&self, headers: &mut HeaderMap) -> anyhow::Result<()> {
+        let cookie = headers
+            .get(header::COOKIE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let cookie = with_cookie(cookie, CF_CLEARANCE, &self.cf_clearance);
+        headers.insert(header::COOKIE, HeaderValue::from_str(&cookie)?);
+        if let Some(user_agent) = self.user_agent.as_deref() {
+            headers.insert(header::USER_AGENT, HeaderValue::from_str(user_agent)?);
+        }
+        Ok(())
+    }
+}
+
+/// Closest Chrome impersonation profile of the user agent, not newer than its version
+fn chrome_profile(user_agent: &str) -> Option<u32> {
+    let (_, version) = user_agent.split_once("Chrome/")?;
+    let major = version.split('.').next()?.parse::<u32>().ok()?;
+    let profile = CHROME_PROFILES
+        .iter()
+        .rev()
+        .find(|v| **v <= major)
+        .unwrap_or(&CHROME_PROFILES[0]);
+    Some(*profile)
+}
+
+/// Cookie header with the cookie set to the value
+fn with_cookie(cookie: &str, name: &str, value: &str) -> String {
+    cookie
+        .split(';')
+        .map(str::trim)
+        .filter(|c| {
+            !c.is_empty()
+                && c.split_once('=')
+                    .map_or(true, |(n, _)| !n.trim().eq_ignore_ascii_case(name))
+        })
+        .map(ToOwned::to_owned)
+        .chain(std::iter::once(format!("{name}={value}")))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// A cf_clearance is bound to the egress address and the TLS fingerprint of the client
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct ClearanceKey {
+    egress: String,
+    impersonate: String,
+}
+
+impl ClearanceKey {
+    /// Key of the pool client, none for a client bound to a local address since the solver
+    /// can not send from it
+    pub fn of(profile: &ClientProfile) -> Option<Self> {
+        let egress = match (&profile.proxy, profile.bind) {
+            (Some(proxy), _) => proxy.to_string(),
+            (None, None) => "direct".to_owned(),
+            (None, Some(_)) => return None,
+        };
+        Some(Self {
+            egress,
+            impersonate: format!("{:?}", profile.impersonate),
+        })
+    }
+}
+
+/// Solves the Cloudflare challenge of a url
+#[trait_variant::make(ClearanceSolver: Send)]
+pub trait LocalClearanceSolver {
+    /// Solve the challenge of the url, through the proxy the challenged request was sent through
+    async fn solve(&self, url: &str, proxy: Option<&Url>) -> anyhow::Result<Clearance>;
+}
+
+/// Solver service speaking the FlareSolverr api, e.g. `http://127.0.0.1:8191/v1`
+#[derive(Clone)]
+pub struct HttpSolver {
+    endpoint: String,
+    timeout: Duration,
+    client: reqwest::Client,
+}
+
+impl HttpSolver {
+    pub fn new(endpoint: String, timeout: u64) -> anyhow::Result<Self> {
+        let timeout = Duration::from_secs(timeout);
+        Ok(Self {
+            endpoint,
+            timeout,
+            // The solver browser takes up to the timeout
+            client: reqwest::Client::builder()
+                .timeout(timeout + Duration::from_secs(10))
+                .build()?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SolveRequest<'a> {
+    cmd: &'static str,
+    url: &'a str,
+    max_timeout: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proxy: Option<SolveProxy>,
+}
+
+#[derive(Serialize)]
+struct SolveProxy {
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+impl From<&Url> for SolveProxy {
+    fn from(proxy: &Url) -> Self {
+        // The credentials are passed apart from the url
+        let mut url = proxy.clone();
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+        Self {
+            url: url.to_string(),
+            username: (!proxy.username().is_empty()).then(|| proxy.username().to_owned()),
+            password: proxy.password().map(ToOwned::to_owned),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SolveResponse {
+    status: String,
+    #[serde(default)]
+    message: String,
+    solution: Option<Solution>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Solution {
+    #[serde(default)]
+    cookies: Vec<SolutionCookie>,
+    user_agent: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SolutionCookie {
+    name: String,
+    value: String,
+    expires: Option<f64>,
+}
+
+impl ClearanceSolver for HttpSolver {
+    async fn solve(&self, url: &str, proxy: Option<&Url>) -> anyhow::Result<Clearance> {
+        let body = SolveRequest {
+            cmd: "request.get",
+            url,
+            max_timeout: self.timeout.as_millis(),
+            proxy: proxy.map(SolveProxy::from),
+        };
+        let resp = self
+            .client
+            .post(&self.endpoint)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<SolveResponse>()
+            .await?;
+
+        let solution = match (resp.status.as_str(), resp.solution) {
+            ("ok", Some(solution)) => solution,
+            _ => anyhow::bail!("solver status {}: {}", resp.status, resp.message),
+        };
+        let cookie = solution
+            .cookies
+            .into_iter()
+            .find(|c| c.name.eq(CF_CLEARANCE))
+            .ok_or_else(|| anyhow::anyhow!("solver returned no {CF_CLEARANCE} cookie"))?;
+        Ok(Clearance {
+            cf_clearance: cookie.value,
+            user_agent: solution.user_agent,
+            // Session cookies have no positive expiry
+            expires: cookie.expires.filter(|e| *e > 0.0).map(|e| e as u64),
+        })
+    }
+}
+
+/// The cf_clearance cookies by egress proxy and impersonation profile
+pub struct ClearanceStore<S = HttpSolver> {
+    solver: S,
+    cache: Cache<ClearanceKey, Clearance>,
+    /// A challenge of a key is solved once for the concurrent requests
+    locks: Cache<ClearanceKey, Arc<Mutex<()>>>,
+}
+
+impl<S: ClearanceSolver + Sync> ClearanceStore<S> {
+    pub fn new(solver: S, ttl: u64) -> Self {
+        Self {
+            solver,
+            cache: Cache::builder()
+                .max_capacity(DEFAULT_MAX_CAPACITY)
+                .time_to_live(Duration::from_secs(ttl))
+                .build(),
+            locks: Cache::builder()
+                .max_capacity(DEFAULT_MAX_CAPACITY)
+                .time_to_idle(Duration::from_secs(ttl))
+                .build(),
+        }
+    }
+
+    /// Cached clearance of the key
+    pub fn get(&self, key: &ClearanceKey) -> Option<Clearance> {
+        self.cache.get(key).filter(|c| !c.expired())
+    }
+
+    /// Solve the challenge of the url for the key, unless a request waited on solved it
+    /// already. `rejected` is the clearance the challenged request was sent with
+    pub async fn solve(
+        &self,
+        key: &ClearanceKey,
+        url: &str,
+        proxy: Option<&Url>,
+        rejected: Option<&Clearance>,
+    ) -> anyhow::Result<Clearance> {
+        let lock = self.locks.get_with_by_ref(key, || Arc::new(Mutex::new(())));
+        let _guard = lock.lock().await;
+
+        if let Some(clearance) = self.get(key) {
+            if Some(&clearance) != rejected {
+                return Ok(clearance);
+            }
+        }
+
+        debug!(
+            "Solving the Cloudflare challenge of {url} for {}",
+            key.egress
+        );
+        self.cache.invalidate(key);
+        let clearance = self.solver.solve(url, proxy).await?;
+        self.cache.insert(key.clone(), clearance.clone());
+        Ok(clearance)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Local solver counting the solved challenges
+    #[derive(Default)]
+    pub(crate) struct MockSolver {
+        pub(crate) solved: AtomicUsize,
+        pub(crate) fail: bool,
+    }
+
+    impl ClearanceSolver for MockSolver {
+        async fn solve(&self, url: &str, _proxy: Option<&Url>) -> anyhow::Result<Clearance> {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            if self.fail {
+                anyhow::bail!("challenge of {url} not solved")
+            }
+            let n = self.solved.fetch_add(1, Ordering::SeqCst);
+            Ok(Clearance {
+                cf_clearance: format!("clearance-{n}"),
+                user_agent: Some("Mozilla/5.0".to_owned()),
+                expires: None,
+            })
+        }
+    }
+
+    fn key(egress: &str) -> ClearanceKey {
+        ClearanceKey {
+            egress: egress.to_owned(),
+            impersonate: "OkHttp4_9".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_chrome_profile() {
+        let ua = |version| {
+            format!("Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/{version} Safari/537.36")
+        };
+        assert_eq!(chrome_profile(&ua("120.0.0.0")), Some(120));
+        assert_eq!(chrome_profile(&ua("124.0.6367.91")), Some(120));
+        assert_eq!(chrome_profile(&ua("115.0.0.0")), Some(114));
+        assert_eq!(chrome_profile(&ua("90.0.0.0")), Some(99));
+        assert_eq!(
+            chrome_profile("Mozilla/5.0 (Macintosh) Version/17.2 Safari/605.1.15"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_with_cookie() {
+        assert_eq!(with_cookie("", CF_CLEARANCE, "a"), "cf_clearance=a");
+        assert_eq!(
+            with_cookie("_puid=p; cf_clearance=old;_account=x", CF_CLEARANCE, "a"),
+            "_puid=p;_account=x;cf_clearance=a"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_clearance_store() {
+        let store = Arc::new(ClearanceStore::new(MockSolver::default(), 60));
+        let url = "https://chat.openai.com/backend-api/models";
+
+        // Concurrent challenges of a key are solved once
+        let tasks = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                tokio::spawn(async move { store.solve(&key("direct"), url, None, None).await })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap().cf_clearance, "clearance-0");
+        }
+        assert_eq!(store.solver.solved.load(Ordering::SeqCst), 1);
+
+        // A rejected clearance is solved again, the other keys have their own
+        let rejected = store.get(&key("direct")).unwrap();
+        let clearance = store
+            .solve(&key("direct"), url, None, Some(&rejected))
+            .await
+            .unwrap();
+        assert_eq!(clearance.cf_clearance, "clearance-1");
+        assert!(store.get(&key("socks5://127.0.0.1:1080")).is_none());
+
+        let failing = ClearanceStore::new(
+            MockSolver {
+                fail: true,
+                ..Default::default()
+            },
+            60,
+        );
+        assert!(failing
+            .solve(&key("direct"), url, None, None)
+            .await
+            .is_err());
+        assert!(failing.get(&key("direct")).is_none());
+    }
+}
@@ -0,0 +1,210 @@
+use crate::{debug, error, homedir::home_dir, now_duration};
+use moka::sync::Cache;
+use native_db::*;
+use native_model::{native_model, Model};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+const DEFAULT_MAX_CAPACITY: u64 = 65535;
+
+static DATABASE_BUILDER: OnceLock<DatabaseBuilder> = OnceLock::new();
+
+/// A ChatGPT conversation the next message can be attached to
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Conversation {
+    pub conversation_id: String,
+    /// The assistant message the next message replies to
+    pub parent_message_id: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[native_model(id = 1, version = 1)]
+#[native_db]
+struct ReDBConversation {
+    #[primary_key]
+    key: String,
+    conversation_id: String,
+    parent_message_id: String,
+    last_time: u64,
+}
+
+/// Message prefix digest -> conversation
+pub(super) enum ConversationStore {
+    Mem(Cache<String, Conversation>),
+    ReDB {
+        db: Arc<Database<'static>>,
+        ttl: u32,
+    },
+}
+
+impl ConversationStore {
+    pub(super) fn new(strategy: &str, ttl: u32) -> anyhow::Result<Self> {
+        match strategy {
+            "mem" => Ok(Self::Mem(
+                Cache::builder()
+                    .max_capacity(DEFAULT_MAX_CAPACITY)
+                    .time_to_live(Duration::from_secs(ttl.into()))
+                    .build(),
+            )),
+            "redb" => {
+                let builder = DATABASE_BUILDER.get_or_init(|| {
+                    let mut builder = DatabaseBuilder::new();
+                    builder
+                        .define::<ReDBConversation>()
+                        .expect("define table failed");
+                    builder
+                });
+
+                let db = Arc::new(
+                    builder.create(
+                        home_dir()
+                            .ok_or_else(|| anyhow::anyhow!("Failed to get home directory"))?
+                            .join(super::WORKER_DIR)
+                            .join("conversation.db"),
+                    )?,
+                );
+                // clear expired conversations every ttl seconds
+                clear_expired_every(db.clone(), ttl);
+                Ok(Self::ReDB { db, ttl })
+            }
+            _ => anyhow::bail!("storage policy: {} is not supported", strategy),
+        }
+    }
+
+    pub(super) fn get(&self, key: &str) -> anyhow::Result<Option<Conversation>> {
+        match self {
+            Self::Mem(cache) => Ok(cache.get(key)),
+            Self::ReDB { db, ttl } => {
+                let r = db.r_transaction()?;
+                let value: Option<ReDBConversation> = r.get().primary(key.to_owned())?;
+                let now_timestamp = now_duration()?.as_secs();
+                Ok(value
+                    .filter(|v| now_timestamp.saturating_sub(v.last_time) < (*ttl).into())
+                    .map(|v| Conversation {
+                        conversation_id: v.conversation_id,
+                        parent_message_id: v.parent_message_id,
+                    }))
+            }
+        }
+    }
+
+    pub(super) fn insert(&self, key: String, value: Conversation) -> anyhow::Result<()> {
+        match self {
+            Self::Mem(cache) => cache.insert(key, value),
+            Self::ReDB { db, .. } => {
+                let rw = db.rw_transaction()?;
+                rw.insert(ReDBConversation {
+                    key,
+                    conversation_id: value.conversation_id,
+                    parent_message_id: value.parent_message_id,
+                    last_time: now_duration()?.as_secs(),
+                })?;
+                rw.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn remove(&self, key: &str) -> anyhow::Result<()> {
+        match self {
+            Self::Mem(cache) => cache.invalidate(key),
+            Self::ReDB { db, .. } => {
+                let rw = db.rw_transaction()?;
+                let value: Option<ReDBConversation> = rw.get().primary(key.to_owned())?;
+                if let Some(value) = value {
+                    rw.remove(value)?;
+                    rw.commit()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn clear_expired_every(db: Arc<Database<'static>>, ttl: u32) {
+    use std::thread;
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(ttl.into()));
+
+        debug!("ReDB Clearing expired conversations...");
+
+        let r = match db.r_transaction() {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Error starting read transaction: {}", e);
+                continue;
+            }
+        };
+
+        let now_timestamp = match now_duration() {
+            Ok(t) => t.as_secs(),
+            Err(e) => {
+                error!("Error getting current time: {}", e);
+                continue;
+            }
+        };
+
+        let scan = match r.scan().primary::<ReDBConversation>() {
+            Ok(scan) => scan,
+            Err(e) => {
+                error!("Error starting scan: {}", e);
+                continue;
+            }
+        };
+
+        for value in scan.all() {
+            if now_timestamp.saturating_sub(value.last_time) >= ttl.into() {
+                let rw = match db.rw_transaction() {
+                    Ok(rw) => rw,
+                    Err(e) => {
+                        error!("Error starting read-write transaction: {}", e);
+                        continue;
+                    }
+                };
+
+                if let Err(e) = rw.remove(value) {
+                    error!("Error removing conversation: {}", e);
+                }
+
+                if let Err(e) = rw.commit() {
+                    error!("Error committing transaction: {}", e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mem_store() {
+        let store = ConversationStore::new("mem", 60).unwrap();
+        assert!(store.get("key").unwrap().is_none());
+
+        store
+            .insert(
+                "key".to_owned(),
+                Conversation {
+                    conversation_id: "conversation".to_owned(),
+                    parent_message_id: "message".to_owned(),
+                },
+            )
+            .unwrap();
+        let conversation = store.get("key").unwrap().unwrap();
+        assert_eq!(conversation.conversation_id, "conversation");
+        assert_eq!(conversation.parent_message_id, "message");
+
+        store.remove("key").unwrap();
+        assert!(store.get("key").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_unsupported_strategy() {
+        assert!(ConversationStore::new("redis", 60).is_err());
+    }
+}
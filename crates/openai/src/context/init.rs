@@ -1,58 +1,151 @@
 use super::{
+    apikey::ApiKeyStore,
     args::Args,
     arkose::{
         har::{HarProvider, HAR},
         ArkoseVersionContext,
     },
+    clearance::ClearanceStore,
+    conversation::ConversationStore,
+    pool::AccountPool,
     preauth::PreauthCookieProvider,
-    CfTurnstile, Context, CTX,
+    reload::Reloadable,
+    CfTurnstile, Context, Gpt4Cap, CTX,
 };
-use crate::{arkose, client::ClientRoundRobinBalancer, error};
-use std::{collections::HashMap, sync::RwLock};
+use crate::{
+    accesslog::AccessLog, arkose, client::ClientRoundRobinBalancer, egress::EgressRouter, error,
+    warn,
+};
+use anyhow::Context as _;
+use std::{collections::HashMap, str::FromStr, sync::RwLock};
 
 /// Use Once to guarantee initialization only once
-pub fn init(args: Args) {
-    if let Some(_) = CTX.set(init_context(args.clone())).err() {
+pub fn init(args: Args) -> anyhow::Result<()> {
+    if let Some(_) = CTX.set(init_context(args.clone())?).err() {
         error!("Failed to initialize context");
     };
 
     if let Some(_) = HAR.set(RwLock::new(init_har_provider(args))).err() {
         error!("Failed to initialize har provider");
     };
+
+    Ok(())
+}
+
+/// Check if the context and the HAR providers are initialized
+pub fn initialized() -> bool {
+    CTX.get().is_some() && HAR.get().is_some()
 }
 
 /// Get the program context
 pub fn instance() -> &'static Context {
-    CTX.get_or_init(|| init_context(Args::builder().build()))
+    CTX.get_or_init(|| {
+        init_context(Args::builder().build()).expect("Failed to initialize the default context")
+    })
 }
 
 /// Init the program context
-fn init_context(args: Args) -> Context {
-    Context {
-        api_client: ClientRoundRobinBalancer::new_client(&args)
-            .expect("Failed to initialize the requesting client"),
-        auth_client: ClientRoundRobinBalancer::new_auth_client(&args)
-            .expect("Failed to initialize the requesting oauth client"),
-        arkose_client: ClientRoundRobinBalancer::new_arkose_client(&args)
-            .expect("Failed to initialize the requesting arkose client"),
+fn init_context(args: Args) -> anyhow::Result<Context> {
+    let cf_turnstile = cf_turnstile(&args);
+    Ok(Context {
+        api_client: Reloadable::new(
+            ClientRoundRobinBalancer::new_client(&args)
+                .context("Failed to initialize the requesting client")?,
+        ),
+        auth_client: Reloadable::new(
+            ClientRoundRobinBalancer::new_auth_client(&args)
+                .context("Failed to initialize the requesting oauth client")?,
+        ),
+        arkose_client: Reloadable::new(
+            ClientRoundRobinBalancer::new_arkose_client(&args)
+                .context("Failed to initialize the requesting arkose client")?,
+        ),
+        egress: Reloadable::new(
+            EgressRouter::new(&args).context("Failed to initialize the proxy rules")?,
+        ),
         preauth_provider: args.pbind.is_some().then(|| PreauthCookieProvider::new()),
+        conversation_history: args.conv_history,
+        conversation_store: conversation_store(&args)?,
+        account_pool: args
+            .pool_enable
+            .then(|| {
+                AccountPool::new(&args.pool_strategy, args.pool_cooldown)
+                    .context("Failed to initialize the account pool")
+            })
+            .transpose()?,
+        api_keys: args
+            .auth_key
+            .is_some()
+            .then(|| ApiKeyStore::new().context("Failed to initialize the api key store"))
+            .transpose()?,
+        gpt4_cap: args.gpt4_cap.then(|| Gpt4Cap {
+            wait: args.gpt4_cap_wait,
+            downgrade: args.gpt4_cap_downgrade,
+        }),
+        access_log: args
+            .access_log
+            .map(|path| {
+                AccessLog::new(
+                    path,
+                    args.access_log_max_size * 1024 * 1024,
+                    args.access_log_keep,
+                    args.access_log_hash_email,
+                )
+                .context("Failed to initialize the access log")
+            })
+            .transpose()?,
+        readyz_har: args
+            .readyz_har
+            .iter()
+            .filter_map(|t| match arkose::Type::from_str(t) {
+                Ok(typed) => Some(typed),
+                Err(err) => {
+                    warn!("Ignore the readyz HAR type: {err}");
+                    None
+                }
+            })
+            .collect(),
+        retry: args.retry,
+        cf_clearance: args
+            .cf_solver
+            .map(|solver| ClearanceStore::new(solver, args.cf_clearance_ttl.unwrap_or(1800))),
+        upstreams: args.upstreams,
         arkose_endpoint: args.arkose_endpoint,
         arkose_context: ArkoseVersionContext::new(),
-        arkose_solver: args.arkose_solver,
         arkose_gpt3_experiment: args.arkose_gpt3_experiment,
         arkose_gpt3_experiment_solver: args.arkose_gpt3_experiment_solver,
         arkose_solver_tguess_endpoint: args.arkose_solver_tguess_endpoint,
         arkose_solver_image_dir: args.arkose_solver_image_dir,
         enable_file_proxy: args.enable_file_proxy,
-        auth_key: args.auth_key,
-        visitor_email_whitelist: args.visitor_email_whitelist,
-        cf_turnstile: args.cf_site_key.and_then(|site_key| {
-            args.cf_secret_key.map(|secret_key| CfTurnstile {
-                site_key,
-                secret_key,
-            })
-        }),
+        cf_turnstile: Reloadable::new(cf_turnstile),
+        arkose_solver: Reloadable::new(args.arkose_solver),
+        auth_key: Reloadable::new(args.auth_key),
+        visitor_email_whitelist: Reloadable::new(args.visitor_email_whitelist),
+    })
+}
+
+/// Conversations can only be reused while ChatGPT keeps them in the history
+fn conversation_store(args: &Args) -> anyhow::Result<Option<ConversationStore>> {
+    if args.conv_reuse && !args.conv_history {
+        warn!("Conversation reuse requires the conversation history, disabled");
+        return Ok(None);
     }
+    args.conv_reuse
+        .then(|| {
+            ConversationStore::new(&args.conv_store, args.conv_ttl)
+                .context("Failed to initialize the conversation store")
+        })
+        .transpose()
+}
+
+/// Turnstile is enabled with both the site key and the secret key
+pub(super) fn cf_turnstile(args: &Args) -> Option<CfTurnstile> {
+    args.cf_site_key.clone().and_then(|site_key| {
+        args.cf_secret_key.clone().map(|secret_key| CfTurnstile {
+            site_key,
+            secret_key,
+        })
+    })
 }
 
 fn init_har_provider(args: Args) -> HashMap<arkose::Type, HarProvider> {
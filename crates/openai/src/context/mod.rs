@@ -1,16 +1,34 @@
+pub mod apikey;
 pub mod args;
 pub mod arkose;
+pub mod clearance;
+pub mod conversation;
 pub mod init;
+pub mod pool;
 mod preauth;
+pub mod reload;
 
+use self::apikey::ApiKeyStore;
+use self::clearance::ClearanceStore;
+use self::conversation::{Conversation, ConversationStore};
+use self::pool::AccountPool;
 use self::preauth::PreauthCookieProvider;
+use self::reload::Reloadable;
 use crate::{
-    arkose::funcaptcha::solver::ArkoseSolver, auth::AuthClient, client::ClientRoundRobinBalancer,
+    accesslog::AccessLog,
+    arkose::{funcaptcha::solver::ArkoseSolver, Type as ArkoseType},
+    auth::AuthClient,
+    client::{ClientHealth, ClientRoundRobinBalancer, Lease},
+    egress::{Destination, EgressRouter},
+    error,
+    upstream::Upstream,
+    URL_CHATGPT_API,
 };
 use reqwest::Client;
 use std::{
+    collections::HashMap,
     path::{Path, PathBuf},
-    sync::OnceLock,
+    sync::{Arc, OnceLock},
 };
 
 pub const WORKER_DIR: &str = ".ninja";
@@ -30,34 +48,44 @@ macro_rules! with_context {
     };
 }
 
-pub fn init(args: args::Args) {
-    init::init(args);
+pub fn init(args: args::Args) -> anyhow::Result<()> {
+    init::init(args)
 }
 
+#[derive(Clone)]
 pub struct CfTurnstile {
     pub site_key: String,
     pub secret_key: String,
 }
 
+pub struct Gpt4Cap {
+    /// Seconds a capped message may wait for a free slot
+    pub wait: u32,
+    /// Send capped messages with GPT-3.5
+    pub downgrade: bool,
+}
+
 pub struct Context {
     /// Requesting client
-    api_client: ClientRoundRobinBalancer,
+    api_client: Reloadable<ClientRoundRobinBalancer>,
     /// Requesting oauth client
-    auth_client: ClientRoundRobinBalancer,
+    auth_client: Reloadable<ClientRoundRobinBalancer>,
     /// Requesting arkose client
-    arkose_client: ClientRoundRobinBalancer,
+    arkose_client: Reloadable<ClientRoundRobinBalancer>,
+    /// Proxy group pools of the proxy rules
+    egress: Reloadable<EgressRouter>,
     /// Arkoselabs context
     arkose_context: arkose::ArkoseVersionContext<'static>,
     /// arkoselabs solver
-    arkose_solver: Option<ArkoseSolver>,
+    arkose_solver: Reloadable<Option<ArkoseSolver>>,
     /// Enable files proxy
     enable_file_proxy: bool,
     /// Login auth key
-    auth_key: Option<String>,
+    auth_key: Reloadable<Option<String>>,
     /// visitor_email_whitelist
-    visitor_email_whitelist: Option<Vec<String>>,
+    visitor_email_whitelist: Reloadable<Option<Vec<String>>>,
     /// Cloudflare Turnstile
-    cf_turnstile: Option<CfTurnstile>,
+    cf_turnstile: Reloadable<Option<CfTurnstile>>,
     /// Arkose endpoint
     arkose_endpoint: Option<String>,
     /// Enable Arkose GPT-3.5 experiment
@@ -70,32 +98,125 @@ pub struct Context {
     arkose_solver_image_dir: Option<PathBuf>,
     /// PreAuth cookie cache
     preauth_provider: Option<PreauthCookieProvider>,
+    /// Keep the chat completions API conversations in the ChatGPT history
+    conversation_history: bool,
+    /// Conversation reuse store
+    conversation_store: Option<ConversationStore>,
+    /// Upstream backends routed by model name
+    upstreams: Vec<Upstream>,
+    /// ChatGPT account pool
+    account_pool: Option<AccountPool>,
+    /// Ninja issued api keys
+    api_keys: Option<ApiKeyStore>,
+    /// GPT-4 message cap
+    gpt4_cap: Option<Gpt4Cap>,
+    /// Access log writer
+    access_log: Option<AccessLog>,
+    /// Arkose types the readiness check requires HAR files for
+    readyz_har: Vec<ArkoseType>,
+    /// Retries of idempotent and conversation requests
+    retry: usize,
+    /// Cloudflare clearance of the challenged requests
+    cf_clearance: Option<ClearanceStore>,
 }
 
 impl Context {
     /// Get the reqwest client
-    pub fn api_client(&self) -> Client {
-        self.api_client.next().into()
+    pub fn api_client(&self) -> Lease<Client> {
+        self.api_client.load().next().into()
+    }
+
+    /// Get the reqwest client of the account for the url, the proxy rules may route it to a
+    /// proxy group, pinned to the account in affinity mode
+    pub fn api_client_for(&self, url: &str, account: Option<&str>) -> Lease<Client> {
+        let pool = self
+            .route_pool(&Destination::url(url))
+            .unwrap_or_else(|| self.api_client.load());
+        pool.next_for(account).into()
+    }
+
+    /// Get the requesting client pool
+    pub fn api_client_pool(&self) -> Arc<ClientRoundRobinBalancer> {
+        self.api_client.load()
     }
 
     /// Get the reqwest auth client
-    pub fn auth_client(&self) -> AuthClient {
-        self.auth_client.next().into()
+    pub fn auth_client(&self) -> Lease<AuthClient> {
+        self.auth_client.load().next().into()
+    }
+
+    /// Get the reqwest auth client of the account for the url, the proxy rules may route it to
+    /// a proxy group, pinned to the account in affinity mode
+    pub fn auth_client_for(&self, url: &str, account: &str) -> Lease<AuthClient> {
+        let pool = match self.egress.load().route(&Destination::url(url)) {
+            Some(group) => group.auth.clone(),
+            None => self.auth_client.load(),
+        };
+        pool.next_for(Some(account)).into()
     }
 
     /// Get the reqwest arkose client
-    pub fn arkose_client(&self) -> Client {
-        self.arkose_client.next().into()
+    pub fn arkose_client(&self) -> Lease<Client> {
+        self.arkose_client.load().next().into()
+    }
+
+    /// Get the reqwest arkose client of the url, the proxy rules may route it to a proxy group
+    pub fn arkose_client_for(&self, url: &str) -> Lease<Client> {
+        match self.egress.load().route(&Destination::url(url)) {
+            Some(group) => group.client.next().into(),
+            None => self.arkose_client(),
+        }
+    }
+
+    /// Get the proxy group pool of the destination, none if no proxy rule routes it
+    pub fn route_pool(&self, dest: &Destination) -> Option<Arc<ClientRoundRobinBalancer>> {
+        self.egress
+            .load()
+            .route(dest)
+            .map(|group| group.client.clone())
+    }
+
+    /// Check if a proxy rule matches on the model
+    pub fn routes_models(&self) -> bool {
+        self.egress.load().routes_models()
+    }
+
+    /// Get the client pools by client type, the proxy group pools are `group:<name>`
+    pub fn client_pools(&self) -> Vec<(String, Arc<ClientRoundRobinBalancer>)> {
+        let mut pools = vec![
+            ("api".to_owned(), self.api_client.load()),
+            ("auth".to_owned(), self.auth_client.load()),
+            ("arkose".to_owned(), self.arkose_client.load()),
+        ];
+        for (name, group) in self.egress.load().groups() {
+            pools.push((format!("group:{name}"), group.client.clone()));
+            pools.push((format!("group:{name}:auth"), group.auth.clone()));
+        }
+        pools
+    }
+
+    /// Probe the proxies of the api and arkose client pools
+    pub async fn proxy_health(&self) -> HashMap<&'static str, Vec<ClientHealth>> {
+        HashMap::from([
+            ("api", self.api_client.load().health(URL_CHATGPT_API).await),
+            (
+                "arkose",
+                self.arkose_client
+                    .load()
+                    .health(ArkoseType::GPT4.origin_url())
+                    .await,
+            ),
+        ])
     }
 
     /// Get the arkoselabs solver
-    pub fn arkose_solver(&self) -> Option<&ArkoseSolver> {
-        self.arkose_solver.as_ref()
+    pub fn arkose_solver(&self) -> Option<ArkoseSolver> {
+        self.arkose_solver.load().as_ref().clone()
     }
 
     /// Cloudflare Turnstile config
-    pub fn cf_turnstile(&self) -> Option<&CfTurnstile> {
-        self.cf_turnstile.as_ref()
+    pub fn cf_turnstile(&self) -> Option<CfTurnstile> {
+        self.cf_turnstile.load().as_ref().clone()
     }
 
     /// Arkoselabs endpoint
@@ -104,8 +225,8 @@ impl Context {
     }
 
     /// Login auth key
-    pub fn auth_key(&self) -> Option<&str> {
-        self.auth_key.as_deref()
+    pub fn auth_key(&self) -> Option<String> {
+        self.auth_key.load().as_ref().clone()
     }
 
     /// Push a preauth cookie
@@ -122,6 +243,89 @@ impl Context {
         self.preauth_provider.as_ref().map(|p| p.get()).flatten()
     }
 
+    /// Keep the chat completions API conversations in the ChatGPT history
+    pub fn conversation_history(&self) -> bool {
+        self.conversation_history
+    }
+
+    /// Enable conversation reuse
+    pub fn conversation_reuse(&self) -> bool {
+        self.conversation_store.is_some()
+    }
+
+    /// Get a reusable conversation by message prefix digest
+    pub fn get_conversation(&self, key: &str) -> Option<Conversation> {
+        let store = self.conversation_store.as_ref()?;
+        store
+            .get(key)
+            .map_err(|err| error!("Failed to get conversation: {err}"))
+            .ok()
+            .flatten()
+    }
+
+    /// Save a reusable conversation by message prefix digest
+    pub fn put_conversation(&self, key: String, value: Conversation) {
+        if let Some(store) = self.conversation_store.as_ref() {
+            if let Err(err) = store.insert(key, value) {
+                error!("Failed to save conversation: {err}")
+            }
+        }
+    }
+
+    /// Forget a conversation that can no longer be continued
+    pub fn remove_conversation(&self, key: &str) {
+        if let Some(store) = self.conversation_store.as_ref() {
+            if let Err(err) = store.remove(key) {
+                error!("Failed to remove conversation: {err}")
+            }
+        }
+    }
+
+    /// Get the ChatGPT account pool
+    pub fn account_pool(&self) -> Option<&AccountPool> {
+        self.account_pool.as_ref()
+    }
+
+    /// Get the ninja issued api keys, available when the auth key is set
+    pub fn api_keys(&self) -> Option<&ApiKeyStore> {
+        self.api_keys.as_ref()
+    }
+
+    /// GPT-4 message cap
+    pub fn gpt4_cap(&self) -> Option<&Gpt4Cap> {
+        self.gpt4_cap.as_ref()
+    }
+
+    /// Arkose types the readiness check requires HAR files for
+    pub fn readyz_har(&self) -> &[ArkoseType] {
+        &self.readyz_har
+    }
+
+    /// Retries of idempotent and conversation requests
+    pub fn retry(&self) -> usize {
+        self.retry
+    }
+
+    /// Cloudflare clearance store, none without a challenge solver
+    pub fn cf_clearance(&self) -> Option<&ClearanceStore> {
+        self.cf_clearance.as_ref()
+    }
+
+    /// Access log writer
+    pub fn access_log(&self) -> Option<&AccessLog> {
+        self.access_log.as_ref()
+    }
+
+    /// Get the upstream backend of the model, the first matching route wins
+    pub fn upstream(&self, model: &str) -> Option<&Upstream> {
+        self.upstreams.iter().find(|u| u.matches(model))
+    }
+
+    /// Check if any upstream backend is configured
+    pub fn has_upstream(&self) -> bool {
+        !self.upstreams.is_empty()
+    }
+
     /// Get the arkose gpt3 experiment
     pub fn arkose_gpt3_experiment(&self) -> bool {
         self.arkose_gpt3_experiment
@@ -133,8 +337,8 @@ impl Context {
     }
 
     /// Get the visitor email whitelist
-    pub fn visitor_email_whitelist(&self) -> Option<&[String]> {
-        self.visitor_email_whitelist.as_deref()
+    pub fn visitor_email_whitelist(&self) -> Option<Vec<String>> {
+        self.visitor_email_whitelist.load().as_ref().clone()
     }
 
     /// Get the arkose gpt3 experiment solver
@@ -0,0 +1,260 @@
+use crate::auth::provide::AuthProvider;
+use crate::auth::OPENAI_OAUTH_TOKEN_URL;
+use crate::homedir::home_dir;
+use crate::token::model::Token;
+use crate::{debug, info, now_duration, warn, with_context, URL_CHATGPT_API};
+use moka::sync::Cache;
+use native_db::*;
+use native_model::{native_model, Model};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+use tokio::time::interval;
+
+/// Refresh the tokens expiring within a day
+const REFRESH_BEFORE_SECONDS: i64 = 86400;
+/// Check the expiring tokens every ten minutes
+const REFRESH_INTERVAL_SECONDS: u64 = 600;
+
+static DATABASE_BUILDER: OnceLock<DatabaseBuilder> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[native_model(id = 1, version = 1)]
+#[native_db]
+struct ReDBAccount {
+    #[primary_key]
+    email: String,
+    token: Token,
+}
+
+/// Account selection strategy
+#[derive(Clone, Copy, Debug)]
+pub enum Strategy {
+    RoundRobin,
+    LeastUsed,
+}
+
+impl std::str::FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-used" => Ok(Strategy::LeastUsed),
+            _ => anyhow::bail!("account pool strategy: {} is not supported", s),
+        }
+    }
+}
+
+struct PoolAccount {
+    token: Token,
+    // Number of dispatched requests
+    used: AtomicU64,
+}
+
+/// Account state reported by the admin API
+#[derive(Serialize)]
+pub struct AccountStatus {
+    pub email: String,
+    pub expires: i64,
+    pub used: u64,
+    pub quarantined: bool,
+}
+
+/// ChatGPT accounts the ninja API key requests are dispatched to
+pub struct AccountPool {
+    db: Arc<Database<'static>>,
+    strategy: Strategy,
+    accounts: RwLock<Vec<Arc<PoolAccount>>>,
+    cursor: AtomicUsize,
+    // Email of the accounts cooling down after a 401 or 429
+    quarantine: Cache<String, ()>,
+}
+
+impl AccountPool {
+    pub(super) fn new(strategy: &str, cooldown: u32) -> anyhow::Result<Self> {
+        let strategy = strategy.parse::<Strategy>()?;
+        let builder = DATABASE_BUILDER.get_or_init(|| {
+            let mut builder = DatabaseBuilder::new();
+            builder
+                .define::<ReDBAccount>()
+                .expect("define table failed");
+            builder
+        });
+
+        let db = builder.create(
+            home_dir()
+                .ok_or_else(|| anyhow::anyhow!("Failed to get home directory"))?
+                .join(super::WORKER_DIR)
+                .join("account.db"),
+        )?;
+
+        let accounts = {
+            let r = db.r_transaction()?;
+            let scan = r.scan().primary::<ReDBAccount>()?;
+            scan.all()
+                .map(|v| {
+                    Arc::new(PoolAccount {
+                        token: v.token,
+                        used: AtomicU64::new(0),
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        info!("Account pool loaded {} accounts", accounts.len());
+
+        Ok(Self {
+            db: Arc::new(db),
+            strategy,
+            accounts: RwLock::new(accounts),
+            cursor: AtomicUsize::new(0),
+            quarantine: Cache::builder()
+                .time_to_live(Duration::from_secs(cooldown.into()))
+                .build(),
+        })
+    }
+
+    /// Select a healthy account, neither expired nor quarantined
+    pub fn next(&self) -> Option<Token> {
+        let accounts = self.accounts.read().expect("account pool lock poisoned");
+        let healthy = accounts
+            .iter()
+            .filter(|a| !a.token.is_expired() && !self.quarantine.contains_key(a.token.email()));
+
+        let account = match self.strategy {
+            Strategy::RoundRobin => {
+                let healthy = healthy.collect::<Vec<_>>();
+                if healthy.is_empty() {
+                    return None;
+                }
+                let index = self.cursor.fetch_add(1, Ordering::Relaxed) % healthy.len();
+                healthy[index]
+            }
+            Strategy::LeastUsed => healthy.min_by_key(|a| a.used.load(Ordering::Relaxed))?,
+        };
+
+        account.used.fetch_add(1, Ordering::Relaxed);
+        Some(account.token.clone())
+    }
+
+    /// Stop dispatching to the account until the cool-down period ends
+    pub fn quarantine(&self, email: &str) {
+        warn!("Account pool quarantine: {email}");
+        self.quarantine.insert(email.to_owned(), ());
+    }
+
+    /// Add or replace the account of the token
+    pub fn insert(&self, token: Token) -> anyhow::Result<()> {
+        let rw = self.db.rw_transaction()?;
+        rw.insert(ReDBAccount {
+            email: token.email().to_owned(),
+            token: token.clone(),
+        })?;
+        rw.commit()?;
+
+        let mut accounts = self.accounts.write().expect("account pool lock poisoned");
+        let account = Arc::new(PoolAccount {
+            used: AtomicU64::new(0),
+            token,
+        });
+        match accounts
+            .iter_mut()
+            .find(|a| a.token.email().eq(account.token.email()))
+        {
+            Some(old) => {
+                account
+                    .used
+                    .store(old.used.load(Ordering::Relaxed), Ordering::Relaxed);
+                *old = account;
+            }
+            None => accounts.push(account),
+        }
+        Ok(())
+    }
+
+    /// Remove the account, returns false if it does not exist
+    pub fn remove(&self, email: &str) -> anyhow::Result<bool> {
+        let rw = self.db.rw_transaction()?;
+        let value: Option<ReDBAccount> = rw.get().primary(email.to_owned())?;
+        if let Some(value) = value {
+            rw.remove(value)?;
+            rw.commit()?;
+        }
+
+        let mut accounts = self.accounts.write().expect("account pool lock poisoned");
+        let len = accounts.len();
+        accounts.retain(|a| a.token.email().ne(email));
+        self.quarantine.invalidate(email);
+        Ok(accounts.len() != len)
+    }
+
+    /// List the accounts state
+    pub fn status(&self) -> Vec<AccountStatus> {
+        let accounts = self.accounts.read().expect("account pool lock poisoned");
+        accounts
+            .iter()
+            .map(|a| AccountStatus {
+                email: a.token.email().to_owned(),
+                expires: a.token.expires(),
+                used: a.used.load(Ordering::Relaxed),
+                quarantined: self.quarantine.contains_key(a.token.email()),
+            })
+            .collect()
+    }
+
+    /// Periodically refresh the tokens close to expiry
+    pub async fn periodic_refresh(&self) {
+        info!("Account pool refresh task is running");
+        let mut interval = interval(Duration::from_secs(REFRESH_INTERVAL_SECONDS));
+        loop {
+            interval.tick().await;
+            self.refresh().await;
+        }
+    }
+
+    async fn refresh(&self) {
+        let now = match now_duration() {
+            Ok(now) => now.as_secs() as i64,
+            Err(err) => {
+                warn!("Account pool refresh error: {err}");
+                return;
+            }
+        };
+
+        let expiring = {
+            let accounts = self.accounts.read().expect("account pool lock poisoned");
+            accounts
+                .iter()
+                .filter(|a| a.token.expires() - now < REFRESH_BEFORE_SECONDS)
+                .map(|a| a.token.clone())
+                .collect::<Vec<_>>()
+        };
+
+        for token in expiring {
+            debug!("Account pool refreshing: {}", token.email());
+            let client = |url: &str| with_context!(auth_client_for, url, token.email());
+            let result = match (token.refresh_token(), token.session_token()) {
+                (Some(refresh_token), _) => match client(OPENAI_OAUTH_TOKEN_URL)
+                    .do_refresh_token(refresh_token)
+                    .await
+                {
+                    Ok(refresh_token) => Token::try_from(refresh_token),
+                    Err(err) => Err(err.into()),
+                },
+                (None, Some(session_token)) => {
+                    match client(URL_CHATGPT_API).refresh_session(session_token).await {
+                        Ok(access_token) => Token::try_from(access_token),
+                        Err(err) => Err(err.into()),
+                    }
+                }
+                _ => Err(anyhow::anyhow!("no refresh token or session token")),
+            };
+
+            match result.and_then(|token| self.insert(token)) {
+                Ok(_) => info!("Account pool refreshed: {}", token.email()),
+                Err(err) => warn!("Account pool refresh {} error: {err}", token.email()),
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use super::{args::Args, init, Context};
+use crate::client::ClientRoundRobinBalancer;
+use crate::egress::EgressRouter;
+use std::sync::{Arc, RwLock};
+
+/// Config section swapped atomically by a reload, readers keep the snapshot they loaded
+pub struct Reloadable<T>(RwLock<Arc<T>>);
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Self(RwLock::new(Arc::new(value)))
+    }
+
+    /// Get the current value
+    pub fn load(&self) -> Arc<T> {
+        self.0.read().expect("reloadable lock poisoned").clone()
+    }
+
+    /// Replace the value
+    pub fn store(&self, value: T) {
+        *self.0.write().expect("reloadable lock poisoned") = Arc::new(value);
+    }
+}
+
+impl Context {
+    /// Swap the reloadable sections with the config, nothing is swapped if a section fails to build
+    pub fn reload(&self, args: &Args) -> anyhow::Result<()> {
+        let api_client = ClientRoundRobinBalancer::new_client(args)?;
+        let auth_client = ClientRoundRobinBalancer::new_auth_client(args)?;
+        let arkose_client = ClientRoundRobinBalancer::new_arkose_client(args)?;
+        let egress = EgressRouter::new(args)?;
+
+        self.api_client.store(api_client);
+        self.auth_client.store(auth_client);
+        self.arkose_client.store(arkose_client);
+        self.egress.store(egress);
+        self.arkose_solver.store(args.arkose_solver.clone());
+        self.auth_key.store(args.auth_key.clone());
+        self.visitor_email_whitelist
+            .store(args.visitor_email_whitelist.clone());
+        self.cf_turnstile.store(init::cf_turnstile(args));
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+
+/// DNS settings of the client resolvers
+#[derive(Default)]
+pub(super) struct DnsConfig {
+    /// Upstream name servers, the system resolver is used if empty
+    pub servers: Vec<NameServerConfig>,
+    /// Static host overrides
+    pub hosts: Vec<(String, Vec<IpAddr>)>,
+    /// Host pattern name servers, the first matching rule wins
+    pub rules: Vec<(String, Vec<NameServerConfig>)>,
+    pub cache_size: Option<usize>,
+    pub min_ttl: Option<Duration>,
+    pub max_ttl: Option<Duration>,
+    pub negative_ttl: Option<Duration>,
+    /// Upstream hosts besides the OpenAI hosts, the name servers are benchmarked against them
+    pub upstream_hosts: Vec<String>,
+}
+
+impl DnsConfig {
+    pub fn new(
+        servers: &[String],
+        hosts: &[String],
+        rules: &[String],
+        upstream_hosts: Vec<String>,
+    ) -> anyhow::Result<Self> {
+        let servers = servers
+            .iter()
+            .map(|s| parse_name_server(s))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut host_map: Vec<(String, Vec<IpAddr>)> = Vec::new();
+        for (host, ip) in hosts
+            .iter()
+            .map(|h| parse_host(h))
+            .collect::<anyhow::Result<Vec<_>>>()?
+        {
+            match host_map.iter_mut().find(|(h, _)| h.eq(&host)) {
+                Some((_, ips)) => ips.push(ip),
+                None => host_map.push((host, vec![ip])),
+            }
+        }
+
+        let mut rule_list: Vec<(String, Vec<NameServerConfig>)> = Vec::new();
+        for rule in rules {
+            let (pattern, server) = rule
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Invalid DNS rule: {rule}"))?;
+            let pattern = normalize(pattern);
+            let server = parse_name_server(server)?;
+            match rule_list.iter_mut().find(|(p, _)| p.eq(&pattern)) {
+                Some((_, servers)) => servers.push(server),
+                None => rule_list.push((pattern, vec![server])),
+            }
+        }
+
+        Ok(Self {
+            servers,
+            hosts: host_map,
+            rules: rule_list,
+            upstream_hosts,
+            ..Default::default()
+        })
+    }
+
+    /// Addresses of the host override
+    pub fn host(&self, host: &str) -> Option<&[IpAddr]> {
+        let host = normalize(host);
+        self.hosts
+            .iter()
+            .find(|(pattern, _)| matches(pattern, &host))
+            .map(|(_, ips)| ips.as_slice())
+    }
+
+    /// Index of the rule of the host
+    pub fn rule(&self, host: &str) -> Option<usize> {
+        let host = normalize(host);
+        self.rules
+            .iter()
+            .position(|(pattern, _)| matches(pattern, &host))
+    }
+
+    /// Apply the cache settings
+    pub fn apply(&self, opts: &mut ResolverOpts) {
+        if let Some(cache_size) = self.cache_size {
+            opts.cache_size = cache_size;
+        }
+        opts.positive_min_ttl = self.min_ttl.or(opts.positive_min_ttl);
+        opts.positive_max_ttl = self.max_ttl.or(opts.positive_max_ttl);
+        // Failed lookups are cached for exactly the negative ttl
+        if let Some(ttl) = self.negative_ttl {
+            opts.negative_min_ttl = Some(ttl);
+            opts.negative_max_ttl = Some(ttl);
+        }
+    }
+}
+
+/// Resolver config of the name servers
+pub(super) fn resolver_config(servers: &[NameServerConfig]) -> ResolverConfig {
+    ResolverConfig::from_parts(None, vec![], servers.to_vec())
+}
+
+/// Parse a name server, `[udp|tcp|tls|https]://<ip>[:port][#<server name>]`,
+/// a bare ip is a plain udp name server
+pub(super) fn parse_name_server(spec: &str) -> anyhow::Result<NameServerConfig> {
+    let spec = spec.trim();
+    let (spec, tls_name) = match spec.split_once('#') {
+        Some((spec, name)) => (spec, Some(name.to_owned())),
+        None => (spec, None),
+    };
+    let (protocol, addr, port) = match spec.split_once("://") {
+        None => (Protocol::Udp, spec, 53),
+        Some(("udp", addr)) => (Protocol::Udp, addr, 53),
+        Some(("tcp", addr)) => (Protocol::Tcp, addr, 53),
+        Some(("tls", addr)) => (Protocol::Tls, addr, 853),
+        Some(("https", addr)) => (Protocol::Https, addr, 443),
+        Some((scheme, _)) => anyhow::bail!("Unsupported DNS protocol: {scheme}"),
+    };
+
+    // The DoH path is always /dns-query
+    let addr = addr.split('/').next().unwrap_or_default();
+    let socket_addr = match addr.parse::<SocketAddr>() {
+        Ok(socket_addr) => socket_addr,
+        Err(_) => {
+            let ip = addr
+                .trim_matches(&['[', ']'][..])
+                .parse::<IpAddr>()
+                .map_err(|_| anyhow::anyhow!("Invalid DNS server address: {addr}"))?;
+            SocketAddr::new(ip, port)
+        }
+    };
+
+    if matches!(protocol, Protocol::Tls | Protocol::Https) && tls_name.is_none() {
+        anyhow::bail!("DNS server {spec} requires the server name, e.g. {spec}#dns.google")
+    }
+
+    let mut server = NameServerConfig::new(socket_addr, protocol);
+    server.tls_dns_name = tls_name;
+    server.trust_negative_responses = true;
+    Ok(server)
+}
+
+/// Parse a host override, `<host>=<ip>`
+fn parse_host(entry: &str) -> anyhow::Result<(String, IpAddr)> {
+    let (host, ip) = entry
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Invalid DNS host override: {entry}"))?;
+    let ip = ip
+        .trim()
+        .parse::<IpAddr>()
+        .map_err(|_| anyhow::anyhow!("Invalid DNS host override address: {entry}"))?;
+    Ok((normalize(host), ip))
+}
+
+fn normalize(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// `*.example.com` matches the subdomains of example.com, any other pattern the host itself
+fn matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .map_or(false, |sub| sub.ends_with('.')),
+        None => pattern.eq(host),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_name_server() {
+        let server = parse_name_server("1.1.1.1").unwrap();
+        assert_eq!(server.socket_addr, "1.1.1.1:53".parse().unwrap());
+        assert_eq!(server.protocol, Protocol::Udp);
+
+        let server = parse_name_server("tls://1.1.1.1#cloudflare-dns.com").unwrap();
+        assert_eq!(server.socket_addr, "1.1.1.1:853".parse().unwrap());
+        assert_eq!(server.protocol, Protocol::Tls);
+        assert_eq!(server.tls_dns_name.as_deref(), Some("cloudflare-dns.com"));
+
+        let server =
+            parse_name_server("https://[2606:4700::1111]:8443/dns-query#one.one.one.one").unwrap();
+        assert_eq!(
+            server.socket_addr,
+            "[2606:4700::1111]:8443".parse().unwrap()
+        );
+        assert_eq!(server.protocol, Protocol::Https);
+
+        assert!(parse_name_server("https://8.8.8.8").is_err());
+        assert!(parse_name_server("quic://8.8.8.8#dns.google").is_err());
+        assert!(parse_name_server("dns.google").is_err());
+    }
+
+    #[test]
+    fn test_hosts_and_rules() {
+        let config = DnsConfig::new(
+            &[],
+            &[
+                "Chat.OpenAI.com.=104.18.37.228".to_owned(),
+                "chat.openai.com=172.64.150.28".to_owned(),
+                "*.oaiusercontent.com=10.0.0.1".to_owned(),
+            ],
+            &["*.openai.com=tls://1.1.1.1#cloudflare-dns.com".to_owned()],
+            vec![],
+        )
+        .unwrap();
+
+        assert_eq!(config.host("chat.openai.com").map(|ips| ips.len()), Some(2));
+        assert!(config.host("files.oaiusercontent.com").is_some());
+        assert!(config.host("oaiusercontent.com").is_none());
+        assert_eq!(config.rule("api.openai.com"), Some(0));
+        assert_eq!(config.rule("openai.com"), None);
+        assert_eq!(config.rule("evilopenai.com"), None);
+    }
+}
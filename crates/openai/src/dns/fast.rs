@@ -1,16 +1,28 @@
 use std::{
+    cmp::Reverse,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
-    time::Instant,
+    sync::{atomic::Ordering, RwLock},
+    time::{Duration, Instant},
 };
 
 use futures::future::join_all;
-use tokio::sync::OnceCell;
 use trust_dns_resolver::{
     config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
     TokioAsyncResolver,
 };
 
-pub(super) const FASTEST_DNS_CONFIG: OnceCell<ResolverConfig> = OnceCell::const_new();
+static FASTEST_DNS_CONFIG: RwLock<Option<ResolverConfig>> = RwLock::new(None);
+
+/// Hosts ninja contacts, the name servers are benchmarked against them and the upstream hosts
+const HOSTS: &[&str] = &[
+    "chat.openai.com",
+    "api.openai.com",
+    "auth0.openai.com",
+    "tcr9i.chat.openai.com",
+    "tcr9i.openai.com",
+    "openai-api.arkoselabs.com",
+    "files.oaiusercontent.com",
+];
 
 /// IP addresses for Tencent Public DNS
 pub const TENCENT_IPS: &[IpAddr] = &[
@@ -49,67 +61,168 @@ impl ResolverConfigExt for ResolverConfig {
     }
 }
 
-/// Fastest DNS resolver
-pub async fn load_fastest_dns(enabled: bool) -> anyhow::Result<()> {
+/// Current fastest DNS group
+pub(super) fn fastest() -> Option<ResolverConfig> {
+    FASTEST_DNS_CONFIG
+        .read()
+        .expect("fastest dns lock poisoned")
+        .clone()
+}
+
+/// Benchmark of a name server group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Score {
+    /// Hosts the group resolved
+    resolved: usize,
+    /// Mean latency of the resolved hosts
+    latency: Duration,
+}
+
+/// Fastest DNS resolver, the current resolver is kept if no name server answers
+pub async fn load_fastest_dns(enabled: bool) {
     if !enabled {
-        return Ok(());
+        return;
     }
 
-    let mut tasks = Vec::new();
+    // The configured name servers compete one by one, otherwise the built-in groups
+    let dns = super::dns_config();
+    let configs = match dns.servers.is_empty() {
+        true => vec![
+            ResolverConfig::google(),
+            ResolverConfig::quad9(),
+            ResolverConfig::cloudflare(),
+            ResolverConfig::tencent(),
+            ResolverConfig::aliyun(),
+        ],
+        false => dns
+            .servers
+            .iter()
+            .map(|ns| super::config::resolver_config(std::slice::from_ref(ns)))
+            .collect(),
+    };
 
     let mut opts = ResolverOpts::default();
     opts.ip_strategy = trust_dns_resolver::config::LookupIpStrategy::Ipv4AndIpv6;
 
-    let configs = vec![
-        ResolverConfig::google(),
-        ResolverConfig::quad9(),
-        ResolverConfig::cloudflare(),
-        ResolverConfig::tencent(),
-        ResolverConfig::aliyun(),
-    ];
+    let hosts = HOSTS
+        .iter()
+        .map(|h| h.to_string())
+        .chain(dns.upstream_hosts.iter().cloned())
+        .collect::<Vec<_>>();
 
-    for config in configs {
+    let mut tasks = Vec::new();
+    for config in &configs {
         let resolver = TokioAsyncResolver::tokio(config.clone(), opts.clone());
+        let hosts = &hosts;
         let task = async move {
-            let start = Instant::now();
-            let ips = resolver.lookup_ip("chat.openai.com").await?;
-            let elapsed = start.elapsed();
-            let ips = ips.iter().collect::<Vec<_>>();
-            tracing::debug!("Fastest DNS resovler: {ips:?} ({elapsed:?})");
-            Ok((elapsed, config))
+            let group = name_servers(config);
+            let lookups = join_all(hosts.iter().map(|host| async {
+                let start = Instant::now();
+                let lookup = resolver.lookup_ip(host.as_str()).await;
+                (lookup, start.elapsed())
+            }))
+            .await;
+
+            let (mut resolved, mut total) = (0, Duration::ZERO);
+            for (host, (lookup, elapsed)) in hosts.iter().zip(lookups) {
+                match lookup {
+                    Ok(ips) => {
+                        tracing::debug!(
+                            "DNS {group:?} resolved {host}: {:?} ({elapsed:?})",
+                            ips.iter().collect::<Vec<_>>()
+                        );
+                        resolved += 1;
+                        total += elapsed;
+                    }
+                    Err(err) => tracing::debug!("DNS {group:?} failed to resolve {host}: {err}"),
+                }
+            }
+            Score {
+                resolved,
+                latency: total / resolved.max(1) as u32,
+            }
         };
         tasks.push(task);
     }
 
-    // Join all tasks and return the fastest DNS
-    let r = join_all(tasks)
-        .await
-        .into_iter()
-        .collect::<anyhow::Result<Vec<_>>>()?;
+    let scores = join_all(tasks).await;
+    let (score, conf) = match rank(&scores) {
+        Some(index) => (scores[index], configs[index].clone()),
+        None => {
+            tracing::warn!("Fastest DNS benchmark failed: no name server resolved any host");
+            return;
+        }
+    };
+
+    let fastest_dns_group = name_servers(&conf);
+    let mut fastest = FASTEST_DNS_CONFIG
+        .write()
+        .expect("fastest dns lock poisoned");
+    let changed = fastest
+        .as_ref()
+        .map_or(true, |current| name_servers(current) != fastest_dns_group);
+    if changed {
+        tracing::info!(
+            "Fastest DNS group ({}/{} hosts, {:?}):\n* {}",
+            score.resolved,
+            hosts.len(),
+            score.latency,
+            fastest_dns_group.join("\n* ")
+        );
+        // Set fastest dns group, the resolvers are rebuilt with it
+        *fastest = Some(conf);
+        super::GENERATION.fetch_add(1, Ordering::Relaxed);
+    }
+}
 
-    let (elapsed, conf) = r
-        .into_iter()
-        .min_by_key(|(elapsed, _)| *elapsed)
-        .ok_or_else(|| anyhow::anyhow!("No fastest dns"))?;
+/// The group resolving the most hosts wins, the lowest mean latency breaks ties.
+/// Groups resolving no host are left out
+fn rank(scores: &[Score]) -> Option<usize> {
+    scores
+        .iter()
+        .enumerate()
+        .filter(|(_, score)| score.resolved > 0)
+        .min_by_key(|(_, score)| (Reverse(score.resolved), score.latency))
+        .map(|(index, _)| index)
+}
+
+/// Re-benchmark the name servers every interval
+pub async fn periodic_benchmark(interval: u64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(interval.max(1)));
+    // The first tick completes immediately, the startup benchmark has already run
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        load_fastest_dns(true).await;
+    }
+}
 
-    // '\n*' split fastest_dns_group
-    let mut fastest_dns_group = conf
+fn name_servers(config: &ResolverConfig) -> Vec<String> {
+    let mut name_servers = config
         .name_servers()
         .iter()
-        .map(|ns| ns.socket_addr.to_string())
+        .map(|ns| format!("{}/{}", ns.socket_addr, ns.protocol))
         .collect::<Vec<_>>();
-
     // this removes all duplicates
-    fastest_dns_group.dedup();
+    name_servers.dedup();
+    name_servers
+}
 
-    tracing::info!(
-        "Fastest DNS group ({elapsed:?}):\n* {}",
-        fastest_dns_group.join("\n* ")
-    );
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    // Set fastest dns group
-    FASTEST_DNS_CONFIG
-        .set(conf)
-        .map_err(|_| anyhow::anyhow!("Failed to set fastest dns group"))?;
-    Ok(())
+    #[test]
+    fn test_rank() {
+        let score = |resolved, millis| Score {
+            resolved,
+            latency: Duration::from_millis(millis),
+        };
+
+        // Resolving more hosts beats a lower latency
+        let scores = [score(5, 10), score(7, 50), score(7, 30), score(0, 0)];
+        assert_eq!(rank(&scores), Some(2));
+        assert_eq!(rank(&[score(0, 0), score(0, 0)]), None);
+        assert_eq!(rank(&[]), None);
+    }
 }
@@ -1,24 +1,62 @@
 //! DNS resolution via the [trust_dns_resolver](https://github.com/bluejekyll/trust-dns) crate
+mod config;
 pub mod fast;
 
 use hyper::client::connect::dns::Name;
 use reqwest::dns::{Addrs, Resolve, Resolving};
 use tokio::sync::OnceCell;
-use trust_dns_resolver::config::{LookupIpStrategy, NameServerConfigGroup};
+use trust_dns_resolver::config::{LookupIpStrategy, NameServerConfig, NameServerConfigGroup};
 pub use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
 use trust_dns_resolver::{lookup_ip::LookupIpIntoIter, system_conf, TokioAsyncResolver};
 
-use std::io;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+
+use self::config::DnsConfig;
+use crate::context::args::Args;
+
+/// DNS settings of the client resolvers
+static DNS_CONFIG: OnceLock<DnsConfig> = OnceLock::new();
+
+/// Bumped when the fastest name servers change, the resolvers built before are rebuilt
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Init the upstream name servers, host overrides, host rules and resolver cache
+pub fn init(args: &Args) -> anyhow::Result<()> {
+    let mut config = DnsConfig::new(
+        &args.dns_servers,
+        &args.dns_hosts,
+        &args.dns_rules,
+        args.upstreams
+            .iter()
+            .filter_map(|u| u.base_url.domain().map(ToOwned::to_owned))
+            .collect(),
+    )?;
+    config.cache_size = args.dns_cache_size;
+    config.min_ttl = args.dns_min_ttl.map(Duration::from_secs);
+    config.max_ttl = args.dns_max_ttl.map(Duration::from_secs);
+    config.negative_ttl = args.dns_negative_ttl.map(Duration::from_secs);
+
+    DNS_CONFIG
+        .set(config)
+        .map_err(|_| anyhow::anyhow!("DNS config is already initialized"))
+}
+
+fn dns_config() -> &'static DnsConfig {
+    DNS_CONFIG.get_or_init(DnsConfig::default)
+}
 
 /// Wrapper around an `AsyncResolver`, which implements the `Resolve` trait.
 #[derive(Debug, Clone)]
 pub(crate) struct TrustDnsResolver {
     /// Since we might not have been called in the context of a
     /// Tokio Runtime in initialization, so we must delay the actual
-    /// construction of the resolver.
-    state: Arc<OnceCell<TokioAsyncResolver>>,
+    /// construction of the resolver. It is rebuilt when the fastest name servers change.
+    state: Arc<RwLock<Option<(usize, Arc<TokioAsyncResolver>)>>>,
+    /// Resolvers of the host rules
+    rules: Arc<Vec<OnceCell<TokioAsyncResolver>>>,
     /// The DNS strategy to use when resolving addresses.
     ip_strategy: LookupIpStrategy,
     /// Use fastest DNS resolver
@@ -30,11 +68,25 @@ impl TrustDnsResolver {
     /// which reads from `/etc/resolve.conf`.
     pub(crate) fn new(ip_strategy: LookupIpStrategy, fastest_dns: bool) -> Self {
         Self {
-            state: Arc::new(OnceCell::new()),
+            state: Arc::new(RwLock::new(None)),
+            rules: Arc::new(dns_config().rules.iter().map(|_| OnceCell::new()).collect()),
             ip_strategy,
             fastest_dns,
         }
     }
+
+    /// Resolver of the unmatched hosts
+    fn resolver(&self) -> Arc<TokioAsyncResolver> {
+        let generation = GENERATION.load(Ordering::Relaxed);
+        if let Some((g, resolver)) = self.state.read().expect("resolver lock poisoned").as_ref() {
+            if *g == generation {
+                return resolver.clone();
+            }
+        }
+        let resolver = Arc::new(new_resolver(self.ip_strategy, self.fastest_dns));
+        *self.state.write().expect("resolver lock poisoned") = Some((generation, resolver.clone()));
+        resolver
+    }
 }
 
 struct SocketAddrs {
@@ -45,13 +97,30 @@ impl Resolve for TrustDnsResolver {
     fn resolve(&self, name: Name) -> Resolving {
         let resolver = self.clone();
         Box::pin(async move {
-            let resolver = resolver
-                .state
-                .get_or_try_init(|| async {
-                    new_resolver(resolver.ip_strategy, resolver.fastest_dns)
-                })
-                .await?;
-            let lookup = resolver.lookup_ip(name.as_str()).await?;
+            let config = dns_config();
+
+            // Static host overrides skip the lookup
+            if let Some(ips) = config.host(name.as_str()) {
+                let addrs: Addrs = Box::new(
+                    ips.to_vec()
+                        .into_iter()
+                        .map(|ip_addr| SocketAddr::new(ip_addr, 0)),
+                );
+                return Ok(addrs);
+            }
+
+            let rule = config
+                .rule(name.as_str())
+                .and_then(|index| Some((resolver.rules.get(index)?, &config.rules[index].1)));
+            let lookup = match rule {
+                Some((cell, servers)) => {
+                    cell.get_or_init(|| async { new_rule_resolver(servers, resolver.ip_strategy) })
+                        .await
+                        .lookup_ip(name.as_str())
+                        .await?
+                }
+                None => resolver.resolver().lookup_ip(name.as_str()).await?,
+            };
             let addrs: Addrs = Box::new(SocketAddrs {
                 iter: lookup.into_iter(),
             });
@@ -68,46 +137,113 @@ impl Iterator for SocketAddrs {
     }
 }
 
-/// Create a new resolver with the default configuration,
-/// which reads from `/etc/resolve.conf`.
-fn new_resolver(
-    ip_strategy: LookupIpStrategy,
-    fastest_dns: bool,
-) -> io::Result<TokioAsyncResolver> {
-    // If we can't read the system conf, just use the defaults.
-    let (mut config, mut opts) = match system_conf::read_system_conf() {
-        Ok((config, opts)) => (config, opts),
-        Err(err) => {
-            tracing::warn!("Error reading DNS system conf: {}", err);
-            // Use Google DNS, Cloudflare DNS and Quad9 DNS
-            let mut group = NameServerConfigGroup::new();
-
-            // Google DNS
-            group.extend(NameServerConfigGroup::google().into_inner());
-
-            // Cloudflare DNS
-            group.extend(NameServerConfigGroup::cloudflare().into_inner());
-
-            // Quad9 DNS
-            group.extend(NameServerConfigGroup::quad9().into_inner());
-
-            let config = ResolverConfig::from_parts(None, vec![], group);
-            (config, ResolverOpts::default())
-        }
+/// Create a new resolver of the configured name servers,
+/// or the default configuration which reads from `/etc/resolve.conf`.
+fn new_resolver(ip_strategy: LookupIpStrategy, fastest_dns: bool) -> TokioAsyncResolver {
+    let dns = dns_config();
+    let (mut config, mut opts) = match dns.servers.is_empty() {
+        false => (
+            config::resolver_config(&dns.servers),
+            ResolverOpts::default(),
+        ),
+        // If we can't read the system conf, just use the defaults.
+        true => match system_conf::read_system_conf() {
+            Ok((config, opts)) => (config, opts),
+            Err(err) => {
+                tracing::warn!("Error reading DNS system conf: {}", err);
+                (fallback_config(), ResolverOpts::default())
+            }
+        },
     };
 
-    // Use built-in fastest DNS group
+    // Use the fastest DNS group
     if fastest_dns {
-        config = fast::FASTEST_DNS_CONFIG
-            .get()
-            .cloned()
-            .unwrap_or_else(|| config)
+        config = fast::fastest().unwrap_or(config)
     }
 
+    dns.apply(&mut opts);
     // Check /ect/hosts file before dns requery (only works for unix like OS)
     opts.use_hosts_file = true;
     // The ip_strategy for the Resolver to use when lookup Ipv4 or Ipv6 addresses
     opts.ip_strategy = ip_strategy;
 
-    Ok(TokioAsyncResolver::tokio(config, opts))
+    TokioAsyncResolver::tokio(config, opts)
+}
+
+/// Create a new resolver of the host rule name servers
+fn new_rule_resolver(
+    servers: &[NameServerConfig],
+    ip_strategy: LookupIpStrategy,
+) -> TokioAsyncResolver {
+    let mut opts = ResolverOpts::default();
+    dns_config().apply(&mut opts);
+    opts.ip_strategy = ip_strategy;
+    TokioAsyncResolver::tokio(config::resolver_config(servers), opts)
+}
+
+/// Use Google DNS, Cloudflare DNS and Quad9 DNS
+fn fallback_config() -> ResolverConfig {
+    let mut group = NameServerConfigGroup::new();
+
+    // Google DNS
+    group.extend(NameServerConfigGroup::google().into_inner());
+
+    // Cloudflare DNS
+    group.extend(NameServerConfigGroup::cloudflare().into_inner());
+
+    // Quad9 DNS
+    group.extend(NameServerConfigGroup::quad9().into_inner());
+
+    ResolverConfig::from_parts(None, vec![], group)
+}
+
+/// Resolver reported by the diagnostics
+#[derive(serde::Serialize)]
+pub(crate) struct ResolverInfo {
+    /// fastest, custom, system or fallback
+    pub kind: &'static str,
+    pub name_servers: Vec<String>,
+    /// Host overrides
+    pub hosts: Vec<String>,
+    /// Host rules and their name servers
+    pub rules: Vec<String>,
+}
+
+/// Describe the resolver the clients use
+pub(crate) fn resolver_info(fastest_dns: bool) -> ResolverInfo {
+    let dns = dns_config();
+    let (kind, config) = match (fastest_dns, fast::fastest()) {
+        (true, Some(config)) => ("fastest", config),
+        _ if !dns.servers.is_empty() => ("custom", config::resolver_config(&dns.servers)),
+        _ => match system_conf::read_system_conf() {
+            Ok((config, _)) => ("system", config),
+            Err(_) => ("fallback", fallback_config()),
+        },
+    };
+
+    let mut name_servers = describe(config.name_servers());
+    name_servers.dedup();
+    let hosts = dns
+        .hosts
+        .iter()
+        .map(|(host, ips)| format!("{host}={ips:?}"))
+        .collect();
+    let rules = dns
+        .rules
+        .iter()
+        .map(|(pattern, servers)| format!("{pattern}={}", describe(servers).join(",")))
+        .collect();
+    ResolverInfo {
+        kind,
+        name_servers,
+        hosts,
+        rules,
+    }
+}
+
+fn describe(servers: &[NameServerConfig]) -> Vec<String> {
+    servers
+        .iter()
+        .map(|ns| format!("{}/{}", ns.socket_addr, ns.protocol))
+        .collect()
 }
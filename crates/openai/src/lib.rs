@@ -1,3 +1,4 @@
+pub mod accesslog;
 pub mod arkose;
 pub mod auth;
 pub mod chatgpt;
@@ -5,17 +6,21 @@ pub mod client;
 mod constant;
 pub mod context;
 mod dns;
+pub mod egress;
 pub mod eventsource;
 pub mod gpt_model;
 pub mod homedir;
 mod log;
+pub mod metrics;
 pub mod platform;
 pub mod proxy;
 
 #[cfg(feature = "serve")]
 pub mod serve;
 pub mod token;
+pub mod tokenizer;
 pub mod unescape;
+pub mod upstream;
 pub mod urldecoding;
 pub mod uuid;
 
@@ -0,0 +1,178 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::arkose;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Latency buckets (seconds), streamed conversations last for minutes
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0,
+];
+
+struct Metrics {
+    registry: Registry,
+    // route, method, status
+    http_requests: IntCounterVec,
+    // route, status
+    http_duration: HistogramVec,
+    // upstream
+    upstream_errors: IntCounterVec,
+    // type, solver, result
+    arkose_tokens: IntCounterVec,
+    // type, solver
+    arkose_duration: HistogramVec,
+    // limit
+    ratelimit_rejections: IntCounterVec,
+    // result
+    puid_cache: IntCounterVec,
+    // client, proxy
+    client_requests: IntCounterVec,
+    // result
+    cf_clearances: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("ninja".to_owned()), None)
+            .expect("Failed to create metrics registry");
+
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            let counter = IntCounterVec::new(Opts::new(name, help), labels)
+                .expect("Failed to create metrics counter");
+            registry
+                .register(Box::new(counter.clone()))
+                .expect("Failed to register metrics counter");
+            counter
+        };
+        let histogram = |name: &str, help: &str, labels: &[&str]| {
+            let opts = HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec());
+            let histogram =
+                HistogramVec::new(opts, labels).expect("Failed to create metrics histogram");
+            registry
+                .register(Box::new(histogram.clone()))
+                .expect("Failed to register metrics histogram");
+            histogram
+        };
+
+        Self {
+            http_requests: counter(
+                "http_requests_total",
+                "HTTP requests",
+                &["route", "method", "status"],
+            ),
+            http_duration: histogram(
+                "http_request_duration_seconds",
+                "HTTP request latency",
+                &["route", "status"],
+            ),
+            upstream_errors: counter(
+                "upstream_errors_total",
+                "Upstream request errors and 5xx responses",
+                &["upstream"],
+            ),
+            arkose_tokens: counter(
+                "arkose_tokens_total",
+                "Arkose token generations",
+                &["type", "solver", "result"],
+            ),
+            arkose_duration: histogram(
+                "arkose_token_duration_seconds",
+                "Arkose token generation latency",
+                &["type", "solver"],
+            ),
+            ratelimit_rejections: counter(
+                "ratelimit_rejections_total",
+                "Requests rejected by the rate limits",
+                &["limit"],
+            ),
+            puid_cache: counter("puid_cache_total", "PUID cache lookups", &["result"]),
+            client_requests: counter(
+                "client_requests_total",
+                "Requesting clients handed out per proxy",
+                &["client", "proxy"],
+            ),
+            cf_clearances: counter(
+                "cf_clearances_total",
+                "Cloudflare challenges solved for the upstream requests",
+                &["result"],
+            ),
+            registry,
+        }
+    }
+}
+
+fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// Record a served HTTP request
+pub fn http_request(route: &str, method: &str, status: u16, elapsed: Duration) {
+    let status = status.to_string();
+    let m = metrics();
+    m.http_requests
+        .with_label_values(&[route, method, &status])
+        .inc();
+    m.http_duration
+        .with_label_values(&[route, &status])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Record an upstream request error
+pub fn upstream_error(upstream: &str) {
+    metrics()
+        .upstream_errors
+        .with_label_values(&[upstream])
+        .inc();
+}
+
+/// Record an arkose token generation
+pub fn arkose_token(typed: arkose::Type, solver: &str, success: bool, elapsed: Duration) {
+    let typed = format!("{typed:?}").to_lowercase();
+    let result = if success { "success" } else { "failure" };
+    let m = metrics();
+    m.arkose_tokens
+        .with_label_values(&[&typed, solver, result])
+        .inc();
+    m.arkose_duration
+        .with_label_values(&[&typed, solver])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Record a request rejected by a rate limit
+pub fn ratelimit_rejection(limit: &str) {
+    metrics()
+        .ratelimit_rejections
+        .with_label_values(&[limit])
+        .inc();
+}
+
+/// Record a PUID cache lookup
+pub fn puid_cache(hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    metrics().puid_cache.with_label_values(&[result]).inc();
+}
+
+/// Record a requesting client handed out by the balancer
+pub fn client_request(client: &str, proxy: &str) {
+    metrics()
+        .client_requests
+        .with_label_values(&[client, proxy])
+        .inc();
+}
+
+/// Record a Cloudflare challenge solving
+pub fn cf_clearance(success: bool) {
+    let result = if success { "success" } else { "failure" };
+    metrics().cf_clearances.with_label_values(&[result]).inc();
+}
+
+/// Encode the metrics in the Prometheus text format
+pub fn gather() -> anyhow::Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&metrics().registry.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
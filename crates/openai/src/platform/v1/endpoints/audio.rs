@@ -0,0 +1,75 @@
+use crate::platform::v1::api::{file_from_disk_to_form_part, Client};
+use crate::platform::v1::error::APIError;
+use crate::platform::v1::resources::audio::{
+    AudioTranscriptionParameters, AudioTranslationParameters,
+};
+use reqwest::multipart::Form;
+
+pub struct Audio<'a> {
+    pub client: &'a Client,
+}
+
+impl Client {
+    pub fn audio(&self) -> Audio {
+        Audio { client: self }
+    }
+}
+
+impl Audio<'_> {
+    /// Returns the response body in the requested `response_format`, JSON by default
+    pub async fn create_transcription(
+        &self,
+        parameters: AudioTranscriptionParameters,
+    ) -> Result<String, APIError> {
+        let mut form = Form::new()
+            .part("file", file_from_disk_to_form_part(parameters.file).await?)
+            .text("model", parameters.model);
+
+        if let Some(prompt) = parameters.prompt {
+            form = form.text("prompt", prompt);
+        }
+
+        if let Some(response_format) = parameters.response_format {
+            form = form.text("response_format", response_format.to_string());
+        }
+
+        if let Some(temperature) = parameters.temperature {
+            form = form.text("temperature", temperature.to_string());
+        }
+
+        if let Some(language) = parameters.language {
+            form = form.text("language", language);
+        }
+
+        self.client
+            .post_with_form("/audio/transcriptions", form)
+            .await
+    }
+
+    /// Translates the audio into English, returns the response body in the
+    /// requested `response_format`, JSON by default
+    pub async fn create_translation(
+        &self,
+        parameters: AudioTranslationParameters,
+    ) -> Result<String, APIError> {
+        let mut form = Form::new()
+            .part("file", file_from_disk_to_form_part(parameters.file).await?)
+            .text("model", parameters.model);
+
+        if let Some(prompt) = parameters.prompt {
+            form = form.text("prompt", prompt);
+        }
+
+        if let Some(response_format) = parameters.response_format {
+            form = form.text("response_format", response_format.to_string());
+        }
+
+        if let Some(temperature) = parameters.temperature {
+            form = form.text("temperature", temperature.to_string());
+        }
+
+        self.client
+            .post_with_form("/audio/translations", form)
+            .await
+    }
+}
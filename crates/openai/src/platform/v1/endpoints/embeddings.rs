@@ -0,0 +1,27 @@
+use crate::platform::v1::api::Client;
+use crate::platform::v1::error::APIError;
+use crate::platform::v1::resources::embedding::{EmbeddingParameters, EmbeddingResponse};
+
+pub struct Embeddings<'a> {
+    pub client: &'a Client,
+}
+
+impl Client {
+    pub fn embeddings(&self) -> Embeddings {
+        Embeddings { client: self }
+    }
+}
+
+impl Embeddings<'_> {
+    pub async fn create(
+        &self,
+        parameters: EmbeddingParameters,
+    ) -> Result<EmbeddingResponse, APIError> {
+        let response = self.client.post("/embeddings", &parameters).await?;
+
+        let embedding_response: EmbeddingResponse = serde_json::from_str(&response)
+            .map_err(|error| APIError::ParseError(error.to_string()))?;
+
+        Ok(embedding_response)
+    }
+}
@@ -0,0 +1,108 @@
+use crate::platform::v1::api::{file_from_disk_to_form_part, Client};
+use crate::platform::v1::error::APIError;
+use crate::platform::v1::resources::image::{
+    CreateImageParameters, CreateImageVariationParameters, EditImageParameters, ImageResponse,
+    ImageSize, ResponseFormat,
+};
+use reqwest::multipart::Form;
+
+pub struct Images<'a> {
+    pub client: &'a Client,
+}
+
+impl Client {
+    pub fn images(&self) -> Images {
+        Images { client: self }
+    }
+}
+
+impl Images<'_> {
+    pub async fn create(
+        &self,
+        parameters: CreateImageParameters,
+    ) -> Result<ImageResponse, APIError> {
+        let response = self.client.post("/images/generations", &parameters).await?;
+
+        parse_image_response(&response)
+    }
+
+    pub async fn edit(&self, parameters: EditImageParameters) -> Result<ImageResponse, APIError> {
+        let mut form = Form::new()
+            .part(
+                "image",
+                file_from_disk_to_form_part(parameters.image).await?,
+            )
+            .text("prompt", parameters.prompt);
+
+        if let Some(mask) = parameters.mask {
+            form = form.part("mask", file_from_disk_to_form_part(mask).await?);
+        }
+
+        let form = image_options(
+            form,
+            parameters.n,
+            parameters.size,
+            parameters.response_format,
+            parameters.user,
+        );
+
+        let response = self.client.post_with_form("/images/edits", form).await?;
+
+        parse_image_response(&response)
+    }
+
+    pub async fn variation(
+        &self,
+        parameters: CreateImageVariationParameters,
+    ) -> Result<ImageResponse, APIError> {
+        let form = Form::new().part(
+            "image",
+            file_from_disk_to_form_part(parameters.image).await?,
+        );
+
+        let form = image_options(
+            form,
+            parameters.n,
+            parameters.size,
+            parameters.response_format,
+            parameters.user,
+        );
+
+        let response = self
+            .client
+            .post_with_form("/images/variations", form)
+            .await?;
+
+        parse_image_response(&response)
+    }
+}
+
+fn image_options(
+    mut form: Form,
+    n: Option<u32>,
+    size: Option<ImageSize>,
+    response_format: Option<ResponseFormat>,
+    user: Option<String>,
+) -> Form {
+    if let Some(n) = n {
+        form = form.text("n", n.to_string());
+    }
+
+    if let Some(size) = size {
+        form = form.text("size", size.to_string());
+    }
+
+    if let Some(response_format) = response_format {
+        form = form.text("response_format", response_format.to_string());
+    }
+
+    if let Some(user) = user {
+        form = form.text("user", user);
+    }
+
+    form
+}
+
+fn parse_image_response(response: &str) -> Result<ImageResponse, APIError> {
+    serde_json::from_str(response).map_err(|error| APIError::ParseError(error.to_string()))
+}
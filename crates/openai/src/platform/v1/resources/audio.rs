@@ -0,0 +1,76 @@
+use crate::platform::v1::models::OpenAIModel;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+#[derive(Debug, Clone)]
+pub struct AudioTranscriptionParameters {
+    /// Path of a mp3, mp4, mpeg, mpga, m4a, wav or webm file
+    pub file: String,
+    pub model: String,
+    pub prompt: Option<String>,
+    pub response_format: Option<AudioOutputFormat>,
+    pub temperature: Option<f32>,
+    /// ISO-639-1 language of the input audio
+    pub language: Option<String>,
+}
+
+impl Default for AudioTranscriptionParameters {
+    fn default() -> Self {
+        AudioTranscriptionParameters {
+            file: "audio.mp3".to_string(),
+            model: OpenAIModel::Whisper1.to_string(),
+            prompt: None,
+            response_format: None,
+            temperature: None,
+            language: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioTranslationParameters {
+    /// Path of a mp3, mp4, mpeg, mpga, m4a, wav or webm file
+    pub file: String,
+    pub model: String,
+    pub prompt: Option<String>,
+    pub response_format: Option<AudioOutputFormat>,
+    pub temperature: Option<f32>,
+}
+
+impl Default for AudioTranslationParameters {
+    fn default() -> Self {
+        AudioTranslationParameters {
+            file: "audio.mp3".to_string(),
+            model: OpenAIModel::Whisper1.to_string(),
+            prompt: None,
+            response_format: None,
+            temperature: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioOutputFormat {
+    Json,
+    Text,
+    Srt,
+    VerboseJson,
+    Vtt,
+}
+
+impl Display for AudioOutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                AudioOutputFormat::Json => "json",
+                AudioOutputFormat::Text => "text",
+                AudioOutputFormat::Srt => "srt",
+                AudioOutputFormat::VerboseJson => "verbose_json",
+                AudioOutputFormat::Vtt => "vtt",
+            }
+        )
+    }
+}
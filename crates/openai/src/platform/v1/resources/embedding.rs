@@ -0,0 +1,48 @@
+use crate::platform::v1::models::OpenAIModel;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct EmbeddingParameters {
+    pub model: String,
+    pub input: EmbeddingInput,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+impl Default for EmbeddingParameters {
+    fn default() -> Self {
+        EmbeddingParameters {
+            model: OpenAIModel::TextEmbeddingAda002.to_string(),
+            input: EmbeddingInput::String("The food was delicious and the waiter...".to_string()),
+            user: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    String(String),
+    StringArray(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Embedding {
+    pub object: String,
+    pub embedding: Vec<f64>,
+    pub index: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: u32,
+    pub total_tokens: u32,
+}
@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CreateImageParameters {
+    pub prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<ImageSize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+impl Default for CreateImageParameters {
+    fn default() -> Self {
+        CreateImageParameters {
+            prompt: "A cute baby sea otter".to_string(),
+            n: None,
+            size: None,
+            response_format: None,
+            user: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EditImageParameters {
+    /// Path of a square PNG image less than 4MB
+    pub image: String,
+    /// Path of a PNG image whose transparent areas mark where `image` should be edited
+    pub mask: Option<String>,
+    pub prompt: String,
+    pub n: Option<u32>,
+    pub size: Option<ImageSize>,
+    pub response_format: Option<ResponseFormat>,
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateImageVariationParameters {
+    /// Path of a square PNG image less than 4MB
+    pub image: String,
+    pub n: Option<u32>,
+    pub size: Option<ImageSize>,
+    pub response_format: Option<ResponseFormat>,
+    pub user: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ImageSize {
+    #[serde(rename = "256x256")]
+    Size256X256,
+    #[serde(rename = "512x512")]
+    Size512X512,
+    #[serde(rename = "1024x1024")]
+    Size1024X1024,
+}
+
+impl Display for ImageSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ImageSize::Size256X256 => "256x256",
+                ImageSize::Size512X512 => "512x512",
+                ImageSize::Size1024X1024 => "1024x1024",
+            }
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Url,
+    B64Json,
+}
+
+impl Display for ResponseFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ResponseFormat::Url => "url",
+                ResponseFormat::B64Json => "b64_json",
+            }
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageResponse {
+    pub created: u32,
+    pub data: Vec<ImageData>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ImageData {
+    Url { url: String },
+    B64Json { b64_json: String },
+}
@@ -1,8 +1,12 @@
+pub mod audio;
 pub mod chat_completion;
 #[cfg(feature = "stream")]
 pub mod chat_completion_stream;
 pub mod completion;
 #[cfg(feature = "stream")]
 pub mod completion_stream;
+pub mod embedding;
+pub mod image;
 pub mod model;
+pub mod moderation;
 pub mod shared;
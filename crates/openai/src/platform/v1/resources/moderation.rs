@@ -0,0 +1,85 @@
+use crate::platform::v1::models::OpenAIModel;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ModerationParameters {
+    pub input: ModerationInput,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+impl Default for ModerationParameters {
+    fn default() -> Self {
+        ModerationParameters {
+            input: ModerationInput::String("I want to kill them.".to_string()),
+            model: Some(OpenAIModel::TextModerationLatest.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ModerationInput {
+    String(String),
+    StringArray(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModerationResponse {
+    pub id: String,
+    pub model: String,
+    pub results: Vec<ModerationResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModerationResult {
+    pub flagged: bool,
+    pub categories: ModerationCategories,
+    pub category_scores: ModerationCategoryScores,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModerationCategories {
+    pub hate: bool,
+    #[serde(rename = "hate/threatening")]
+    pub hate_threatening: bool,
+    #[serde(default)]
+    pub harassment: bool,
+    #[serde(default, rename = "harassment/threatening")]
+    pub harassment_threatening: bool,
+    #[serde(rename = "self-harm")]
+    pub self_harm: bool,
+    #[serde(default, rename = "self-harm/intent")]
+    pub self_harm_intent: bool,
+    #[serde(default, rename = "self-harm/instructions")]
+    pub self_harm_instructions: bool,
+    pub sexual: bool,
+    #[serde(rename = "sexual/minors")]
+    pub sexual_minors: bool,
+    pub violence: bool,
+    #[serde(rename = "violence/graphic")]
+    pub violence_graphic: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModerationCategoryScores {
+    pub hate: f64,
+    #[serde(rename = "hate/threatening")]
+    pub hate_threatening: f64,
+    #[serde(default)]
+    pub harassment: f64,
+    #[serde(default, rename = "harassment/threatening")]
+    pub harassment_threatening: f64,
+    #[serde(rename = "self-harm")]
+    pub self_harm: f64,
+    #[serde(default, rename = "self-harm/intent")]
+    pub self_harm_intent: f64,
+    #[serde(default, rename = "self-harm/instructions")]
+    pub self_harm_instructions: f64,
+    pub sexual: f64,
+    #[serde(rename = "sexual/minors")]
+    pub sexual_minors: f64,
+    pub violence: f64,
+    #[serde(rename = "violence/graphic")]
+    pub violence_graphic: f64,
+}
@@ -8,6 +8,8 @@ use url::Url;
 /// RandomIpv6 trait
 pub trait Ipv6CidrExt {
     fn random_ipv6(&self) -> IpAddr;
+    /// Address of the subnet derived from the key, the same key always gets the same address
+    fn hashed_ipv6(&self, key: &str) -> IpAddr;
 }
 
 impl Ipv6CidrExt for Ipv6Cidr {
@@ -19,6 +21,21 @@ impl Ipv6CidrExt for Ipv6Cidr {
         let host_part = (rand << prefix_len) >> prefix_len;
         IpAddr::V6((net_part | host_part).into())
     }
+
+    fn hashed_ipv6(&self, key: &str) -> IpAddr {
+        use sha2::{Digest, Sha256};
+
+        let digest = Sha256::digest(key.as_bytes());
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&digest[..16]);
+        let hash = u128::from_be_bytes(bytes);
+
+        let ipv6: u128 = self.first_address().into();
+        let host_mask = u128::MAX
+            .checked_shr(self.network_length() as u32)
+            .unwrap_or(0);
+        IpAddr::V6(((ipv6 & !host_mask) | (hash & host_mask)).into())
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -28,7 +45,7 @@ pub enum InnerProxy {
     Proxy(Url),
     /// Bind to interface, supports ipv4, ipv6
     Interface(IpAddr),
-    /// Bind to ipv6 subnet, ramdomly generate ipv6 address
+    /// Bind to ipv6 subnet, the address is derived from the request identity, random otherwise
     IPv6Subnet(Ipv6Cidr),
 }
 
@@ -42,7 +59,28 @@ pub enum Proxy {
     Arkose(InnerProxy),
 }
 
+/// Named proxy group the egress rules route requests to
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProxyGroup {
+    pub name: String,
+    /// Proxies of the group
+    #[serde(default)]
+    pub proxies: Vec<InnerProxy>,
+    /// Join a direct connection client to the group
+    #[serde(default)]
+    pub direct: bool,
+}
+
 impl Proxy {
+    /// The proxy regardless of the protocol it serves
+    pub fn inner(&self) -> &InnerProxy {
+        match self {
+            Proxy::All(inner) | Proxy::Api(inner) | Proxy::Auth(inner) | Proxy::Arkose(inner) => {
+                inner
+            }
+        }
+    }
+
     pub fn proto(&self) -> &'static str {
         match self {
             Proxy::All(_) => "All",
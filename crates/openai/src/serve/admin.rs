@@ -0,0 +1,314 @@
+use axum::extract::{Path, Query};
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::{delete, get, post};
+use axum::{Json, Router, TypedHeader};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use subtle::ConstantTimeEq;
+
+use crate::auth::model::AuthAccount;
+use crate::auth::provide::AuthProvider;
+use crate::auth::{OPENAI_OAUTH_TOKEN_URL, OPENAI_OAUTH_URL};
+use crate::context::apikey::{ApiKey, ApiKeyStore};
+use crate::context::args::Args;
+use crate::context::pool::AccountPool;
+use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::puid::token_identity;
+use crate::token::model::Token;
+use crate::{client, metrics, now_duration, with_context, URL_CHATGPT_API};
+
+pub(super) fn config(router: Router, args: &Args) -> Router {
+    let router = router
+        .route("/metrics", get(get_metrics))
+        .route("/admin/reload", post(post_reload))
+        .route("/admin/proxies", get(get_proxies))
+        .route("/admin/proxies/drain", post(post_drain_proxy))
+        .route("/admin/egress", get(get_egress));
+
+    let router = if args.pool_enable {
+        router
+            .route("/admin/accounts", get(get_accounts).post(post_account))
+            .route("/admin/accounts/:email", delete(delete_account))
+    } else {
+        router
+    };
+
+    if with_context!(api_keys).is_some() {
+        router
+            .route("/admin/keys", get(get_keys).post(post_key))
+            .route("/admin/keys/:key", delete(delete_key))
+    } else {
+        router
+    }
+}
+
+/// Admin requests must carry the auth key
+pub(super) fn check_admin(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<(), ResponseError> {
+    let auth_key = with_context!(auth_key)
+        .ok_or_else(|| ResponseError::Forbidden(ProxyError::AuthKeyRequired))?;
+    let bearer = bearer.ok_or_else(|| ResponseError::Unauthorized(ProxyError::AuthKeyRequired))?;
+    if !is_auth_key(&auth_key, bearer.token()) {
+        return Err(ResponseError::Forbidden(ProxyError::AuthKeyError));
+    }
+    Ok(())
+}
+
+/// Compare the token with the auth key in constant time
+pub(crate) fn is_auth_key(auth_key: &str, token: &str) -> bool {
+    auth_key.as_bytes().ct_eq(token.as_bytes()).into()
+}
+
+fn account_pool() -> Result<&'static AccountPool, ResponseError> {
+    with_context!(account_pool)
+        .ok_or_else(|| ResponseError::NotFound(ProxyError::NoAvailableAccount))
+}
+
+fn api_keys() -> Result<&'static ApiKeyStore, ResponseError> {
+    with_context!(api_keys).ok_or_else(|| ResponseError::NotFound(ProxyError::ApiKeyNotFound))
+}
+
+/// GET /metrics
+async fn get_metrics(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<impl IntoResponse, ResponseError> {
+    check_admin(bearer)?;
+    let body = metrics::gather().map_err(ResponseError::InternalServerError)?;
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body))
+}
+
+/// POST /admin/reload
+async fn post_reload(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<impl IntoResponse, ResponseError> {
+    check_admin(bearer)?;
+    let report = super::reload::reload().map_err(ResponseError::BadRequest)?;
+    Ok(Json(report))
+}
+
+/// GET /admin/proxies
+async fn get_proxies(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<impl IntoResponse, ResponseError> {
+    check_admin(bearer)?;
+    let status = with_context!()
+        .client_pools()
+        .into_iter()
+        .map(|(kind, pool)| (kind, pool.status()))
+        .collect::<HashMap<_, _>>();
+    Ok(Json(status))
+}
+
+/// Drain a proxy of every client pool, or put it back with `drain: false`
+#[derive(Deserialize)]
+struct DrainProxy {
+    proxy: String,
+    #[serde(default = "default_drain")]
+    drain: bool,
+}
+
+fn default_drain() -> bool {
+    true
+}
+
+/// POST /admin/proxies/drain
+async fn post_drain_proxy(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(body): Json<DrainProxy>,
+) -> Result<impl IntoResponse, ResponseError> {
+    check_admin(bearer)?;
+    let found = with_context!()
+        .client_pools()
+        .iter()
+        .any(|(_, pool)| pool.contains(&body.proxy));
+    if !found {
+        return Err(ResponseError::NotFound(ProxyError::ProxyNotFound));
+    }
+    client::set_drained(&body.proxy, body.drain);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct EgressQuery {
+    /// Access token email or api key identity
+    identity: Option<String>,
+}
+
+/// GET /admin/egress, the IPv6 of the identities with a live client,
+/// or the IPv6 the identity of the query is sent from
+async fn get_egress(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Query(query): Query<EgressQuery>,
+) -> Result<impl IntoResponse, ResponseError> {
+    check_admin(bearer)?;
+    let egress = with_context!()
+        .client_pools()
+        .into_iter()
+        .map(|(kind, pool)| match query.identity.as_deref() {
+            Some(identity) => (
+                kind,
+                pool.egress_ipv6(identity)
+                    .map(|address| client::Egress {
+                        identity: identity.to_owned(),
+                        address,
+                    })
+                    .into_iter()
+                    .collect(),
+            ),
+            None => (kind, pool.egress()),
+        })
+        .collect::<HashMap<_, Vec<_>>>();
+    Ok(Json(egress))
+}
+
+/// An account is added with a refresh token, a session token or the login credentials
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AddAccount {
+    RefreshToken { refresh_token: String },
+    SessionToken { session_token: String },
+    Login(AuthAccount),
+}
+
+/// GET /admin/accounts
+async fn get_accounts(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<impl IntoResponse, ResponseError> {
+    check_admin(bearer)?;
+    Ok(Json(account_pool()?.status()))
+}
+
+/// POST /admin/accounts
+async fn post_account(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(body): Json<AddAccount>,
+) -> Result<impl IntoResponse, ResponseError> {
+    check_admin(bearer)?;
+    let pool = account_pool()?;
+
+    let token = match body {
+        AddAccount::RefreshToken { refresh_token } => Token::try_from(
+            with_context!(
+                auth_client_for,
+                OPENAI_OAUTH_TOKEN_URL,
+                &token_identity(&refresh_token)
+            )
+            .do_refresh_token(&refresh_token)
+            .await?,
+        ),
+        AddAccount::SessionToken { session_token } => Token::try_from(
+            with_context!(
+                auth_client_for,
+                URL_CHATGPT_API,
+                &token_identity(&session_token)
+            )
+            .refresh_session(&session_token)
+            .await?,
+        ),
+        AddAccount::Login(account) => Token::try_from(
+            with_context!(auth_client_for, OPENAI_OAUTH_URL, &account.username)
+                .do_access_token(&account)
+                .await?,
+        ),
+    }
+    .map_err(ResponseError::BadRequest)?;
+
+    let resp = json!({
+        "email": token.email(),
+        "expires": token.expires(),
+    });
+    pool.insert(token)
+        .map_err(ResponseError::InternalServerError)?;
+    Ok(Json(resp))
+}
+
+/// DELETE /admin/accounts/:email
+async fn delete_account(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(email): Path<String>,
+) -> Result<impl IntoResponse, ResponseError> {
+    check_admin(bearer)?;
+    match account_pool()?
+        .remove(&email)
+        .map_err(ResponseError::InternalServerError)?
+    {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ResponseError::NotFound(ProxyError::AccountNotFound)),
+    }
+}
+
+/// Quota window defaults to a day
+fn default_window() -> u64 {
+    86400
+}
+
+/// Mint a key, all fields are optional
+#[derive(Deserialize)]
+struct MintKey {
+    #[serde(default)]
+    models: Vec<String>,
+    requests: Option<u64>,
+    tokens: Option<u64>,
+    #[serde(default = "default_window")]
+    window: u64,
+    expires: Option<i64>,
+    note: Option<String>,
+}
+
+/// GET /admin/keys
+async fn get_keys(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<impl IntoResponse, ResponseError> {
+    check_admin(bearer)?;
+    Ok(Json(api_keys()?.status()))
+}
+
+/// POST /admin/keys
+async fn post_key(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(body): Json<MintKey>,
+) -> Result<impl IntoResponse, ResponseError> {
+    check_admin(bearer)?;
+    if body.window == 0 {
+        return Err(ResponseError::BadRequest(ProxyError::InvalidParameter(
+            "window",
+        )));
+    }
+
+    let key = ApiKey {
+        key: ApiKey::generate(),
+        models: body.models,
+        requests: body.requests,
+        tokens: body.tokens,
+        window: body.window,
+        expires: body.expires,
+        note: body.note,
+        created: now_duration()
+            .map_err(ResponseError::InternalServerError)?
+            .as_secs() as i64,
+    };
+    api_keys()?
+        .insert(key.clone())
+        .map_err(ResponseError::InternalServerError)?;
+    Ok(Json(key))
+}
+
+/// DELETE /admin/keys/:key
+async fn delete_key(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(key): Path<String>,
+) -> Result<impl IntoResponse, ResponseError> {
+    check_admin(bearer)?;
+    match api_keys()?
+        .remove(&key)
+        .map_err(ResponseError::InternalServerError)?
+    {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ResponseError::NotFound(ProxyError::ApiKeyNotFound)),
+    }
+}
@@ -44,6 +44,40 @@ pub enum ProxyError {
     DeserializeError(serde_json::Error),
     #[error("Invalid access token")]
     InvalidAccessToken,
+    #[error("Invalid image url")]
+    InvalidImageUrl,
+    #[error("Image url must resolve to a public address")]
+    ImageUrlNotPublic,
+    #[error("Image exceeds {0} bytes")]
+    ImageTooLarge(usize),
+    #[error("Unsupported image format, expected png, jpeg, gif or webp")]
+    UnsupportedImageFormat,
+    #[error("Upload image error ({0})")]
+    UploadImageError(String),
+    #[error("Invalid parameter ({0})")]
+    InvalidParameter(&'static str),
+    #[error("Invalid parameter (n must be between 1 and {0})")]
+    InvalidChoices(usize),
+    #[error("The model `{0}` does not exist")]
+    ModelNotFound(String),
+    #[error("No available account in the pool")]
+    NoAvailableAccount,
+    #[error("Account not found")]
+    AccountNotFound,
+    #[error("Api key is invalid")]
+    ApiKeyInvalid,
+    #[error("Api key has expired")]
+    ApiKeyExpired,
+    #[error("GPT-4 message cap reached, {0} messages every {1} minutes")]
+    MessageCapReached(usize, u64),
+    #[error("Api key quota exceeded")]
+    ApiKeyQuotaExceeded,
+    #[error("Api key not found")]
+    ApiKeyNotFound,
+    #[error("Proxy not found")]
+    ProxyNotFound,
+    #[error("The model `{0}` is not allowed for the api key")]
+    ModelNotAllowed(String),
 
     /// get access token profile error
     #[error("Get access token profile error")]
@@ -54,6 +88,10 @@ pub enum ProxyError {
     CfMissingCaptcha,
     #[error("Cloudflare error ({0})")]
     CfError(reqwest::Error),
+    #[error("Cloudflare challenge of the upstream not cleared")]
+    CfChallenge,
+    #[error("Cloudflare clearance error ({0})")]
+    CfClearanceError(anyhow::Error),
 
     /// Request error
     #[error("Request error ({0})")]
@@ -0,0 +1,312 @@
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router, TypedHeader};
+use moka::sync::Cache;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+use url::Url;
+
+use super::admin::check_admin;
+use super::error::ResponseError;
+use super::reload;
+use super::WAN_ADDRESS;
+use crate::client::proxy_name;
+use crate::context::args::Args;
+use crate::context::arkose::har;
+use crate::proxy::InnerProxy;
+use crate::{context, dns, with_context};
+
+const REDACTED: &str = "***";
+
+/// Solver endpoint -> reachable, probes hit the cached result for a few seconds
+static REACHABLE: OnceLock<Cache<String, bool>> = OnceLock::new();
+
+pub(super) fn config(router: Router, args: &Args) -> Router {
+    let fastest_dns = args.fastest_dns;
+    router
+        .route("/healthz", get(get_healthz))
+        .route("/readyz", get(get_readyz))
+        .route(
+            "/admin/diagnostics",
+            get(move |bearer: Option<TypedHeader<Authorization<Bearer>>>| {
+                get_diagnostics(bearer, fastest_dns)
+            }),
+        )
+}
+
+/// GET /healthz
+async fn get_healthz() -> impl IntoResponse {
+    Json(json!({ "status": "ok" }))
+}
+
+/// GET /readyz
+async fn get_readyz() -> impl IntoResponse {
+    // Reading the context before it is initialized would initialize a default one
+    if !context::init::initialized() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "ready": false, "context": false })),
+        );
+    }
+
+    let missing_versions = with_context!(arkose_context)
+        .missing_versions()
+        .iter()
+        .map(|t| format!("{t:?}"))
+        .collect::<Vec<_>>();
+    let empty_har = with_context!(readyz_har)
+        .iter()
+        .filter(|t| har::har_count(t) == 0)
+        .map(|t| format!("{t:?}"))
+        .collect::<Vec<_>>();
+    let solver = match with_context!(arkose_solver) {
+        Some(solver) => Some(reachable(solver.endpoint()).await),
+        None => None,
+    };
+
+    let ready = missing_versions.is_empty() && empty_har.is_empty() && solver.unwrap_or(true);
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (
+        status,
+        Json(json!({
+            "ready": ready,
+            "context": true,
+            "arkose_missing_versions": missing_versions,
+            "har_empty": empty_har,
+            "solver_reachable": solver,
+        })),
+    )
+}
+
+/// GET /admin/diagnostics
+async fn get_diagnostics(
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    fastest_dns: bool,
+) -> Result<impl IntoResponse, ResponseError> {
+    check_admin(bearer)?;
+    Ok(Json(json!({
+        "config": config_view(&reload::current(), true),
+        "wan_address": WAN_ADDRESS.get(),
+        "dns": dns::resolver_info(fastest_dns),
+        "proxies": with_context!().proxy_health().await,
+    })))
+}
+
+/// Probe the client pools with the configured url, the auth clients are not probed
+pub(super) async fn periodic_probe() {
+    loop {
+        let args = reload::current();
+        tokio::time::sleep(Duration::from_secs(args.proxy_probe_interval.max(1))).await;
+        if let Some(url) = args.proxy_probe_url.as_deref() {
+            for (_, pool) in with_context!().client_pools() {
+                pool.health(url).await;
+            }
+        }
+    }
+}
+
+/// Any response means the endpoint is reachable
+async fn reachable(url: &str) -> bool {
+    let cache = REACHABLE.get_or_init(|| {
+        Cache::builder()
+            .time_to_live(Duration::from_secs(10))
+            .build()
+    });
+    if let Some(reachable) = cache.get(url) {
+        return reachable;
+    }
+
+    let reachable = with_context!(api_client)
+        .head(url)
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await
+        .is_ok();
+    cache.insert(url.to_owned(), reachable);
+    reachable
+}
+
+/// Effective config, with the keys and the proxy credentials redacted if `redact` is set
+pub(super) fn config_view(args: &Args, redact: bool) -> Value {
+    let secret = |v: &Option<String>| {
+        v.as_ref().map(|s| match redact {
+            true => REDACTED.to_owned(),
+            false => s.to_owned(),
+        })
+    };
+    let url_name = |url: &Url| match redact {
+        true => proxy_name(url),
+        false => url.to_string(),
+    };
+    let url = |v: &Option<String>| {
+        v.as_ref().map(|s| match Url::parse(s) {
+            Ok(url) => url_name(&url),
+            Err(_) => REDACTED.to_owned(),
+        })
+    };
+
+    let inner_name = |inner: &InnerProxy| match inner {
+        InnerProxy::Proxy(url) => url_name(url),
+        InnerProxy::Interface(ip) => format!("interface:{ip}"),
+        InnerProxy::IPv6Subnet(cidr) => format!("ipv6-subnet:{cidr}"),
+    };
+
+    let proxies = args
+        .proxies
+        .iter()
+        .map(|p| format!("{}={}", p.proto(), inner_name(p.inner())))
+        .collect::<Vec<_>>();
+
+    let proxy_groups = args
+        .proxy_groups
+        .iter()
+        .map(|g| {
+            let mut members = g.proxies.iter().map(inner_name).collect::<Vec<_>>();
+            if g.direct {
+                members.push("direct".to_owned());
+            }
+            (g.name.clone(), members)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let proxy_weights = args
+        .proxy_weights
+        .iter()
+        .map(|w| {
+            match w
+                .rsplit_once('=')
+                .and_then(|(proxy, weight)| Url::parse(proxy).ok().map(|url| (url, weight)))
+            {
+                Some((proxy, weight)) => format!("{}={weight}", url_name(&proxy)),
+                None => w.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let upstreams = args
+        .upstreams
+        .iter()
+        .map(|u| {
+            json!({
+                "models": u.models,
+                "base_url": url_name(&u.base_url),
+                "auth": u.auth,
+                "api_key": secret(&u.api_key),
+                "api_version": u.api_version,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut config = json!({
+        "bind": args.bind,
+        "concurrent_limit": args.concurrent_limit,
+        "timeout": args.timeout,
+        "connect_timeout": args.connect_timeout,
+        "tcp_keepalive": args.tcp_keepalive,
+        "no_keepalive": args.no_keepalive,
+        "pool_idle_timeout": args.pool_idle_timeout,
+        "cookie_store": args.cookie_store,
+        "fastest_dns": args.fastest_dns,
+        "fastest_dns_interval": args.fastest_dns_interval,
+        "dns_servers": args.dns_servers,
+        "dns_hosts": args.dns_hosts,
+        "dns_rules": args.dns_rules,
+        "dns_cache_size": args.dns_cache_size,
+        "dns_min_ttl": args.dns_min_ttl,
+        "dns_max_ttl": args.dns_max_ttl,
+        "dns_negative_ttl": args.dns_negative_ttl,
+        "enable_direct": args.enable_direct,
+        "proxy_strategy": args.proxy_strategy,
+        "proxy_weights": proxy_weights,
+        "proxy_probe_url": url(&args.proxy_probe_url),
+        "proxy_probe_interval": args.proxy_probe_interval,
+        "proxy_affinity": args.proxy_affinity,
+        "proxy_groups": proxy_groups,
+        "proxy_rules": args.proxy_rules,
+        "retry": args.retry,
+        "breaker_threshold": args.breaker_threshold,
+        "breaker_probe_interval": args.breaker_probe_interval,
+        "proxies": proxies,
+        "tls": args.tls_cert.is_some(),
+        "auth_key": secret(&args.auth_key),
+        "visitor_email_whitelist": args.visitor_email_whitelist.as_ref().map(|w| match redact {
+            true => json!(w.len()),
+            false => json!(w),
+        }),
+        "impersonate_uas": args
+            .impersonate_uas
+            .as_ref()
+            .map(|uas| uas.iter().map(|ua| format!("{ua:?}")).collect::<Vec<_>>()),
+        "enable_webui": args.enable_webui,
+        "enable_file_proxy": args.enable_file_proxy,
+        "enable_arkose_proxy": args.enable_arkose_proxy,
+        "cf_site_key": args.cf_site_key,
+        "cf_secret_key": secret(&args.cf_secret_key),
+        "cf_solver_endpoint": url(&args.cf_solver_endpoint),
+        "cf_solver_timeout": args.cf_solver_timeout,
+        "cf_clearance_ttl": args.cf_clearance_ttl,
+        "arkose_endpoint": url(&args.arkose_endpoint),
+        "arkose_har_dir": args.arkose_har_dir,
+        "arkose_gpt3_experiment": args.arkose_gpt3_experiment,
+        "arkose_gpt3_experiment_solver": args.arkose_gpt3_experiment_solver,
+        "arkose_solver": args.arkose_solver.as_ref().map(|s| json!({
+            "solver": s.solver,
+            "endpoint": url(&Some(s.endpoint().to_owned())),
+            "limit": s.limit,
+        })),
+        "arkose_solver_tguess_endpoint": url(&args.arkose_solver_tguess_endpoint),
+        "conv_reuse": args.conv_reuse,
+        "conv_history": args.conv_history,
+        "conv_store": args.conv_store,
+        "conv_ttl": args.conv_ttl,
+        "pool_enable": args.pool_enable,
+        "pool_strategy": args.pool_strategy,
+        "pool_cooldown": args.pool_cooldown,
+        "gpt4_cap": args.gpt4_cap,
+        "gpt4_cap_wait": args.gpt4_cap_wait,
+        "gpt4_cap_downgrade": args.gpt4_cap_downgrade,
+        "access_log": args.access_log,
+        "readyz_har": args.readyz_har,
+        "upstreams": upstreams,
+    });
+
+    #[cfg(feature = "limit")]
+    if let Some(config) = config.as_object_mut() {
+        config.insert(
+            "token_bucket".to_owned(),
+            json!({
+                "enable": args.tb_enable,
+                "strategy": args.tb_strategy,
+                "redis_url": url(&args.tb_redis_url),
+                "capacity": args.tb_capacity,
+                "fill_rate": args.tb_fill_rate,
+                "expired": args.tb_expired,
+                "keys": args.tb_keys,
+                "routes": args.tb_routes,
+                "trusted_proxies": args.tb_trusted_proxies,
+                "egress": args.tb_egress,
+            }),
+        );
+    }
+
+    #[cfg(feature = "preauth")]
+    if let Some(config) = config.as_object_mut() {
+        config.insert(
+            "preauth".to_owned(),
+            json!({
+                "bind": args.pbind,
+                "upstream": url(&args.pupstream),
+            }),
+        );
+    }
+
+    config
+}
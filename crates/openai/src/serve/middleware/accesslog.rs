@@ -0,0 +1,70 @@
+use crate::accesslog::{self, AccessLog, AccessRecord};
+use crate::{now_duration, with_context};
+use axum::{
+    body::{Bytes, HttpBody},
+    extract::MatchedPath,
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use rand::Rng;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const X_REQUEST_ID: &str = "x-request-id";
+
+/// Write a JSON access log line per request, the line is written once the response body
+/// is finished so the duration covers the whole stream
+pub(crate) async fn accesslog_middleware<B>(request: Request<B>, next: Next<B>) -> Response {
+    let log = match with_context!(access_log) {
+        Some(log) => log,
+        None => return next.run(request).await,
+    };
+
+    let start = Instant::now();
+    let request_id = format!("{:032x}", rand::thread_rng().gen::<u128>());
+    let record = Arc::new(Mutex::new(AccessRecord {
+        timestamp: now_duration().map_or(0, |d| d.as_millis()),
+        request_id: request_id.clone(),
+        method: request.method().to_string(),
+        route: request
+            .extensions()
+            .get::<MatchedPath>()
+            .map_or_else(|| request.uri().path(), |p| p.as_str())
+            .to_owned(),
+        ..Default::default()
+    }));
+
+    let mut resp = accesslog::scope(record.clone(), next.run(request)).await;
+    record.lock().expect("access record lock poisoned").status = resp.status().as_u16();
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        resp.headers_mut().insert(X_REQUEST_ID, value);
+    }
+
+    // The guard writes the line when the body is dropped after the last chunk
+    let guard = Guard { log, record, start };
+    resp.map(|body| {
+        axum::body::boxed(body.map_data(move |chunk: Bytes| {
+            let mut record = guard.record.lock().expect("access record lock poisoned");
+            if record.ttfb_ms.is_none() {
+                record.ttfb_ms = Some(guard.start.elapsed().as_millis());
+            }
+            chunk
+        }))
+    })
+}
+
+struct Guard {
+    log: &'static AccessLog,
+    record: Arc<Mutex<AccessRecord>>,
+    start: Instant,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let mut record = self.record.lock().expect("access record lock poisoned");
+        record.duration_ms = self.start.elapsed().as_millis();
+        record.email = record.email.take().map(|email| self.log.mask_email(email));
+        self.log.write(&record);
+    }
+}
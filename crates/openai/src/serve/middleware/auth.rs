@@ -1,11 +1,14 @@
 use crate::serve::error::{ProxyError, ResponseError};
 use crate::serve::whitelist;
-use crate::token;
-use axum::http::header;
+
+use super::pool;
+use crate::context::apikey::API_KEY_PREFIX;
+use crate::{token, with_context};
+use axum::http::{header, HeaderMap};
 use axum::{http::Request, middleware::Next, response::Response};
 
 pub(crate) async fn auth_middleware<B>(
-    request: Request<B>,
+    mut request: Request<B>,
     next: Next<B>,
 ) -> Result<Response, ResponseError> {
     // Allow access to the public folder
@@ -16,6 +19,26 @@ pub(crate) async fn auth_middleware<B>(
         return Ok(next.run(request).await);
     };
 
+    // Ninja issued api keys, the key is passed on to the limiter and the proxy
+    if let Some(key) = api_key(request.headers()) {
+        let key = with_context!(api_keys)
+            .and_then(|store| store.get(key))
+            .ok_or(ResponseError::Unauthorized(ProxyError::ApiKeyInvalid))?;
+        if key
+            .is_expired()
+            .map_err(ResponseError::InternalServerError)?
+        {
+            return Err(ResponseError::Unauthorized(ProxyError::ApiKeyExpired));
+        }
+        request.extensions_mut().insert(key);
+        return Ok(next.run(request).await);
+    }
+
+    // The auth key is dispatched to an account of the pool
+    if pool::is_pool_key(request.headers()) {
+        return Ok(next.run(request).await);
+    }
+
     // Check if the request has an authorization header
     let token = match request.headers().get(header::AUTHORIZATION) {
         Some(token) => token,
@@ -26,6 +49,8 @@ pub(crate) async fn auth_middleware<B>(
     match token::check_for_u8(token.as_bytes()) {
         Ok(Some(profile)) => {
             whitelist::check_whitelist(profile.email()).map_err(ResponseError::Forbidden)?;
+            // The proxy reads the verified profile instead of checking the token again
+            request.extensions_mut().insert(profile);
             Ok(next.run(request).await)
         }
         Ok(None) => {
@@ -35,3 +60,13 @@ pub(crate) async fn auth_middleware<B>(
         Err(err) => Err(ResponseError::Forbidden(err)),
     }
 }
+
+/// Get the ninja issued api key of the bearer
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .filter(|key| key.starts_with(API_KEY_PREFIX))
+}
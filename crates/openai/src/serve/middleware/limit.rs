@@ -1,25 +1,373 @@
+use crate::context::apikey::{ApiKey, QuotaStatus};
+use crate::context::reload::Reloadable;
 use crate::serve::error::{ProxyError, ResponseError};
+use crate::{metrics, tokenizer, with_context};
 use axum::{
+    body::{Bytes, HttpBody},
     extract::{ConnectInfo, State},
-    http::Request,
+    http::{header, HeaderMap, HeaderValue, Request},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
+use serde_json::Value;
 
-use super::tokenbucket::{TokenBucket, TokenBucketProvider};
+use super::limitkey::Limiter;
+use super::tokenbucket::BucketStatus;
+
+const X_RATELIMIT_LIMIT_REQUESTS: &str = "x-ratelimit-limit-requests";
+const X_RATELIMIT_REMAINING_REQUESTS: &str = "x-ratelimit-remaining-requests";
+const X_RATELIMIT_RESET_REQUESTS: &str = "x-ratelimit-reset-requests";
+const X_RATELIMIT_LIMIT_TOKENS: &str = "x-ratelimit-limit-tokens";
+const X_RATELIMIT_REMAINING_TOKENS: &str = "x-ratelimit-remaining-tokens";
+const X_RATELIMIT_RESET_TOKENS: &str = "x-ratelimit-reset-tokens";
+/// Largest response body buffered to read the usage from
+const MAX_USAGE_BODY: usize = 16 * 1024 * 1024;
 
 pub(crate) async fn limit_middleware<B>(
-    State(limit): State<std::sync::Arc<TokenBucketProvider>>,
+    State(limit): State<std::sync::Arc<Reloadable<Limiter>>>,
     ConnectInfo(socket_addr): ConnectInfo<std::net::SocketAddr>,
     request: Request<B>,
     next: Next<B>,
-) -> Result<Response, ResponseError> {
-    let addr = socket_addr.ip();
-    match limit.acquire(addr) {
-        Ok(condition) => match condition {
-            true => Ok(next.run(request).await),
-            false => Err(ResponseError::TooManyRequests(ProxyError::TooManyRequests)),
-        },
-        Err(err) => Err(ResponseError::BadGateway(err)),
+) -> Result<Response, ResponseError>
+where
+    B: HttpBody<Data = Bytes> + From<Bytes> + Send + Unpin + 'static,
+    B::Error: std::fmt::Display,
+{
+    let status = limit
+        .load()
+        .acquire(socket_addr.ip(), request.uri().path(), request.headers())
+        .await
+        .map_err(ResponseError::BadGateway)?;
+
+    // A request rejected by the token buckets does not count against the key quota
+    if let Some(status) = status.as_ref().filter(|s| !s.allowed) {
+        metrics::ratelimit_rejection("token_bucket");
+        let mut resp = ResponseError::TooManyRequests(ProxyError::TooManyRequests).into_response();
+        ratelimit_headers(resp.headers_mut(), status);
+        return Ok(resp);
+    }
+
+    // Ninja issued api keys are limited by their own quota
+    let key = request.extensions().get::<ApiKey>().cloned();
+    if let (Some(key), Some(store)) = (key.as_ref(), with_context!(api_keys)) {
+        let quota = store.acquire(key).map_err(ResponseError::BadGateway)?;
+        if !quota.allowed {
+            metrics::ratelimit_rejection("api_key");
+            let mut resp =
+                ResponseError::TooManyRequests(ProxyError::ApiKeyQuotaExceeded).into_response();
+            quota_headers(resp.headers_mut(), &quota);
+            return Ok(resp);
+        }
+    }
+
+    let mut resp = match key {
+        Some(key) if key.tokens.is_some() => {
+            let (request, prompt) = prompt_tokens(request).await?;
+            count_tokens(key.key, prompt, next.run(request).await)
+        }
+        _ => next.run(request).await,
+    };
+
+    if let Some(status) = status {
+        ratelimit_headers(resp.headers_mut(), &status);
+    }
+    Ok(resp)
+}
+
+/// Rate limit headers named as api.openai.com, `Retry-After` is added to 429
+fn ratelimit_headers(headers: &mut HeaderMap, status: &BucketStatus) {
+    let values = [
+        (X_RATELIMIT_LIMIT_REQUESTS, status.limit.to_string()),
+        (X_RATELIMIT_REMAINING_REQUESTS, status.remaining.to_string()),
+        (X_RATELIMIT_RESET_REQUESTS, reset_duration(status.reset)),
+    ];
+    for (name, value) in values {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            // Keep the limits reported by the upstream
+            headers.entry(name).or_insert(value);
+        }
+    }
+
+    if !status.allowed {
+        headers.insert(header::RETRY_AFTER, HeaderValue::from(status.retry_after));
+    }
+}
+
+/// Rate limit headers of the key quota window, `Retry-After` is the window reset
+fn quota_headers(headers: &mut HeaderMap, quota: &QuotaStatus) {
+    let reset = reset_duration(quota.reset);
+    let limits = [
+        (
+            quota.requests,
+            [
+                X_RATELIMIT_LIMIT_REQUESTS,
+                X_RATELIMIT_REMAINING_REQUESTS,
+                X_RATELIMIT_RESET_REQUESTS,
+            ],
+        ),
+        (
+            quota.tokens,
+            [
+                X_RATELIMIT_LIMIT_TOKENS,
+                X_RATELIMIT_REMAINING_TOKENS,
+                X_RATELIMIT_RESET_TOKENS,
+            ],
+        ),
+    ];
+    for (limit, [limit_name, remaining_name, reset_name]) in limits {
+        if let Some((limit, remaining)) = limit {
+            headers.insert(limit_name, HeaderValue::from(limit));
+            headers.insert(remaining_name, HeaderValue::from(remaining));
+            if let Ok(value) = HeaderValue::from_str(&reset) {
+                headers.insert(reset_name, value);
+            }
+        }
+    }
+    headers.insert(header::RETRY_AFTER, HeaderValue::from(quota.reset.max(1)));
+}
+
+/// Format seconds like api.openai.com, e.g. `1s`, `6m0s`, `1h0m0s`
+fn reset_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m{}s", secs / 60, secs % 60),
+        _ => format!("{}h{}m{}s", secs / 3600, secs % 3600 / 60, secs % 60),
+    }
+}
+
+/// Estimate the prompt tokens of the request body, in case the response has no usage
+async fn prompt_tokens<B>(request: Request<B>) -> Result<(Request<B>, u64), ResponseError>
+where
+    B: HttpBody<Data = Bytes> + From<Bytes> + Unpin,
+    B::Error: std::fmt::Display,
+{
+    let (parts, mut body) = request.into_parts();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| ResponseError::BadRequest(anyhow::anyhow!("{err}")))?;
+        bytes.extend_from_slice(&chunk);
+    }
+    let prompt =
+        serde_json::from_slice::<Value>(&bytes).map_or(0, |json| estimate_prompt_tokens(&json));
+    Ok((
+        Request::from_parts(parts, B::from(Bytes::from(bytes))),
+        prompt,
+    ))
+}
+
+/// Count the prompt text of chat messages, completion prompts and embedding inputs
+fn estimate_prompt_tokens(json: &Value) -> u64 {
+    let bpe = tokenizer::cl100k_base();
+    let count = |value: &Value| match value {
+        Value::String(text) => bpe.count(text),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|p| p.as_str().or_else(|| p.get("text").and_then(Value::as_str)))
+            .map(|text| bpe.count(text))
+            .sum(),
+        _ => 0,
+    };
+
+    let mut tokens = 0;
+    for message in json
+        .get("messages")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        // Every message follows <|start|>{role/name}\n{content}<|end|>\n
+        tokens += 3 + message.get("content").map_or(0, count);
+    }
+    for key in ["prompt", "input"] {
+        tokens += json.get(key).map_or(0, count);
+    }
+    tokens as u64
+}
+
+/// Count the tokens of the response against the token quota of the key once the body
+/// is done or dropped
+fn count_tokens(key: String, prompt: u64, resp: Response) -> Response {
+    let stream = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with("text/event-stream"));
+    let mut metered = Metered {
+        key,
+        counter: TokenCounter::new(prompt, stream),
+    };
+    resp.map(|body| {
+        axum::body::boxed(body.map_data(move |chunk: Bytes| {
+            metered.counter.feed(&chunk);
+            chunk
+        }))
+    })
+}
+
+/// Consumes the counted tokens of the key when the response body is dropped
+struct Metered {
+    key: String,
+    counter: TokenCounter,
+}
+
+impl Drop for Metered {
+    fn drop(&mut self) {
+        if let Some(store) = with_context!(api_keys) {
+            store.consume(&self.key, self.counter.finish());
+        }
+    }
+}
+
+/// Reads the usage of a chat completion, completion or embedding response, or
+/// collects the completion text to estimate the tokens if no usage arrives
+struct TokenCounter {
+    prompt: u64,
+    stream: bool,
+    buf: Vec<u8>,
+    overflow: bool,
+    usage: Option<u64>,
+    completion: String,
+}
+
+impl TokenCounter {
+    fn new(prompt: u64, stream: bool) -> Self {
+        Self {
+            prompt,
+            stream,
+            buf: Vec::new(),
+            overflow: false,
+            usage: None,
+            completion: String::new(),
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8]) {
+        if self.overflow {
+            return;
+        }
+        if self.buf.len() + chunk.len() > MAX_USAGE_BODY {
+            self.overflow = true;
+            self.buf = Vec::new();
+            return;
+        }
+        // Carriage returns only separate lines, json strings escape them
+        self.buf.extend(chunk.iter().filter(|b| **b != b'\r'));
+
+        if self.stream {
+            while let Some(end) = self.buf.windows(2).position(|w| w.eq(b"\n\n")) {
+                let event = self.buf.drain(..end + 2).collect::<Vec<_>>();
+                self.event(&event);
+            }
+        }
+    }
+
+    /// Parse the data lines of a server-sent event
+    fn event(&mut self, event: &[u8]) {
+        for line in event.split(|b| *b == b'\n') {
+            if let Some(json) = line
+                .strip_prefix(b"data:")
+                .and_then(|data| serde_json::from_slice::<Value>(data).ok())
+            {
+                self.json(&json);
+            }
+        }
+    }
+
+    fn json(&mut self, json: &Value) {
+        if let Some(total) = json.pointer("/usage/total_tokens").and_then(Value::as_u64) {
+            self.usage = Some(total);
+        }
+        for choice in json
+            .get("choices")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            for pointer in ["/delta/content", "/message/content", "/text"] {
+                if let Some(text) = choice.pointer(pointer).and_then(Value::as_str) {
+                    self.completion.push_str(text);
+                }
+            }
+            for message in ["delta", "message"] {
+                let calls = choice
+                    .get(message)
+                    .and_then(|m| m.get("tool_calls"))
+                    .and_then(Value::as_array);
+                for call in calls.into_iter().flatten() {
+                    if let Some(arguments) =
+                        call.pointer("/function/arguments").and_then(Value::as_str)
+                    {
+                        self.completion.push_str(arguments);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tokens of the response, the reported usage or the estimate
+    fn finish(&mut self) -> u64 {
+        let buf = std::mem::take(&mut self.buf);
+        if self.stream {
+            self.event(&buf);
+        } else if let Ok(json) = serde_json::from_slice::<Value>(&buf) {
+            self.json(&json);
+        }
+        self.usage.unwrap_or_else(|| {
+            self.prompt + tokenizer::cl100k_base().count(&self.completion) as u64
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{estimate_prompt_tokens, reset_duration, TokenCounter};
+    use crate::tokenizer;
+
+    #[test]
+    fn test_reset_duration() {
+        assert_eq!(reset_duration(1), "1s");
+        assert_eq!(reset_duration(360), "6m0s");
+        assert_eq!(reset_duration(3661), "1h1m1s");
+    }
+
+    #[test]
+    fn test_usage() {
+        let mut counter = TokenCounter::new(5, false);
+        counter.feed(br#"{"usage":{"prompt_tokens":9,"#);
+        counter.feed(br#""total_tokens":21}}"#);
+        assert_eq!(counter.finish(), 21);
+
+        // The usage of the final chunk is read across chunk boundaries
+        let mut counter = TokenCounter::new(5, true);
+        counter.feed(b"data: {\"choices\":[{\"delta\":{\"content\":\"hi\"}}]}\r\n\r\ndata: {\"usage\": {\"total_");
+        counter.feed(b"tokens\": 7}}\n\ndata: [DONE]\n\n");
+        assert_eq!(counter.finish(), 7);
+    }
+
+    #[test]
+    fn test_estimate() {
+        let bpe = tokenizer::cl100k_base();
+        let reply = r#"say \"total_tokens\": 1"#;
+
+        // The completion text can not pass for a usage
+        let mut counter = TokenCounter::new(5, true);
+        let chunk = serde_json::json!({"choices": [{"delta": {"content": reply}}]});
+        counter.feed(format!("data: {chunk}\n\ndata: [DONE]\n\n").as_bytes());
+        assert_eq!(counter.finish(), 5 + bpe.count(reply) as u64);
+
+        let mut counter = TokenCounter::new(0, false);
+        counter.feed(br#"{"choices":[{"message":{"content":"hello world"}}]}"#);
+        assert_eq!(counter.finish(), bpe.count("hello world") as u64);
+
+        let json = serde_json::json!({
+            "messages": [
+                {"role": "system", "content": "be brief"},
+                {"role": "user", "content": [{"type": "text", "text": "hello"}]}
+            ]
+        });
+        assert_eq!(
+            estimate_prompt_tokens(&json),
+            (6 + bpe.count("be brief") + bpe.count("hello")) as u64
+        );
     }
 }
@@ -0,0 +1,300 @@
+use axum::http::{header, HeaderMap, HeaderName};
+use base64::{engine::general_purpose, Engine};
+use cidr::IpCidr;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::tokenbucket::{BucketStatus, TokenBucket, TokenBucketProvider};
+use crate::token;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_REAL_IP: &str = "x-real-ip";
+
+/// Client identity a token bucket is keyed on
+#[derive(Clone, Debug, PartialEq)]
+pub enum LimitKey {
+    /// Client ip, the forwarded headers are trusted from the trusted proxies
+    Ip,
+    /// Access token email
+    Email,
+    /// Bearer token hash
+    Bearer,
+    /// Custom header value
+    Header(HeaderName),
+}
+
+impl FromStr for LimitKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(LimitKey::Ip),
+            "email" => Ok(LimitKey::Email),
+            "bearer" => Ok(LimitKey::Bearer),
+            _ => match s.strip_prefix("header:") {
+                Some(name) => Ok(LimitKey::Header(HeaderName::from_str(name)?)),
+                None => anyhow::bail!("limit key: {} is not supported", s),
+            },
+        }
+    }
+}
+
+/// Limit keys of the requests under a path prefix, e.g. `/v1=bearer,ip`
+#[derive(Clone, Debug, PartialEq)]
+struct LimitRoute {
+    path: String,
+    keys: Vec<LimitKey>,
+}
+
+impl FromStr for LimitRoute {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, keys) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("limit route: {} must be `path=key,key`", s))?;
+        Ok(LimitRoute {
+            path: path.to_owned(),
+            keys: parse_keys(keys.split(','))?,
+        })
+    }
+}
+
+fn parse_keys<'a>(keys: impl Iterator<Item = &'a str>) -> anyhow::Result<Vec<LimitKey>> {
+    let keys = keys
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+        .map(LimitKey::from_str)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if keys.is_empty() {
+        anyhow::bail!("limit keys must not be empty")
+    }
+    Ok(keys)
+}
+
+/// Token bucket limits composed of the limit keys of the request route
+pub struct Limiter {
+    bucket: Arc<TokenBucketProvider>,
+    keys: Vec<LimitKey>,
+    routes: Vec<LimitRoute>,
+    trusted_proxies: Vec<IpCidr>,
+    /// Limit the requests sent from each derived IPv6
+    egress: bool,
+}
+
+impl Limiter {
+    pub fn new(
+        bucket: impl Into<Arc<TokenBucketProvider>>,
+        keys: &[String],
+        routes: &[String],
+        trusted_proxies: Vec<IpCidr>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            bucket: bucket.into(),
+            // Limit by the client ip if no key is configured
+            keys: match keys.is_empty() {
+                true => vec![LimitKey::Ip],
+                false => parse_keys(keys.iter().map(String::as_str))?,
+            },
+            routes: routes
+                .iter()
+                .map(|r| LimitRoute::from_str(r))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            trusted_proxies,
+            egress: false,
+        })
+    }
+
+    /// Limit the requests sent from each derived IPv6 as well
+    pub fn with_egress(mut self, egress: bool) -> Self {
+        self.egress = egress;
+        self
+    }
+
+    /// Token buckets shared by the limiters rebuilt on reload
+    pub fn bucket(&self) -> Arc<TokenBucketProvider> {
+        self.bucket.clone()
+    }
+
+    /// Take a token from every bucket of the request, none is taken if a bucket is empty,
+    /// returns the most restrictive bucket state, none if the limit is disabled
+    pub async fn acquire(
+        &self,
+        peer: IpAddr,
+        path: &str,
+        headers: &HeaderMap,
+    ) -> anyhow::Result<Option<BucketStatus>> {
+        self.bucket.acquire(&self.keys(peer, path, headers)).await
+    }
+
+    /// Take a token from the bucket of the IPv6 a request is sent from, none if the limit is disabled
+    pub async fn acquire_egress(&self, egress: IpAddr) -> anyhow::Result<Option<BucketStatus>> {
+        match self.egress {
+            true => self.bucket.acquire(&[format!("egress:{egress}")]).await,
+            false => Ok(None),
+        }
+    }
+
+    /// Bucket keys of the request, the longest matching route prefix wins.
+    /// A key missing from the request falls back to the client ip.
+    fn keys(&self, peer: IpAddr, path: &str, headers: &HeaderMap) -> Vec<String> {
+        let keys = self
+            .routes
+            .iter()
+            .filter(|r| path.starts_with(&r.path))
+            .max_by_key(|r| r.path.len())
+            .map_or(&self.keys, |r| &r.keys);
+
+        let mut values = keys
+            .iter()
+            .map(|key| {
+                let value = match key {
+                    LimitKey::Ip => None,
+                    LimitKey::Email => bearer(headers)
+                        .and_then(|b| token::check(b).ok().flatten())
+                        .map(|profile| format!("email:{}", profile.email())),
+                    LimitKey::Bearer => bearer(headers).map(|b| {
+                        let hash = Sha256::digest(b.as_bytes());
+                        format!("bearer:{}", general_purpose::URL_SAFE_NO_PAD.encode(hash))
+                    }),
+                    LimitKey::Header(name) => headers
+                        .get(name)
+                        .and_then(|v| v.to_str().ok())
+                        .map(|v| format!("header:{name}:{v}")),
+                };
+                value.unwrap_or_else(|| format!("ip:{}", self.client_ip(peer, headers)))
+            })
+            .collect::<Vec<_>>();
+        values.dedup();
+        values
+    }
+
+    /// Client ip, the forwarded headers are only trusted when the peer is a trusted proxy
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+
+        if let Some(forwarded) = headers.get(X_FORWARDED_FOR).and_then(|v| v.to_str().ok()) {
+            // The right-most address not appended by a trusted proxy is the client
+            let mut ip = peer;
+            for addr in forwarded.rsplit(',') {
+                match addr.trim().parse::<IpAddr>() {
+                    Ok(addr) => ip = addr,
+                    Err(_) => break,
+                }
+                if !self.is_trusted(ip) {
+                    break;
+                }
+            }
+            return ip;
+        }
+
+        headers
+            .get(X_REAL_IP)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(peer)
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|cidr| cidr.contains(&ip))
+    }
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::serve::middleware::tokenbucket::MemTokenBucket;
+    use axum::http::HeaderValue;
+
+    fn limiter(keys: &[&str], routes: &[&str]) -> Limiter {
+        Limiter::new(
+            TokenBucketProvider::Mem(MemTokenBucket::new(true, 1, 0, 60)),
+            &keys.iter().map(|k| k.to_string()).collect::<Vec<_>>(),
+            &routes.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+            vec![IpCidr::from_str("10.0.0.0/8").unwrap()],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let limiter = limiter(&["ip"], &[]);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("1.1.1.1, 2.2.2.2, 10.0.0.2"),
+        );
+        let proxy = "10.0.0.1".parse().unwrap();
+        let client = "3.3.3.3".parse().unwrap();
+        assert_eq!(
+            limiter.client_ip(proxy, &headers),
+            "2.2.2.2".parse::<IpAddr>().unwrap()
+        );
+        // Untrusted peers can not spoof the client ip
+        assert_eq!(limiter.client_ip(client, &headers), client);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(X_REAL_IP, HeaderValue::from_static("4.4.4.4"));
+        assert_eq!(
+            limiter.client_ip(proxy, &headers),
+            "4.4.4.4".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_keys() {
+        let limiter = limiter(&["ip"], &["/v1=header:x-user,ip", "/v1/chat=bearer"]);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-user", HeaderValue::from_static("alice"));
+        let peer = "3.3.3.3".parse().unwrap();
+        assert_eq!(
+            limiter.keys(peer, "/backend-api/me", &headers),
+            ["ip:3.3.3.3"]
+        );
+        assert_eq!(
+            limiter.keys(peer, "/v1/models", &headers),
+            ["header:x-user:alice", "ip:3.3.3.3"]
+        );
+        // Without a bearer the key falls back to the client ip
+        assert_eq!(
+            limiter.keys(peer, "/v1/chat/completions", &headers),
+            ["ip:3.3.3.3"]
+        );
+        let status = limiter.acquire(peer, "/v1/models", &headers).await.unwrap();
+        assert!(status.is_some_and(|s| s.allowed && s.remaining == 0));
+        let status = limiter.acquire(peer, "/v1/models", &headers).await.unwrap();
+        assert!(status.is_some_and(|s| !s.allowed && s.retry_after == 60));
+    }
+
+    #[tokio::test]
+    async fn test_acquire_all_or_none() {
+        let limiter = limiter(&["ip"], &["/v1=header:x-user,ip"]);
+        let headers = |user: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-user", HeaderValue::from_static(user));
+            headers
+        };
+        let peer = "3.3.3.3".parse().unwrap();
+        let other = "4.4.4.4".parse().unwrap();
+
+        let status = limiter.acquire(peer, "/v1/models", &headers("alice")).await;
+        assert!(status.unwrap().is_some_and(|s| s.allowed));
+        // The ip bucket is empty, the bucket of bob keeps its token
+        let status = limiter.acquire(peer, "/v1/models", &headers("bob")).await;
+        assert!(status.unwrap().is_some_and(|s| !s.allowed));
+        let status = limiter.acquire(other, "/v1/models", &headers("bob")).await;
+        assert!(status.unwrap().is_some_and(|s| s.allowed));
+    }
+}
@@ -0,0 +1,55 @@
+use crate::metrics;
+use axum::{
+    body::{Bytes, HttpBody},
+    extract::MatchedPath,
+    http::{Method, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::time::Instant;
+
+/// Count the served requests and their latency by the matched route
+pub(crate) async fn metrics_middleware<B>(request: Request<B>, next: Next<B>) -> Response {
+    // Label by the route pattern to keep the path cardinality bounded
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("other", |p| p.as_str())
+        .to_owned();
+    let method = request.method().clone();
+    let start = Instant::now();
+
+    let resp = next.run(request).await;
+    let observed = Observed {
+        route,
+        method,
+        status: resp.status().as_u16(),
+        start,
+    };
+    // Streamed responses are observed once the body ends
+    resp.map(|body| {
+        axum::body::boxed(body.map_data(move |chunk: Bytes| {
+            let _ = &observed;
+            chunk
+        }))
+    })
+}
+
+/// Records the request when the response body is dropped
+struct Observed {
+    route: String,
+    method: Method,
+    status: u16,
+    start: Instant,
+}
+
+impl Drop for Observed {
+    fn drop(&mut self) {
+        metrics::http_request(
+            &self.route,
+            self.method.as_str(),
+            self.status,
+            self.start.elapsed(),
+        );
+    }
+}
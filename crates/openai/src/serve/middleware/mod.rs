@@ -1,6 +1,11 @@
+pub mod accesslog;
 pub mod auth;
 pub mod csrf;
 #[cfg(feature = "limit")]
 pub mod limit;
 #[cfg(feature = "limit")]
+pub mod limitkey;
+pub mod metrics;
+pub mod pool;
+#[cfg(feature = "limit")]
 pub mod tokenbucket;
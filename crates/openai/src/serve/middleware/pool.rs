@@ -0,0 +1,75 @@
+use crate::context::apikey::ApiKey;
+use crate::serve::admin::is_auth_key;
+use crate::serve::error::{ProxyError, ResponseError};
+use crate::{token, with_context};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::{http::Request, middleware::Next, response::Response};
+use std::cell::Cell;
+
+tokio::task_local! {
+    /// Status of the ChatGPT response to the pool account request served by the current task
+    static ORIGIN_STATUS: Cell<Option<StatusCode>>;
+}
+
+/// Record the status ChatGPT answered the request with, only it may quarantine the account
+pub(crate) fn origin_status(status: StatusCode) {
+    let _ = ORIGIN_STATUS.try_with(|s| s.set(Some(status)));
+}
+
+/// Check if the request is authenticated with the auth key and the account pool is enabled
+pub(crate) fn is_pool_key(headers: &HeaderMap) -> bool {
+    if with_context!(account_pool).is_none() {
+        return false;
+    }
+
+    match (with_context!(auth_key), headers.get(header::AUTHORIZATION)) {
+        (Some(auth_key), Some(value)) => value.to_str().map_or(false, |v| {
+            is_auth_key(&auth_key, v.trim_start_matches("Bearer "))
+        }),
+        _ => false,
+    }
+}
+
+/// Dispatch the request authenticated with the auth key or a ninja issued api key
+/// to an account of the pool, the account is quarantined
+/// if ChatGPT rejects it with 401 or 429
+pub(crate) async fn pool_middleware<B>(
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ResponseError> {
+    let pool = match with_context!(account_pool) {
+        Some(pool)
+            if is_pool_key(request.headers()) || request.extensions().get::<ApiKey>().is_some() =>
+        {
+            pool
+        }
+        _ => return Ok(next.run(request).await),
+    };
+
+    let account = pool.next().ok_or(ResponseError::ServiceUnavailable(
+        ProxyError::NoAvailableAccount,
+    ))?;
+    request.headers_mut().insert(
+        header::AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {}", account.access_token()))
+            .map_err(ResponseError::InternalServerError)?,
+    );
+    if let Ok(Some(profile)) = token::check(account.access_token()) {
+        request.extensions_mut().insert(profile);
+    }
+
+    // Local rejections and third-party upstream responses do not count against the account
+    let (resp, status) = ORIGIN_STATUS
+        .scope(Cell::new(None), async {
+            let resp = next.run(request).await;
+            (resp, ORIGIN_STATUS.with(Cell::get))
+        })
+        .await;
+    if matches!(
+        status,
+        Some(StatusCode::UNAUTHORIZED | StatusCode::TOO_MANY_REQUESTS)
+    ) {
+        pool.quarantine(account.email());
+    }
+    Ok(resp)
+}
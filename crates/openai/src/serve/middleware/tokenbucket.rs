@@ -1,14 +1,52 @@
 use moka::sync::Cache;
+use redis::aio::ConnectionManager;
 use serde::{Deserialize, Serialize};
-use std::net::IpAddr;
-use std::sync::{Arc, OnceLock};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::Duration;
+use tokio::sync::OnceCell;
 
 use crate::homedir::home_dir;
 use crate::{context, debug, error, now_duration};
 
-pub trait TokenBucket: Send + Sync {
-    fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool>;
+#[trait_variant::make(TokenBucket: Send)]
+pub trait LocalTokenBucket {
+    /// Take a token from the bucket of every limit key, only if each of them has one left,
+    /// returns the state of the bucket with the fewest tokens, none if the limit is disabled
+    async fn acquire(&self, keys: &[String]) -> anyhow::Result<Option<BucketStatus>>;
+}
+
+/// Bucket state after taking a token
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BucketStatus {
+    /// A token is taken
+    pub allowed: bool,
+    /// Bucket capacity
+    pub limit: u32,
+    /// Tokens left
+    pub remaining: u32,
+    /// Seconds until the bucket is full
+    pub reset: u64,
+    /// Seconds until the next token, zero if tokens are left
+    pub retry_after: u64,
+}
+
+impl BucketStatus {
+    fn new(allowed: bool, capacity: u32, remaining: u32, fill_rate: u32, expired: u32) -> Self {
+        // Without refilling, an idle bucket is full again once it expires
+        let seconds = |tokens: u32| match fill_rate {
+            0 => expired as u64,
+            rate => (tokens as u64).div_ceil(rate as u64),
+        };
+        Self {
+            allowed,
+            limit: capacity,
+            remaining,
+            reset: seconds(capacity.saturating_sub(remaining)),
+            retry_after: if remaining > 0 { 0 } else { seconds(1) },
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -16,6 +54,7 @@ pub trait TokenBucket: Send + Sync {
 pub enum Strategy {
     Mem,
     ReDB,
+    Redis,
 }
 
 impl Default for Strategy {
@@ -31,6 +70,7 @@ impl std::str::FromStr for Strategy {
         match s {
             "mem" => Ok(Strategy::Mem),
             "redb" => Ok(Strategy::ReDB),
+            "redis" => Ok(Strategy::Redis),
             _ => anyhow::bail!("storage policy: {} is not supported", s),
         }
     }
@@ -42,19 +82,31 @@ struct BucketState {
     last_time: u64,
 }
 
+/// Tokens of the bucket refilled since the last time
+fn refill(tokens: u32, last_time: u64, now: u64, capacity: u32, fill_rate: u32) -> u32 {
+    let elapsed = u32::try_from(now.saturating_sub(last_time)).unwrap_or(u32::MAX);
+    tokens
+        .saturating_add(elapsed.saturating_mul(fill_rate))
+        .min(capacity)
+}
+
 pub struct MemTokenBucket {
     enable: bool,
     /// token bucket capacity `capacity`
     capacity: u32,
     /// token bucket fill rate `fill_rate`
     fill_rate: u32,
-    /// ip -> token backet
-    buckets: moka::sync::Cache<IpAddr, BucketState>,
+    /// token bucket expired `expired`
+    expired: u32,
+    /// limit key -> token backet
+    buckets: moka::sync::Cache<String, BucketState>,
+    /// The buckets of a request are checked and taken from at once
+    lock: Mutex<()>,
 }
 
 impl MemTokenBucket {
     pub fn new(enable: bool, capacity: u32, fill_rate: u32, expired: u32) -> Self {
-        let buckets: Cache<IpAddr, BucketState> = Cache::builder()
+        let buckets: Cache<String, BucketState> = Cache::builder()
             .max_capacity(65535)
             .time_to_idle(Duration::from_secs(expired as u64))
             .build();
@@ -62,40 +114,56 @@ impl MemTokenBucket {
             enable,
             capacity,
             fill_rate,
+            expired,
             buckets,
+            lock: Mutex::new(()),
         }
     }
 }
 
 impl TokenBucket for MemTokenBucket {
-    fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool> {
-        if !self.enable {
-            return Ok(true);
+    async fn acquire(&self, keys: &[String]) -> anyhow::Result<Option<BucketStatus>> {
+        if !self.enable || keys.is_empty() {
+            return Ok(None);
         }
 
         let now_timestamp = now_duration()?.as_secs();
-
-        let mut bucket = self
-            .buckets
-            .entry(ip)
-            .or_insert(BucketState {
-                tokens: self.capacity,
-                last_time: now_timestamp,
+        let _guard = self.lock.lock().expect("token bucket lock poisoned");
+
+        let mut buckets = keys
+            .iter()
+            .map(|key| {
+                let mut bucket = self.buckets.get(key).unwrap_or(BucketState {
+                    tokens: self.capacity,
+                    last_time: now_timestamp,
+                });
+                bucket.tokens = refill(
+                    bucket.tokens,
+                    bucket.last_time,
+                    now_timestamp,
+                    self.capacity,
+                    self.fill_rate,
+                );
+                bucket.last_time = now_timestamp;
+                bucket
             })
-            .into_value();
-
-        let elapsed = now_timestamp - bucket.last_time;
-        let tokens_to_add = (elapsed as u32) * self.fill_rate;
-        bucket.tokens = (bucket.tokens + tokens_to_add).min(self.capacity);
-        bucket.last_time = now_timestamp;
-
-        if bucket.tokens > 0 {
-            bucket.tokens -= 1;
-            self.buckets.insert(ip, bucket);
-            Ok(true)
-        } else {
-            Ok(false)
+            .collect::<Vec<_>>();
+
+        let allowed = buckets.iter().all(|b| b.tokens > 0);
+        if allowed {
+            for (key, bucket) in keys.iter().zip(buckets.iter_mut()) {
+                bucket.tokens -= 1;
+                self.buckets.insert(key.to_owned(), bucket.clone());
+            }
         }
+        let remaining = buckets.iter().map(|b| b.tokens).min().unwrap_or_default();
+        Ok(Some(BucketStatus::new(
+            allowed,
+            self.capacity,
+            remaining,
+            self.fill_rate,
+            self.expired,
+        )))
     }
 }
 
@@ -105,16 +173,58 @@ use native_model::{native_model, Model};
 
 static DATABASE_BUILDER: OnceLock<DatabaseBuilder> = OnceLock::new();
 
+/// Bucket of a client ip, the state before limit keys
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 #[native_model(id = 1, version = 1)]
 #[native_db]
-struct ReDBBucketState {
+struct LegacyBucketState {
     #[primary_key]
     ip: u128,
     tokens: u32,
     last_time: u64,
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[native_model(id = 1, version = 2, from = LegacyBucketState)]
+#[native_db]
+struct ReDBBucketState {
+    #[primary_key]
+    key: String,
+    tokens: u32,
+    last_time: u64,
+}
+
+impl From<LegacyBucketState> for ReDBBucketState {
+    fn from(legacy: LegacyBucketState) -> Self {
+        // IPv4 addresses were stored as 32-bit numbers
+        let ip = match u32::try_from(legacy.ip) {
+            Ok(ip) => IpAddr::from(Ipv4Addr::from(ip)),
+            Err(_) => IpAddr::from(Ipv6Addr::from(legacy.ip)),
+        };
+        Self {
+            key: format!("ip:{ip}"),
+            tokens: legacy.tokens,
+            last_time: legacy.last_time,
+        }
+    }
+}
+
+impl From<ReDBBucketState> for LegacyBucketState {
+    fn from(state: ReDBBucketState) -> Self {
+        let ip = match state.key.strip_prefix("ip:").map(IpAddr::from_str) {
+            Some(Ok(IpAddr::V4(ip))) => u32::from(ip).into(),
+            Some(Ok(IpAddr::V6(ip))) => ip.into(),
+            _ => 0,
+        };
+        Self {
+            ip,
+            tokens: state.tokens,
+            last_time: state.last_time,
+        }
+    }
+}
+
+/// Token bucket stored in the local ReDB database
 #[derive(typed_builder::TypedBuilder)]
 pub struct RedisTokenBucket<'a> {
     enable: bool,
@@ -122,38 +232,47 @@ pub struct RedisTokenBucket<'a> {
     capacity: u32,
     /// token bucket fill rate `fill_rate`
     fill_rate: u32,
+    /// token bucket expired `expired`
+    expired: u32,
     /// native db
     db: Arc<native_db::Database<'a>>,
 }
 
 impl<'a> RedisTokenBucket<'a> {
-    pub fn new(enable: bool, capacity: u32, fill_rate: u32, expired: u32) -> Self {
+    pub fn new(enable: bool, capacity: u32, fill_rate: u32, expired: u32) -> Result<Self> {
         // create database
         let builder = DATABASE_BUILDER.get_or_init(|| {
             let mut builder = DatabaseBuilder::new();
+            builder
+                .define::<LegacyBucketState>()
+                .expect("define table failed");
             builder
                 .define::<ReDBBucketState>()
                 .expect("define table failed");
             builder
         });
 
-        let db = builder
-            .create(
-                home_dir()
-                    .expect("Failed to get home directory")
-                    .join(context::WORKER_DIR)
-                    .join("token_bucket.db"),
-            )
-            .expect("create database failed");
+        let db = builder.create(
+            home_dir()
+                .ok_or_else(|| anyhow::anyhow!("Failed to get home directory"))?
+                .join(context::WORKER_DIR)
+                .join("token_bucket.db"),
+        )?;
+        // Buckets of a previous version are moved to the limit key table
+        let rw = db.rw_transaction()?;
+        rw.migrate::<ReDBBucketState>()?;
+        rw.commit()?;
+
         let db = Arc::new(db);
         // clear expired buckets every expired seconds
         clear_expired_buckets_every(db.clone(), expired);
-        Self {
+        Ok(Self {
             enable,
             capacity,
             fill_rate,
+            expired,
             db,
-        }
+        })
     }
 }
 
@@ -211,91 +330,189 @@ fn clear_expired_buckets_every(db: Arc<Database<'static>>, expired: u32) {
 }
 
 impl TokenBucket for RedisTokenBucket<'_> {
-    fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool> {
-        if !self.enable {
-            return Ok(true);
+    async fn acquire(&self, keys: &[String]) -> anyhow::Result<Option<BucketStatus>> {
+        if !self.enable || keys.is_empty() {
+            return Ok(None);
         }
 
         let rw = self.db.rw_transaction()?;
-        let pk = ip_to_number(ip);
         let now_timestamp = now_duration()?.as_secs();
-        let mut bucket: ReDBBucketState = match rw.get().primary(pk)? {
-            Some(bucket) => bucket,
-            None => ReDBBucketState {
-                ip: pk,
-                tokens: self.capacity,
-                last_time: now_timestamp,
-            },
-        };
-
-        let elapsed = now_timestamp - bucket.last_time;
-        let tokens_to_add = (elapsed as u32) * self.fill_rate;
-        bucket.tokens = (bucket.tokens + tokens_to_add).min(self.capacity);
-        bucket.last_time = now_timestamp;
+        let mut buckets = Vec::with_capacity(keys.len());
+        for key in keys {
+            let mut bucket: ReDBBucketState = match rw.get().primary(key.to_owned())? {
+                Some(bucket) => bucket,
+                None => ReDBBucketState {
+                    key: key.to_owned(),
+                    tokens: self.capacity,
+                    last_time: now_timestamp,
+                },
+            };
+            bucket.tokens = refill(
+                bucket.tokens,
+                bucket.last_time,
+                now_timestamp,
+                self.capacity,
+                self.fill_rate,
+            );
+            bucket.last_time = now_timestamp;
+            buckets.push(bucket);
+        }
 
-        if bucket.tokens > 0 {
-            bucket.tokens -= 1;
-            rw.insert(bucket)?;
+        let allowed = buckets.iter().all(|b| b.tokens > 0);
+        let remaining = buckets
+            .iter()
+            .map(|b| b.tokens.saturating_sub(allowed.into()))
+            .min()
+            .unwrap_or_default();
+        if allowed {
+            for mut bucket in buckets {
+                bucket.tokens -= 1;
+                rw.insert(bucket)?;
+            }
             rw.commit()?;
-            Ok(true)
-        } else {
-            Ok(false)
         }
+        Ok(Some(BucketStatus::new(
+            allowed,
+            self.capacity,
+            remaining,
+            self.fill_rate,
+            self.expired,
+        )))
     }
 }
 
-fn ip_to_number(ip: IpAddr) -> u128 {
-    match ip {
-        IpAddr::V4(ipv4) => {
-            let octets = ipv4.octets();
-            ((octets[0] as u128) << 24)
-                + ((octets[1] as u128) << 16)
-                + ((octets[2] as u128) << 8)
-                + (octets[3] as u128)
+/// Refill and take a token from every bucket atomically, only if each of them has one left,
+/// the redis server time is shared by all instances.
+/// KEYS buckets, ARGV capacity, fill rate and expired seconds, the buckets never expire with 0,
+/// returns 1 if the tokens are taken and the fewest tokens left
+const REDIS_ACQUIRE_SCRIPT: &str = r#"
+local capacity = tonumber(ARGV[1])
+local fill_rate = tonumber(ARGV[2])
+local expired = tonumber(ARGV[3])
+local now = tonumber(redis.call('TIME')[1])
+local tokens = {}
+local allowed = 1
+for i, key in ipairs(KEYS) do
+    local bucket = redis.call('HMGET', key, 'tokens', 'last_time')
+    local last_time = tonumber(bucket[2]) or now
+    tokens[i] = math.min(capacity, (tonumber(bucket[1]) or capacity) + math.max(0, now - last_time) * fill_rate)
+    if tokens[i] <= 0 then
+        allowed = 0
+    end
+end
+local remaining = capacity
+for i, key in ipairs(KEYS) do
+    tokens[i] = tokens[i] - allowed
+    remaining = math.min(remaining, tokens[i])
+    redis.call('HSET', key, 'tokens', tokens[i], 'last_time', now)
+    if expired > 0 then
+        redis.call('EXPIRE', key, expired)
+    end
+end
+return {allowed, remaining}
+"#;
+
+/// Token bucket shared by the instances using the same redis server
+pub struct RedisServerTokenBucket {
+    enable: bool,
+    /// token bucket capacity `capacity`
+    capacity: u32,
+    /// token bucket fill rate `fill_rate`
+    fill_rate: u32,
+    /// idle buckets expire after `expired` seconds
+    expired: u32,
+    client: redis::Client,
+    /// Connected on first use, reconnects after a failure
+    conn: OnceCell<ConnectionManager>,
+    script: redis::Script,
+}
+
+impl RedisServerTokenBucket {
+    pub fn new(
+        enable: bool,
+        capacity: u32,
+        fill_rate: u32,
+        expired: u32,
+        url: &str,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            enable,
+            capacity,
+            fill_rate,
+            expired,
+            client: redis::Client::open(url)?,
+            conn: OnceCell::new(),
+            script: redis::Script::new(REDIS_ACQUIRE_SCRIPT),
+        })
+    }
+}
+
+impl TokenBucket for RedisServerTokenBucket {
+    async fn acquire(&self, keys: &[String]) -> anyhow::Result<Option<BucketStatus>> {
+        if !self.enable || keys.is_empty() {
+            return Ok(None);
         }
-        IpAddr::V6(ipv6) => {
-            let octets = ipv6.octets();
-            ((octets[0] as u128) << 120)
-                + ((octets[1] as u128) << 112)
-                + ((octets[2] as u128) << 104)
-                + ((octets[3] as u128) << 96)
-                + ((octets[4] as u128) << 88)
-                + ((octets[5] as u128) << 80)
-                + ((octets[6] as u128) << 72)
-                + ((octets[7] as u128) << 64)
-                + ((octets[8] as u128) << 56)
-                + ((octets[9] as u128) << 48)
-                + ((octets[10] as u128) << 40)
-                + ((octets[11] as u128) << 32)
-                + ((octets[12] as u128) << 24)
-                + ((octets[13] as u128) << 16)
-                + ((octets[14] as u128) << 8)
-                + (octets[15] as u128)
+
+        let mut conn = self
+            .conn
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await?
+            .clone();
+        let mut invocation = self.script.prepare_invoke();
+        for key in keys {
+            invocation.key(format!("ninja:tb:{key}"));
         }
+        let (allowed, tokens): (i32, u32) = invocation
+            .arg(self.capacity)
+            .arg(self.fill_rate)
+            .arg(self.expired)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(Some(BucketStatus::new(
+            allowed == 1,
+            self.capacity,
+            tokens,
+            self.fill_rate,
+            self.expired,
+        )))
     }
 }
 
 pub enum TokenBucketProvider {
     Mem(MemTokenBucket),
     ReDB(RedisTokenBucket<'static>),
+    Redis(RedisServerTokenBucket),
 }
 
-impl From<(Strategy, bool, u32, u32, u32)> for TokenBucketProvider {
-    fn from(value: (Strategy, bool, u32, u32, u32)) -> Self {
+impl TryFrom<(Strategy, bool, u32, u32, u32, Option<&str>)> for TokenBucketProvider {
+    type Error = anyhow::Error;
+
+    fn try_from(value: (Strategy, bool, u32, u32, u32, Option<&str>)) -> anyhow::Result<Self> {
         let strategy = match value.0 {
             Strategy::Mem => Self::Mem(MemTokenBucket::new(value.1, value.2, value.3, value.4)),
-            Strategy::ReDB => Self::ReDB(RedisTokenBucket::new(value.1, value.2, value.3, value.4)),
+            Strategy::ReDB => {
+                Self::ReDB(RedisTokenBucket::new(value.1, value.2, value.3, value.4)?)
+            }
+            Strategy::Redis => Self::Redis(RedisServerTokenBucket::new(
+                value.1,
+                value.2,
+                value.3,
+                value.4,
+                value
+                    .5
+                    .ok_or_else(|| anyhow::anyhow!("redis strategy requires the redis url"))?,
+            )?),
         };
-        strategy
+        Ok(strategy)
     }
 }
 
 impl TokenBucket for TokenBucketProvider {
-    fn acquire(&self, ip: IpAddr) -> anyhow::Result<bool> {
-        let condition = match self {
-            Self::Mem(t) => t.acquire(ip),
-            Self::ReDB(t) => t.acquire(ip),
-        };
-        Ok(condition?)
+    async fn acquire(&self, keys: &[String]) -> anyhow::Result<Option<BucketStatus>> {
+        match self {
+            Self::Mem(t) => TokenBucket::acquire(t, keys).await,
+            Self::ReDB(t) => TokenBucket::acquire(t, keys).await,
+            Self::Redis(t) => TokenBucket::acquire(t, keys).await,
+        }
     }
 }
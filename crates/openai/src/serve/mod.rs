@@ -1,9 +1,12 @@
+mod admin;
 mod error;
+mod health;
 mod middleware;
 #[cfg(feature = "preauth")]
 mod preauth;
 mod proxy;
 mod puid;
+mod reload;
 #[cfg(feature = "template")]
 mod router;
 mod signal;
@@ -12,20 +15,23 @@ mod whitelist;
 
 use self::proxy::ext::RequestExt;
 use self::proxy::ext::SendRequestExt;
+use self::proxy::models;
 use self::proxy::resp::response_convert;
+use self::puid::token_identity;
 use crate::arkose;
 use crate::arkose::ArkoseContext;
 use crate::arkose::ArkoseToken;
 use crate::auth::model::{AccessToken, AuthAccount, RefreshToken, SessionAccessToken};
 use crate::auth::provide::AuthProvider;
+use crate::auth::{OPENAI_OAUTH_REVOKE_URL, OPENAI_OAUTH_TOKEN_URL, OPENAI_OAUTH_URL};
 use crate::constant::API_AUTH_SESSION_COOKIE_KEY;
 use crate::context;
 use crate::context::args::Args;
+use crate::context::reload::Reloadable;
 use crate::dns;
 use crate::proxy::{InnerProxy, Proxy};
 use crate::serve::error::ProxyError;
 use crate::serve::error::ResponseError;
-use crate::serve::middleware::tokenbucket::{Strategy, TokenBucketProvider};
 use crate::{info, warn, with_context};
 use crate::{URL_CHATGPT_API, URL_PLATFORM_API};
 use axum::body::Body;
@@ -47,8 +53,8 @@ use axum_server::HttpConfig;
 use axum_server::{AddrIncomingConfig, Handle};
 use std::net::SocketAddr;
 use std::ops::Not;
-use std::str::FromStr;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tower_http::trace;
 use tracing::Level;
@@ -81,6 +87,15 @@ fn print_boot_message(inner: &Args) {
         "ArkoseLabs GPT-3.5 experiment solver: {}",
         inner.arkose_gpt3_experiment_solver
     );
+    info!("Conversation reuse: {}", inner.conv_reuse);
+    info!("Account pool: {}", inner.pool_enable);
+    info!("GPT-4 message cap: {}", inner.gpt4_cap);
+    inner.access_log.as_ref().map(|path| {
+        info!("Access log: {}", path.display());
+    });
+    inner.upstreams.iter().for_each(|u| {
+        info!("Upstream: {} -> {}", u.models.join(","), u.base_url);
+    });
     inner.arkose_solver.as_ref().map(|solver| {
         info!("ArkoseLabs solver: {:?}", solver.solver);
     });
@@ -105,16 +120,24 @@ fn print_boot_message(inner: &Args) {
     });
 }
 
-pub struct Serve(Args);
+pub use reload::ConfigLoader;
+
+pub struct Serve(Args, Option<(PathBuf, ConfigLoader)>);
 
 impl Serve {
     pub fn new(inner: Args) -> Self {
-        Self(inner)
+        Self(inner, None)
+    }
+
+    /// Hot-reload the config file
+    pub fn config(mut self, path: PathBuf, loader: ConfigLoader) -> Self {
+        self.1 = Some((path, loader));
+        self
     }
 
     /// from issue: https://github.com/hyperium/hyper/issues/3140
     #[tokio::main]
-    pub async fn run(self) -> anyhow::Result<()> {
+    pub async fn run(mut self) -> anyhow::Result<()> {
         tracing_subscriber::registry()
             .with(
                 tracing_subscriber::EnvFilter::try_from_default_env()
@@ -126,8 +149,11 @@ impl Serve {
         // print boot message
         print_boot_message(&self.0);
 
+        // init dns, the clients of the context resolve with it
+        dns::init(&self.0)?;
+
         // init context
-        context::init(self.0.clone());
+        context::init(self.0.clone())?;
 
         // init global layer provider
         let global_layer = tower::ServiceBuilder::new()
@@ -156,22 +182,19 @@ impl Serve {
             )))
             .layer(axum::extract::DefaultBodyLimit::max(200 * 1024 * 1024));
 
+        // init config reload
+        let limiter = Arc::new(Reloadable::new(reload::limiter(&self.0, None)?));
+        reload::init(self.0.clone(), self.1.take(), limiter.clone());
+
         // init auth layer provider
         let app_layer = {
-            let limit_context = TokenBucketProvider::from((
-                Strategy::from_str(self.0.tb_strategy.as_str())?,
-                self.0.tb_enable,
-                self.0.tb_capacity,
-                self.0.tb_fill_rate,
-                self.0.tb_expired,
-            ));
-
             tower::ServiceBuilder::new()
                 .layer(axum::middleware::from_fn(middleware::auth::auth_middleware))
                 .layer(axum::middleware::from_fn_with_state(
-                    Arc::new(limit_context),
+                    limiter,
                     middleware::limit::limit_middleware,
                 ))
+                .layer(axum::middleware::from_fn(middleware::pool::pool_middleware))
         };
 
         let router = Router::new()
@@ -187,6 +210,10 @@ impl Serve {
             .route("/auth/sess_token", post(post_sess_token))
             .route("/auth/billing", post(post_billing));
 
+        let router = admin::config(router, &self.0);
+
+        let router = health::config(router, &self.0);
+
         let router = router::config(
             // Enable arkose token endpoint proxy
             if self.0.enable_arkose_proxy {
@@ -196,7 +223,13 @@ impl Serve {
             },
             &self.0,
         )
-        .layer(global_layer);
+        .layer(global_layer)
+        .layer(axum::middleware::from_fn(
+            middleware::metrics::metrics_middleware,
+        ))
+        .layer(axum::middleware::from_fn(
+            middleware::accesslog::accesslog_middleware,
+        ));
 
         // Signal the server to shutdown using Handle.
         let handle = Handle::new();
@@ -205,7 +238,10 @@ impl Serve {
         tokio::spawn(signal::graceful_shutdown(handle.clone()));
 
         // Fast dns test
-        dns::fast::load_fastest_dns(self.0.fastest_dns).await?;
+        dns::fast::load_fastest_dns(self.0.fastest_dns).await;
+        if let (true, Some(interval)) = (self.0.fastest_dns, self.0.fastest_dns_interval) {
+            tokio::spawn(dns::fast::periodic_benchmark(interval));
+        }
 
         // check wan address.
         check_wan_address().await;
@@ -213,6 +249,14 @@ impl Serve {
         // upgrade arkose version.
         tokio::spawn(with_context!(arkose_context).periodic_upgrade());
 
+        // probe the client pools.
+        tokio::spawn(health::periodic_probe());
+
+        // refresh the account pool tokens.
+        if let Some(pool) = with_context!(account_pool) {
+            tokio::spawn(pool.periodic_refresh());
+        }
+
         // http server tcp keepalive
         let tcp_keepalive = Duration::from_secs(self.0.tcp_keepalive as u64 + 1);
 
@@ -296,9 +340,13 @@ impl Serve {
 async fn post_billing(
     TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
 ) -> Result<impl IntoResponse, ResponseError> {
-    match with_context!(auth_client)
-        .billing_credit_grants(bearer.token())
-        .await
+    match with_context!(
+        auth_client_for,
+        URL_PLATFORM_API,
+        &token_identity(bearer.token())
+    )
+    .billing_credit_grants(bearer.token())
+    .await
     {
         Ok(billing) => Ok(Json(billing)),
         Err(err) => Err(err.into()),
@@ -309,10 +357,14 @@ async fn post_billing(
 async fn post_refresh_session(
     TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
 ) -> Result<impl IntoResponse, ResponseError> {
-    let session_token = with_context!(auth_client)
-        .refresh_session(bearer.token())
-        .await
-        .map_err(ResponseError::BadRequest)?;
+    let session_token = with_context!(
+        auth_client_for,
+        URL_CHATGPT_API,
+        &token_identity(bearer.token())
+    )
+    .refresh_session(bearer.token())
+    .await
+    .map_err(ResponseError::BadRequest)?;
 
     match session_token {
         AccessToken::Session(session_token) => {
@@ -329,9 +381,13 @@ async fn post_refresh_session(
 async fn post_sess_token(
     TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
 ) -> Result<impl IntoResponse, ResponseError> {
-    match with_context!(auth_client)
-        .dashboard_login(bearer.token())
-        .await
+    match with_context!(
+        auth_client_for,
+        URL_PLATFORM_API,
+        &token_identity(bearer.token())
+    )
+    .dashboard_login(bearer.token())
+    .await
     {
         Ok(dash_session) => Ok(Json(dash_session)),
         Err(err) => Err(err.into()),
@@ -355,7 +411,10 @@ async fn post_access_token(
         }
     }
 
-    match with_context!(auth_client).do_access_token(&account).await? {
+    match with_context!(auth_client_for, OPENAI_OAUTH_URL, &account.username)
+        .do_access_token(&account)
+        .await?
+    {
         AccessToken::Session(session_token) => {
             let resp: Response<Body> = session_token.try_into()?;
             Ok(resp.into_response())
@@ -368,9 +427,13 @@ async fn post_access_token(
 async fn post_refresh_token(
     TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
 ) -> Result<Json<RefreshToken>, ResponseError> {
-    match with_context!(auth_client)
-        .do_refresh_token(bearer.token())
-        .await
+    match with_context!(
+        auth_client_for,
+        OPENAI_OAUTH_TOKEN_URL,
+        &token_identity(bearer.token())
+    )
+    .do_refresh_token(bearer.token())
+    .await
     {
         Ok(refresh_token) => Ok(Json(refresh_token)),
         Err(err) => Err(err.into()),
@@ -381,9 +444,13 @@ async fn post_refresh_token(
 async fn post_revoke_token(
     TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
 ) -> Result<StatusCode, ResponseError> {
-    match with_context!(auth_client)
-        .do_revoke_token(bearer.token())
-        .await
+    match with_context!(
+        auth_client_for,
+        OPENAI_OAUTH_REVOKE_URL,
+        &token_identity(bearer.token())
+    )
+    .do_revoke_token(bearer.token())
+    .await
     {
         Ok(_) => Ok(StatusCode::OK),
         Err(err) => Err(err.into()),
@@ -417,7 +484,7 @@ async fn get_arkose_token(
 
     ArkoseToken::new_from_context(
         ArkoseContext::builder()
-            .client(with_context!(arkose_client))
+            .client(with_context!(arkose_client_for, typed.origin_url()).clone())
             .typed(typed)
             .identifier(blob.map(|v| v.0.blob).flatten())
             .build(),
@@ -436,16 +503,22 @@ async fn get_arkose_token(
 ///
 /// platform API match path /v1/{tail.*}
 /// reference: https://platform.openai.com/docs/api-reference
+///
+/// GET /v1/models and /v1/models/{model} with an access token are answered locally
 async fn official_proxy(req: RequestExt) -> Result<impl IntoResponse, ResponseError> {
-    let resp = with_context!(api_client)
+    if models::support(&req) {
+        return Ok(models::handle(req).await?.into_response());
+    }
+
+    let resp = with_context!(api_client_pool)
         .send_request(URL_PLATFORM_API, req)
         .await?;
-    response_convert(resp).await
+    Ok(response_convert(resp).await?.into_response())
 }
 
 /// reference: doc/http.rest
 async fn unofficial_proxy(req: RequestExt) -> Result<impl IntoResponse, ResponseError> {
-    let resp = with_context!(api_client)
+    let resp = with_context!(api_client_pool)
         .send_request(URL_CHATGPT_API, req)
         .await?;
     response_convert(resp).await
@@ -488,6 +561,9 @@ impl TryInto<Response<Body>> for SessionAccessToken {
     }
 }
 
+/// WAN address found by the startup check
+static WAN_ADDRESS: OnceLock<String> = OnceLock::new();
+
 async fn check_wan_address() {
     match with_context!(api_client)
         .get("https://ifconfig.me")
@@ -498,7 +574,8 @@ async fn check_wan_address() {
     {
         Ok(resp) => match resp.text().await {
             Ok(res) => {
-                info!("What is my IP address: {}", res.trim())
+                info!("What is my IP address: {}", res.trim());
+                let _ = WAN_ADDRESS.set(res.trim().to_owned());
             }
             Err(err) => {
                 warn!("Check IP address error: {}", err.to_string())
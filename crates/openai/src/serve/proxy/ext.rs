@@ -12,7 +12,15 @@ use http::header::CONTENT_TYPE;
 use http::{header, Uri};
 use typed_builder::TypedBuilder;
 
+use crate::client::InFlight;
+use crate::context::apikey::ApiKey;
 use crate::serve::error::ResponseError;
+use crate::token::TokenProfile;
+
+use super::toapi::limit::Limits;
+use super::toapi::reuse::PrefixDigest;
+use super::toapi::tool::ToolSpec;
+use super::toapi::Upstream;
 
 /// Context extension.
 #[derive(TypedBuilder)]
@@ -21,6 +29,27 @@ pub struct Context {
     pub stream: bool,
     // Mapper model
     pub model: String,
+    // Tool calling
+    #[builder(default)]
+    pub(super) tool: Option<ToolSpec>,
+    // Prompt tokens
+    #[builder(default)]
+    pub prompt_tokens: i64,
+    // Send usage chunk at the end of stream
+    #[builder(default)]
+    pub include_usage: bool,
+    // Request history digest, the reply is remembered for conversation reuse
+    #[builder(default)]
+    pub(super) conversation: Option<PrefixDigest>,
+    // Client side stop sequences and max tokens
+    #[builder(default)]
+    pub(super) limits: Limits,
+    // Number of choices
+    #[builder(default = 1)]
+    pub n: usize,
+    // Regenerates further choices
+    #[builder(default)]
+    pub(super) upstream: Option<Upstream>,
 }
 
 /// Response extension.
@@ -29,6 +58,9 @@ pub struct ResponseExt {
     #[builder(setter(into), default)]
     pub context: Option<Context>,
     pub inner: reqwest::Response,
+    // Request in flight through the pool client until the body is streamed
+    #[builder(setter(into), default)]
+    pub in_flight: Option<InFlight>,
 }
 
 /// Extractor for request parts.
@@ -38,6 +70,10 @@ pub struct RequestExt {
     pub headers: http::HeaderMap,
     pub jar: CookieJar,
     pub body: Option<Bytes>,
+    // Ninja issued api key of the request
+    pub api_key: Option<ApiKey>,
+    // Profile of the bearer verified by the auth or the pool middleware
+    pub profile: Option<TokenProfile>,
 }
 
 impl RequestExt {
@@ -105,7 +141,7 @@ where
     type Rejection = Response;
 
     async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
-        let (parts, body) = req.into_parts();
+        let (mut parts, body) = req.into_parts();
 
         let body = if parts.headers.get(CONTENT_TYPE).is_some() {
             Some(
@@ -123,6 +159,8 @@ where
             method: parts.method,
             headers: parts.headers,
             body,
+            api_key: parts.extensions.remove::<ApiKey>(),
+            profile: parts.extensions.remove::<TokenProfile>(),
         })
     }
 }
@@ -1,8 +1,11 @@
 pub mod ext;
+mod msgcap;
 pub mod req;
 pub mod resp;
 mod toapi;
 
+pub(super) use toapi::models;
+
 use super::error::ResponseError;
 use crate::constant::CF_CLEARANCE;
 use crate::constant::PUID;
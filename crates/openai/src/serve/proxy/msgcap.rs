@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use moka::sync::Cache;
+
+use crate::chatgpt::api::ChatGPTBuilder;
+use crate::client::{ClientRoundRobinBalancer, Lease};
+use crate::gpt_model::GPTModel;
+use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::puid::reduce_key;
+use crate::{debug, metrics, now_duration, warn, with_context};
+
+/// ChatGPT GPT-4 cap if the account does not report it, 40 messages every 3 hours
+const DEFAULT_MESSAGE_CAP: usize = 40;
+const DEFAULT_MESSAGE_CAP_WINDOW: u64 = 3 * 3600;
+
+/// Account email -> message cap and window (seconds)
+static LIMITS: OnceLock<Cache<String, (usize, u64)>> = OnceLock::new();
+/// Account email -> send time of the GPT-4 messages in the window
+static WINDOWS: OnceLock<Cache<String, Arc<Mutex<VecDeque<u64>>>>> = OnceLock::new();
+
+/// A message counted against the cap, given back unless ChatGPT accepted it
+pub(crate) struct Slot {
+    messages: Arc<Mutex<VecDeque<u64>>>,
+    at: u64,
+    kept: bool,
+}
+
+impl Slot {
+    /// ChatGPT accepted the message, it stays in the window
+    pub(crate) fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+        let mut messages = self.messages.lock().expect("message cap lock poisoned");
+        if let Some(index) = messages.iter().rposition(|&t| t == self.at) {
+            messages.remove(index);
+        }
+    }
+}
+
+/// Count a message of the account against the ChatGPT GPT-4 message cap over a sliding window,
+/// returns the model the message is sent with and the slot of a counted message
+pub(crate) async fn acquire(
+    token: &str,
+    model: GPTModel,
+    pool: &ClientRoundRobinBalancer,
+) -> Result<(GPTModel, Option<Slot>), ResponseError> {
+    let cap = match with_context!(gpt4_cap) {
+        Some(cap) if model.is_gpt4() => cap,
+        _ => return Ok((model, None)),
+    };
+
+    let email = reduce_key(token)?;
+    let (limit, window) = limits(token, &email, pool).await;
+    // The GPT-4 models share the cap of the account
+    let messages = WINDOWS
+        .get_or_init(|| {
+            Cache::builder()
+                .time_to_idle(Duration::from_secs(86400))
+                .build()
+        })
+        .get_with(email.clone(), Default::default);
+
+    let deadline = now_secs()? + u64::from(cap.wait);
+    loop {
+        let now = now_secs()?;
+        let taken = take(
+            &mut messages.lock().expect("message cap lock poisoned"),
+            limit,
+            window,
+            now,
+        );
+        let wait = match taken {
+            Ok(()) => {
+                let slot = Slot {
+                    messages,
+                    at: now,
+                    kept: false,
+                };
+                return Ok((model, Some(slot)));
+            }
+            Err(wait) => wait,
+        };
+
+        // Wait for a free slot up to the configured seconds first
+        if now + wait > deadline {
+            if cap.downgrade {
+                debug!("GPT-4 message cap of {email} reached, downgrade to GPT-3.5");
+                return Ok((GPTModel::Gpt35, None));
+            }
+            metrics::ratelimit_rejection("gpt4_cap");
+            return Err(ResponseError::TooManyRequests(
+                ProxyError::MessageCapReached(limit, window / 60),
+            ));
+        }
+
+        tokio::time::sleep(Duration::from_secs(wait.max(1))).await;
+    }
+}
+
+/// Take a slot of the window, or the seconds until the oldest message leaves it
+fn take(messages: &mut VecDeque<u64>, limit: usize, window: u64, now: u64) -> Result<(), u64> {
+    while messages.front().is_some_and(|&t| t + window <= now) {
+        messages.pop_front();
+    }
+    if messages.len() < limit {
+        messages.push_back(now);
+        return Ok(());
+    }
+    Err(messages.front().map_or(0, |&t| t + window - now))
+}
+
+/// Get the message cap reported by the account, cached for an hour
+async fn limits(token: &str, email: &str, pool: &ClientRoundRobinBalancer) -> (usize, u64) {
+    let cache = LIMITS.get_or_init(|| {
+        Cache::builder()
+            .time_to_live(Duration::from_secs(3600))
+            .build()
+    });
+
+    if let Some(limits) = cache.get(email) {
+        return limits;
+    }
+
+    let client: Lease<reqwest::Client> = pool.next_for(Some(email)).into();
+    let api = ChatGPTBuilder::builder()
+        .client(client.clone())
+        .access_token(token.to_owned())
+        .build();
+    let limits = match api.get_conversation_limit().await {
+        // The window is reported in minutes
+        Ok(resp) if resp.message_cap > 0 && resp.message_cap_window > 0 => (
+            resp.message_cap as usize,
+            resp.message_cap_window as u64 * 60,
+        ),
+        Ok(_) => (DEFAULT_MESSAGE_CAP, DEFAULT_MESSAGE_CAP_WINDOW),
+        Err(err) => {
+            warn!("Get conversation limit of {email} error: {err}");
+            (DEFAULT_MESSAGE_CAP, DEFAULT_MESSAGE_CAP_WINDOW)
+        }
+    };
+
+    cache.insert(email.to_owned(), limits);
+    limits
+}
+
+fn now_secs() -> Result<u64, ResponseError> {
+    Ok(now_duration()
+        .map_err(ResponseError::InternalServerError)?
+        .as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_take() {
+        let mut messages = VecDeque::new();
+        assert_eq!(take(&mut messages, 2, 60, 100), Ok(()));
+        assert_eq!(take(&mut messages, 2, 60, 110), Ok(()));
+        // The oldest message leaves the window at 160
+        assert_eq!(take(&mut messages, 2, 60, 130), Err(30));
+        assert_eq!(take(&mut messages, 2, 60, 160), Ok(()));
+        assert_eq!(messages, [110, 160]);
+    }
+
+    #[test]
+    fn test_slot_refund() {
+        let messages = Arc::new(Mutex::new(VecDeque::from([100, 110])));
+        let slot = |at| Slot {
+            messages: messages.clone(),
+            at,
+            kept: false,
+        };
+
+        // A message ChatGPT did not accept gives its slot back
+        drop(slot(110));
+        assert_eq!(*messages.lock().unwrap(), [100]);
+        slot(100).keep();
+        assert_eq!(*messages.lock().unwrap(), [100]);
+    }
+}
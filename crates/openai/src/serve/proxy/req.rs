@@ -1,4 +1,6 @@
+use std::future::Future;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use axum::body::Bytes;
 use axum::{
@@ -6,30 +8,53 @@ use axum::{
     http::{self},
 };
 use http::header;
-use http::{HeaderMap, Method};
+use http::{HeaderMap, HeaderName, HeaderValue, Method};
 use serde_json::{json, Value};
+use url::Url;
 
 use crate::arkose::{ArkoseContext, ArkoseToken, Type};
+use crate::client::{cf_challenge, ClientProfile, ClientRoundRobinBalancer, Lease};
 use crate::constant::{ARKOSE_TOKEN, EMPTY, MODEL, NULL, PUID};
+use crate::context::clearance::{Clearance, ClearanceKey, ClearanceSolver, ClearanceStore};
+use crate::egress::Destination;
 use crate::gpt_model::GPTModel;
-use crate::{arkose, with_context};
+use crate::upstream::{AuthStyle, Upstream};
+use crate::{accesslog, arkose, debug, metrics, warn, with_context};
 
 use super::ext::{RequestExt, ResponseExt, SendRequestExt};
 use super::header_convert;
+use super::msgcap;
 use super::toapi;
 use crate::serve::error::{ProxyError, ResponseError};
-use crate::serve::puid::{get_or_init, reduce_key};
+use crate::serve::middleware::pool::origin_status;
+use crate::serve::puid::{get_or_init, key_hash, reduce_key};
+use crate::serve::reload::current_limiter;
 
 #[async_trait]
-impl SendRequestExt for reqwest::Client {
+impl SendRequestExt for ClientRoundRobinBalancer {
     async fn send_request(
         &self,
         origin: &'static str,
         mut req: RequestExt,
     ) -> Result<ResponseExt, ResponseError> {
-        // If to_api is true, then send request to api
-        if toapi::support(&req) {
-            return toapi::send_request(req).await;
+        // Check the model against the api key allow-list
+        check_model(&req)?;
+
+        // Account of the request
+        let account = account(&req);
+
+        // Record the request for the access log
+        access_log(&req, account.as_deref());
+
+        // Identity of the request, the pool derives its IPv6 from it and pins it to a client in affinity mode
+        let identity = account.clone().or_else(|| key_identity(&req));
+
+        // If the model is routed to an upstream backend, then send request to it
+        if let Some(upstream) = route(&req) {
+            let routed = with_context!(route_pool, &Destination::url(upstream.base_url.as_str()));
+            let pool = routed.as_deref().unwrap_or(self);
+            check_egress(pool, identity.as_deref()).await?;
+            return send_upstream(pool.next().into(), upstream, req).await;
         }
 
         // Build rqeuest path and query
@@ -42,23 +67,384 @@ impl SendRequestExt for reqwest::Client {
         // Build url
         let url = format!("{origin}{path_and_query}");
 
-        // Handle conversation request
-        handle_conv_request(&mut req).await?;
+        // The proxy rules may route the request to a proxy group
+        let model = match with_context!(routes_models) {
+            true => body_model(&req),
+            false => None,
+        };
+        let routed = with_context!(route_pool, &Destination::url(&url).model(model.as_deref()));
+        let pool = routed.as_deref().unwrap_or(self);
+        check_egress(pool, identity.as_deref()).await?;
+
+        // If to_api is true, then send request to api
+        if toapi::support(&req) {
+            accesslog::record(|r| r.toapi = true);
+            // Sent through the proxy group of the request, or the api client pool
+            let pool = routed.unwrap_or_else(|| with_context!(api_client_pool));
+            return toapi::send_request(req, pool).await;
+        }
+
+        // Handle conversation request, the message cap slot is kept if ChatGPT accepts the message
+        let conversation = handle_conv_request(&mut req, pool).await?;
 
         // Handle dashboard request
         handle_dashboard_request(&mut req).await?;
 
-        // Build request
-        let mut builder =
-            self.request(req.method, url)
-                .headers(header_convert(&req.headers, &req.jar, origin)?);
-        if let Some(body) = req.body {
-            builder = builder.body(body);
+        let headers = header_convert(&req.headers, &req.jar, origin)?;
+        let retry = match retryable(&req) {
+            true => with_context!(retry),
+            false => 0,
+        };
+
+        // Send request, a retry goes through the next client of the pool
+        let (req, url, headers) = (&req, url.as_str(), &headers);
+        let (identity, arkose) = (identity.as_deref(), conversation.arkose.as_ref());
+        let (resp, in_flight) = retry_send(retry, |attempt| async move {
+            let lease = pool.nth_for(identity, attempt);
+            let index = lease.index();
+            let (client, in_flight) = lease.into_parts();
+
+            let send = |cleared: Option<Clearance>, resend: bool| {
+                let client = client.clone();
+                async move {
+                    // The cookie only passes with the TLS fingerprint of the browser it was solved with
+                    let mut headers = headers.clone();
+                    let client = match cleared {
+                        Some(cleared) => {
+                            cleared.apply(&mut headers)?;
+                            match cleared.impersonate() {
+                                Some(impersonate) => pool.impersonating(index, impersonate),
+                                None => client,
+                            }
+                        }
+                        None => client,
+                    };
+                    let client: reqwest::Client = client.into();
+
+                    // The arkose token of a previous attempt or of the challenged request is spent
+                    let body = match arkose {
+                        Some(arkose) if attempt > 0 || resend => {
+                            Some(arkose_body(req.body.as_ref(), arkose).await?)
+                        }
+                        _ => req.body.clone(),
+                    };
+                    let mut builder = client.request(req.method.clone(), url).headers(headers);
+                    if let Some(body) = body {
+                        builder = builder.body(body);
+                    }
+                    Ok::<_, ResponseError>(builder.send().await)
+                }
+            };
+
+            // The cached cf_clearance of the client egress goes along
+            let scope = clearance(pool, index);
+            let scope = scope
+                .as_ref()
+                .map(|(store, key, profile)| (*store, key, profile.proxy.as_ref()));
+            let start = Instant::now();
+            let resp = match send_cleared(scope, url, is_challenge, send).await? {
+                Ok(resp) => resp,
+                // The solver would fail the retries as well
+                Err(err) => {
+                    pool.report(index, false, start.elapsed(), origin);
+                    return Err(ResponseError::BadGateway(ProxyError::CfClearanceError(err)));
+                }
+            };
+
+            let resp = observe(origin, resp);
+            // Connect errors and challenge pages count against the proxy, 5xx responses against the upstream.
+            // A conversation that reached ChatGPT is not sent again, the message may have been accepted
+            let (proxy_failed, transient) = match &resp {
+                Ok(r) => {
+                    let challenged = cf_challenge(r);
+                    let transient = challenged || r.status().is_server_error();
+                    (challenged, transient && !is_conversation(req))
+                }
+                Err(err) => (err.is_connect(), err.is_connect()),
+            };
+            pool.report(index, !proxy_failed, start.elapsed(), origin);
+
+            let resp = resp.map_err(ResponseError::from);
+            Ok::<_, ResponseError>(match transient {
+                true => Attempt::Transient((resp, in_flight)),
+                false => Attempt::Done((resp, in_flight)),
+            })
+        })
+        .await?;
+        let resp = resp?;
+
+        origin_status(resp.status());
+        if let (Some(slot), true) = (conversation.slot, resp.status().is_success()) {
+            slot.keep();
+        }
+        Ok(ResponseExt::builder()
+            .inner(resp)
+            .in_flight(in_flight)
+            .build())
+    }
+}
+
+/// Outcome of a send attempt, a transient failure is sent again
+enum Attempt<T> {
+    Done(T),
+    Transient(T),
+}
+
+/// Send until an attempt is done or the retries run out, backing off between the attempts
+async fn retry_send<T, E, F, Fut>(retry: usize, mut send: F) -> Result<T, E>
+where
+    F: FnMut(usize) -> Fut,
+    Fut: Future<Output = Result<Attempt<T>, E>>,
+{
+    let mut attempt = 0;
+    loop {
+        match send(attempt).await? {
+            Attempt::Transient(_) if attempt < retry => {
+                attempt += 1;
+                debug!("Retrying the request ({attempt}/{retry})");
+                tokio::time::sleep(backoff(attempt)).await;
+            }
+            Attempt::Done(out) | Attempt::Transient(out) => return Ok(out),
+        }
+    }
+}
+
+/// Send with the cached cf_clearance of the client egress, a Cloudflare challenge is solved for
+/// the egress and the request sent once more. `send` gets the clearance and whether it resends
+/// a challenged request, the error of an unsolved challenge is returned inside
+async fn send_cleared<S, T, F, Fut>(
+    scope: Option<(&ClearanceStore<S>, &ClearanceKey, Option<&Url>)>,
+    url: &str,
+    challenged: fn(&T) -> bool,
+    mut send: F,
+) -> Result<Result<T, anyhow::Error>, ResponseError>
+where
+    S: ClearanceSolver + Sync,
+    F: FnMut(Option<Clearance>, bool) -> Fut,
+    Fut: Future<Output = Result<T, ResponseError>>,
+{
+    let (store, key, proxy) = match scope {
+        Some(scope) => scope,
+        None => return Ok(Ok(send(None, false).await?)),
+    };
+
+    let cleared = store.get(key);
+    let resp = send(cleared.clone(), false).await?;
+    if !challenged(&resp) {
+        return Ok(Ok(resp));
+    }
+
+    let solved = store.solve(key, url, proxy, cleared.as_ref()).await;
+    metrics::cf_clearance(solved.is_ok());
+    match solved {
+        Ok(solved) => Ok(Ok(send(Some(solved), true).await?)),
+        Err(err) => {
+            warn!("Cloudflare challenge of {url} not solved: {err}");
+            Ok(Err(err))
+        }
+    }
+}
+
+/// Check if the upstream answered with a Cloudflare challenge page
+fn is_challenge(resp: &reqwest::Result<reqwest::Response>) -> bool {
+    matches!(resp, Ok(resp) if cf_challenge(resp))
+}
+
+/// Delay before the attempt, doubling from 100 milliseconds up to 3.2 seconds
+fn backoff(attempt: usize) -> Duration {
+    Duration::from_millis(100 << attempt.saturating_sub(1).min(5))
+}
+
+/// Clearance store and key of the pool client, with the profile of the client the solver
+/// sends through
+fn clearance(
+    pool: &ClientRoundRobinBalancer,
+    index: usize,
+) -> Option<(&'static ClearanceStore, ClearanceKey, &ClientProfile)> {
+    let store = with_context!(cf_clearance)?;
+    let profile = pool.profile(index)?;
+    Some((store, ClearanceKey::of(profile)?, profile))
+}
+
+/// Idempotent requests are retried, as are conversation requests whose connection
+/// failed since the message never reached ChatGPT
+fn retryable(req: &RequestExt) -> bool {
+    matches!(
+        req.method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE
+    ) || is_conversation(req)
+}
+
+/// Check if the request sends a conversation message
+fn is_conversation(req: &RequestExt) -> bool {
+    req.method.eq(&Method::POST) && req.uri.path().eq("/backend-api/conversation")
+}
+
+/// Count the upstream send errors and 5xx responses
+pub(super) fn observe(
+    upstream: &str,
+    resp: reqwest::Result<reqwest::Response>,
+) -> reqwest::Result<reqwest::Response> {
+    match resp {
+        Ok(ref r) if !r.status().is_server_error() => {}
+        _ => metrics::upstream_error(upstream),
+    }
+    if let Ok(ref r) = resp {
+        accesslog::record(|record| record.upstream_status = Some(r.status().as_u16()));
+    }
+    resp
+}
+
+/// Email of the access token of the request, the bearer is the pool account token
+/// if the request was dispatched to the pool
+fn account(req: &RequestExt) -> Option<String> {
+    req.profile
+        .as_ref()
+        .map(|profile| profile.email().to_owned())
+}
+
+/// Hash of the ninja issued api key or the bearer of the request
+fn key_identity(req: &RequestExt) -> Option<String> {
+    let key = req
+        .api_key
+        .as_ref()
+        .map(|key| key.key.as_str())
+        .or_else(|| req.bearer_auth())?;
+    Some(key_hash(key))
+}
+
+/// Take a token from the bucket of the IPv6 the identity's requests are sent from
+async fn check_egress(
+    pool: &ClientRoundRobinBalancer,
+    identity: Option<&str>,
+) -> Result<(), ResponseError> {
+    let (egress, limiter) = match (
+        identity.and_then(|identity| pool.egress_ipv6(identity)),
+        current_limiter(),
+    ) {
+        (Some(egress), Some(limiter)) => (egress, limiter),
+        _ => return Ok(()),
+    };
+    match limiter
+        .acquire_egress(egress)
+        .await
+        .map_err(ResponseError::BadGateway)?
+    {
+        Some(status) if !status.allowed => {
+            metrics::ratelimit_rejection("egress");
+            Err(ResponseError::TooManyRequests(ProxyError::TooManyRequests))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Record the account, model and stream mode of the request for the access log
+fn access_log(req: &RequestExt, email: Option<&str>) {
+    if with_context!(access_log).is_none() {
+        return;
+    }
+
+    let email = email.map(str::to_owned);
+    let json = req
+        .body
+        .as_ref()
+        .and_then(|body| serde_json::from_slice::<Value>(body).ok());
+    accesslog::record(|r| {
+        if email.is_some() {
+            r.email = email;
+        }
+        if let Some(json) = json {
+            r.model = json.get(MODEL).and_then(|m| m.as_str()).map(str::to_owned);
+            r.stream = json
+                .get("stream")
+                .and_then(|s| s.as_bool())
+                .unwrap_or(false);
+        }
+    });
+}
+
+/// Check if the ninja issued api key of the request may use the model
+fn check_model(req: &RequestExt) -> Result<(), ResponseError> {
+    let (key, body) = match (req.api_key.as_ref(), req.body.as_ref()) {
+        (Some(key), Some(body)) => (key, body),
+        _ => return Ok(()),
+    };
+
+    let json = match serde_json::from_slice::<Value>(body) {
+        Ok(json) => json,
+        Err(_) => return Ok(()),
+    };
+    match json.get(MODEL).and_then(|m| m.as_str()) {
+        Some(model) if !key.allows(model) => Err(ResponseError::Forbidden(
+            ProxyError::ModelNotAllowed(model.to_owned()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Find the upstream backend of the `/v1` request model
+fn route(req: &RequestExt) -> Option<&'static Upstream> {
+    if !with_context!(has_upstream) || !req.uri.path().starts_with("/v1/") {
+        return None;
+    }
+
+    let model = body_model(req)?;
+    with_context!(upstream, &model)
+}
+
+/// Model of the json request body
+fn body_model(req: &RequestExt) -> Option<String> {
+    let body = req.body.as_ref()?;
+    let json = serde_json::from_slice::<Value>(body).ok()?;
+    json.get(MODEL)?.as_str().map(ToOwned::to_owned)
+}
+
+/// Send request to the upstream backend
+async fn send_upstream(
+    client: Lease<reqwest::Client>,
+    upstream: &Upstream,
+    req: RequestExt,
+) -> Result<ResponseExt, ResponseError> {
+    let (client, in_flight) = client.into_parts();
+    let url = upstream
+        .url(req.uri.path(), req.uri.query())
+        .map_err(ResponseError::InternalServerError)?;
+
+    let mut headers = HeaderMap::new();
+    for name in [header::CONTENT_TYPE, header::ACCEPT] {
+        if let Some(value) = req.headers.get(&name) {
+            headers.insert(name, value.clone());
         }
+    }
 
-        // Send request
-        Ok(ResponseExt::builder().inner(builder.send().await?).build())
+    // Only the upstream's own api key is sent, never the caller's bearer token
+    if let Some(key) = upstream.api_key.as_deref() {
+        let value = match upstream.auth {
+            AuthStyle::Bearer => Some((header::AUTHORIZATION, format!("Bearer {key}"))),
+            AuthStyle::ApiKey => Some((HeaderName::from_static("api-key"), key.to_owned())),
+            AuthStyle::None => None,
+        };
+        if let Some((name, value)) = value {
+            headers.insert(
+                name,
+                HeaderValue::from_str(&value).map_err(ResponseError::InternalServerError)?,
+            );
+        }
     }
+
+    let mut builder = client.request(req.method, url).headers(headers);
+    if let Some(body) = req.body {
+        builder = builder.body(body);
+    }
+
+    let resp = observe(
+        upstream.base_url.host_str().unwrap_or_default(),
+        builder.send().await,
+    )?;
+    Ok(ResponseExt::builder()
+        .inner(resp)
+        .in_flight(in_flight)
+        .build())
 }
 
 /// Check if the request has puid
@@ -71,11 +457,23 @@ pub(super) fn has_puid(headers: &HeaderMap) -> Result<bool, ResponseError> {
     }
 }
 
+/// Conversation request state kept across the send attempts
+#[derive(Default)]
+struct Conversation {
+    /// Message cap slot, kept if ChatGPT accepts the message
+    slot: Option<msgcap::Slot>,
+    /// Context of the arkose token added to the message, each attempt needs a fresh token
+    arkose: Option<ArkoseContext>,
+}
+
 /// Handle conversation request
-async fn handle_conv_request(req: &mut RequestExt) -> Result<(), ResponseError> {
+async fn handle_conv_request(
+    req: &mut RequestExt,
+    pool: &ClientRoundRobinBalancer,
+) -> Result<Conversation, ResponseError> {
     // Only handle POST request
-    if !(req.uri.path().eq("/backend-api/conversation") && req.method.eq(&Method::POST)) {
-        return Ok(());
+    if !is_conversation(req) {
+        return Ok(Conversation::default());
     }
 
     // Handle empty body
@@ -108,7 +506,7 @@ async fn handle_conv_request(req: &mut RequestExt) -> Result<(), ResponseError>
         let cache_id = reduce_key(&token)?;
 
         // Get or init puid
-        let puid = get_or_init(&token, model, cache_id).await?;
+        let puid = get_or_init(&token, model, cache_id, pool).await?;
 
         if let Some(puid) = puid {
             req.headers.insert(
@@ -122,6 +520,15 @@ async fn handle_conv_request(req: &mut RequestExt) -> Result<(), ResponseError>
     // Parse model
     let model = GPTModel::from_str(model).map_err(ResponseError::BadRequest)?;
 
+    // Count the message against the account message cap, a capped message may be downgraded
+    let (capped, slot) = msgcap::acquire(&token, model.clone(), pool).await?;
+    let mut changed = capped.ne(&model);
+    if changed {
+        body.insert(MODEL.to_owned(), json!(capped));
+    }
+    let model = capped;
+    let mut arkose = None;
+
     // If model is gpt3 or gpt4, then add arkose_token
     if (with_context!(arkose_gpt3_experiment) && model.is_gpt3()) || model.is_gpt4() {
         let condition = match body.get(ARKOSE_TOKEN) {
@@ -133,25 +540,43 @@ async fn handle_conv_request(req: &mut RequestExt) -> Result<(), ResponseError>
         };
 
         if condition {
-            let arkose_token = ArkoseToken::new_from_context(
-                ArkoseContext::builder()
-                    .client(with_context!(arkose_client))
-                    .typed(model.into())
-                    .identifier(Some(token))
-                    .build(),
-            )
-            .await?;
+            let typed = Type::from(model);
+            let client = with_context!(arkose_client_for, typed.origin_url());
+            let context = ArkoseContext::builder()
+                .client(client.clone())
+                .typed(typed)
+                .identifier(Some(token))
+                .build();
+            let arkose_token = ArkoseToken::new_from_context(context.clone()).await?;
             body.insert(ARKOSE_TOKEN.to_owned(), json!(arkose_token.value()));
-            // Updaye Modify bytes
-            req.body = Some(Bytes::from(
-                serde_json::to_vec(&json).map_err(ResponseError::BadRequest)?,
-            ));
+            arkose = Some(context);
+            changed = true;
         }
     }
 
+    // Updaye Modify bytes
+    if changed {
+        req.body = Some(Bytes::from(
+            serde_json::to_vec(&json).map_err(ResponseError::BadRequest)?,
+        ));
+    }
+
     drop(json);
 
-    Ok(())
+    Ok(Conversation { slot, arkose })
+}
+
+/// Conversation body with a fresh arkose token
+async fn arkose_body(body: Option<&Bytes>, arkose: &ArkoseContext) -> Result<Bytes, ResponseError> {
+    let body = body.ok_or(ResponseError::BadRequest(ProxyError::BodyRequired))?;
+    let mut json = serde_json::from_slice::<Value>(body).map_err(ResponseError::BadRequest)?;
+    let arkose_token = ArkoseToken::new_from_context(arkose.clone()).await?;
+    if let Some(body) = json.as_object_mut() {
+        body.insert(ARKOSE_TOKEN.to_owned(), json!(arkose_token.value()));
+    }
+    Ok(Bytes::from(
+        serde_json::to_vec(&json).map_err(ResponseError::BadRequest)?,
+    ))
 }
 
 /// Handle dashboard request
@@ -177,7 +602,7 @@ async fn handle_dashboard_request(req: &mut RequestExt) -> Result<(), ResponseEr
     if body.get(ARKOSE_TOKEN).is_none() {
         let arkose_token = arkose::ArkoseToken::new_from_context(
             arkose::ArkoseContext::builder()
-                .client(with_context!(arkose_client))
+                .client(with_context!(arkose_client_for, Type::Platform.origin_url()).clone())
                 .typed(Type::Platform)
                 .identifier(None)
                 .build(),
@@ -194,3 +619,116 @@ async fn handle_dashboard_request(req: &mut RequestExt) -> Result<(), ResponseEr
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::context::clearance::test::MockSolver;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_retry_send() {
+        // Transient attempts are sent again until the retries run out
+        let sent = AtomicUsize::new(0);
+        let out = retry_send(2, |attempt| {
+            sent.fetch_add(1, Ordering::Relaxed);
+            async move { Ok::<_, ()>(Attempt::Transient(attempt)) }
+        })
+        .await;
+        assert_eq!(out, Ok(2));
+        assert_eq!(sent.load(Ordering::Relaxed), 3);
+
+        // A done attempt ends the retries
+        let out = retry_send(3, |attempt| async move {
+            Ok::<_, ()>(match attempt {
+                0 => Attempt::Transient(attempt),
+                _ => Attempt::Done(attempt),
+            })
+        })
+        .await;
+        assert_eq!(out, Ok(1));
+
+        // An error is not retried
+        let sent = AtomicUsize::new(0);
+        let out = retry_send(3, |_| {
+            sent.fetch_add(1, Ordering::Relaxed);
+            async { Err::<Attempt<usize>, _>("failed") }
+        })
+        .await;
+        assert_eq!(out, Err("failed"));
+        assert_eq!(sent.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_cleared() {
+        let profile = ClientProfile {
+            proxy: None,
+            bind: None,
+            impersonate: reqwest::impersonate::Impersonate::OkHttp4_9,
+        };
+        let key = ClearanceKey::of(&profile).unwrap();
+        let store = ClearanceStore::new(MockSolver::default(), 60);
+        let url = "https://chat.openai.com/backend-api/conversation";
+        let challenged = |resp: &&str| resp.eq(&"challenge");
+
+        // A challenge is solved and the request sent again with the clearance
+        let mut sent = vec![];
+        let resp = send_cleared(
+            Some((&store, &key, None)),
+            url,
+            challenged,
+            |cleared, resend| {
+                sent.push((cleared.map(|c| c.cf_clearance), resend));
+                async move { Ok::<_, ResponseError>(if resend { "ok" } else { "challenge" }) }
+            },
+        )
+        .await;
+        assert_eq!(resp.ok().and_then(Result::ok), Some("ok"));
+        assert_eq!(
+            sent,
+            [(None, false), (Some("clearance-0".to_owned()), true)]
+        );
+
+        // The cached clearance goes along with the next request
+        let mut sent = vec![];
+        let resp = send_cleared(Some((&store, &key, None)), url, challenged, |cleared, _| {
+            sent.push(cleared.map(|c| c.cf_clearance));
+            async { Ok::<_, ResponseError>("ok") }
+        })
+        .await;
+        assert_eq!(resp.ok().and_then(Result::ok), Some("ok"));
+        assert_eq!(sent, [Some("clearance-0".to_owned())]);
+
+        // An unsolved challenge is not sent again
+        let failing = ClearanceStore::new(
+            MockSolver {
+                fail: true,
+                ..Default::default()
+            },
+            60,
+        );
+        let sent = AtomicUsize::new(0);
+        let resp = send_cleared(Some((&failing, &key, None)), url, challenged, |_, _| {
+            sent.fetch_add(1, Ordering::Relaxed);
+            async { Ok::<_, ResponseError>("challenge") }
+        })
+        .await;
+        assert!(resp.ok().is_some_and(|r| r.is_err()));
+        assert_eq!(sent.load(Ordering::Relaxed), 1);
+
+        // Without a solver the response is passed on
+        let resp = send_cleared::<MockSolver, _, _, _>(None, url, challenged, |cleared, _| {
+            assert!(cleared.is_none());
+            async { Ok::<_, ResponseError>("challenge") }
+        })
+        .await;
+        assert_eq!(resp.ok().and_then(Result::ok), Some("challenge"));
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_millis(100));
+        assert_eq!(backoff(2), Duration::from_millis(200));
+        assert_eq!(backoff(10), Duration::from_millis(3200));
+    }
+}
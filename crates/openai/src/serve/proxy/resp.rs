@@ -1,25 +1,44 @@
 use std::time::UNIX_EPOCH;
 
+use crate::client::cf_challenge;
 use crate::constant::{CF_CLEARANCE, NINJA_VERSION, PUID};
 use crate::with_context;
 use crate::LIB_VERSION;
 use axum::body::Body;
-use axum::body::StreamBody;
+use axum::body::{Bytes, HttpBody, StreamBody};
 use axum::http::header;
 use axum::response::{IntoResponse, Response};
 use axum_extra::extract::cookie;
 use axum_extra::extract::cookie::Cookie;
 use serde_json::Value;
 
-use crate::serve::error::ResponseError;
+use crate::serve::error::{ProxyError, ResponseError};
 
 use super::ext::ResponseExt;
 use super::toapi;
 
 /// Response convert
 pub(crate) async fn response_convert(
-    resp: ResponseExt,
+    mut resp: ResponseExt,
 ) -> Result<impl IntoResponse, ResponseError> {
+    // The pool client counts the request in flight until the body is streamed
+    let in_flight = resp.in_flight.take();
+    let resp = convert(resp).await?;
+    Ok(resp.map(|body| {
+        axum::body::boxed(body.map_data(move |chunk: Bytes| {
+            let _ = &in_flight;
+            chunk
+        }))
+    }))
+}
+
+async fn convert(resp: ResponseExt) -> Result<Response, ResponseError> {
+    // A Cloudflare challenge page the solver did not clear is not passed to the client,
+    // without a solver the client gets the page as before
+    if with_context!(cf_clearance).is_some() && cf_challenge(&resp.inner) {
+        return Err(ResponseError::Forbidden(ProxyError::CfChallenge));
+    }
+
     // If to api is some, then convert to api response
     if resp.context.is_some() {
         return Ok(toapi::response_convert(resp).await?.into_response());
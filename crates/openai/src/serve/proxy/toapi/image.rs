@@ -0,0 +1,407 @@
+use axum::http::HeaderMap;
+use base64::{engine::general_purpose, Engine};
+use reqwest::header::{CONTENT_LENGTH, LOCATION};
+use serde::Deserialize;
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use url::Url;
+
+use crate::chatgpt::model::req::{Attachment, ImageAssetPointer, Part};
+use crate::client::ClientRoundRobinBalancer;
+use crate::egress::Destination;
+use crate::serve::error::{ProxyError, ResponseError};
+use crate::{with_context, URL_CHATGPT_API};
+
+/// Largest image accepted, the ChatGPT upload limit
+const MAX_IMAGE_SIZE: usize = 20 * 1024 * 1024;
+/// Redirects followed when downloading an image
+const MAX_REDIRECTS: usize = 5;
+
+/// An image uploaded to the ChatGPT file service
+pub(super) struct UploadedImage {
+    file_id: String,
+    name: String,
+    size: usize,
+    mime_type: &'static str,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Deserialize)]
+struct CreateFileResponse {
+    status: String,
+    #[serde(default)]
+    file_id: String,
+    #[serde(default)]
+    upload_url: String,
+}
+
+impl UploadedImage {
+    /// Message part referencing the image
+    pub(super) fn part(&self) -> Part<'static> {
+        Part::Image(
+            ImageAssetPointer::builder()
+                .asset_pointer(format!("file-service://{}", self.file_id))
+                .size_bytes(self.size)
+                .width(self.width)
+                .height(self.height)
+                .build(),
+        )
+    }
+
+    /// Message metadata attachment of the image
+    pub(super) fn attachment(&self) -> Attachment {
+        Attachment::builder()
+            .id(self.file_id.clone())
+            .name(self.name.clone())
+            .size(self.size)
+            .mime_type(self.mime_type.to_owned())
+            .width(self.width)
+            .height(self.height)
+            .build()
+    }
+}
+
+/// Upload an image given as a data url or remote url
+pub(super) async fn upload(
+    client: &reqwest::Client,
+    headers: &HeaderMap,
+    url: &str,
+    account: &str,
+) -> Result<UploadedImage, ResponseError> {
+    let bytes = load(url, account).await?;
+    let (mime_type, width, height) = probe(&bytes).ok_or(ResponseError::BadRequest(
+        ProxyError::UnsupportedImageFormat,
+    ))?;
+    let name = format!(
+        "image.{}",
+        mime_type
+            .trim_start_matches("image/")
+            .replace("jpeg", "jpg")
+    );
+    // Create the file
+    let file = client
+        .post(format!("{URL_CHATGPT_API}/backend-api/files"))
+        .headers(headers.clone())
+        .json(&json!({
+            "file_name": name,
+            "file_size": bytes.len(),
+            "use_case": "multimodal",
+        }))
+        .send()
+        .await
+        .map_err(ResponseError::BadGateway)?
+        .error_for_status()
+        .map_err(ResponseError::BadGateway)?
+        .json::<CreateFileResponse>()
+        .await
+        .map_err(ResponseError::BadGateway)?;
+
+    if file.status.ne("success") || file.upload_url.is_empty() {
+        return Err(ResponseError::BadGateway(ProxyError::UploadImageError(
+            file.status,
+        )));
+    }
+
+    // Upload the content to the blob storage
+    let size = bytes.len();
+    client
+        .put(&file.upload_url)
+        .header("x-ms-blob-type", "BlockBlob")
+        .header("x-ms-version", "2020-04-08")
+        .header(reqwest::header::CONTENT_TYPE, mime_type)
+        .body(bytes)
+        .send()
+        .await
+        .map_err(ResponseError::BadGateway)?
+        .error_for_status()
+        .map_err(ResponseError::BadGateway)?;
+
+    // Mark the file as uploaded
+    client
+        .post(format!(
+            "{URL_CHATGPT_API}/backend-api/files/{}/uploaded",
+            file.file_id
+        ))
+        .headers(headers.clone())
+        .json(&json!({}))
+        .send()
+        .await
+        .map_err(ResponseError::BadGateway)?
+        .error_for_status()
+        .map_err(ResponseError::BadGateway)?;
+
+    Ok(UploadedImage {
+        file_id: file.file_id,
+        name,
+        size,
+        mime_type,
+        width,
+        height,
+    })
+}
+
+/// Read the image bytes of a data url or download a remote url through the egress of the account
+async fn load(url: &str, account: &str) -> Result<Vec<u8>, ResponseError> {
+    if let Some(data) = url.strip_prefix("data:") {
+        let (meta, data) = data
+            .split_once(',')
+            .ok_or(ResponseError::BadRequest(ProxyError::InvalidImageUrl))?;
+        if !meta.ends_with(";base64") {
+            return Err(ResponseError::BadRequest(ProxyError::InvalidImageUrl));
+        }
+        let data = data.trim();
+        if data.len() / 4 * 3 > MAX_IMAGE_SIZE {
+            return Err(ResponseError::PayloadTooLarge(ProxyError::ImageTooLarge(
+                MAX_IMAGE_SIZE,
+            )));
+        }
+        return general_purpose::STANDARD
+            .decode(data)
+            .map_err(ResponseError::BadRequest);
+    }
+
+    let mut url =
+        Url::parse(url).map_err(|_| ResponseError::BadRequest(ProxyError::InvalidImageUrl))?;
+    for _ in 0..=MAX_REDIRECTS {
+        // The proxy rules may route the download to a proxy group
+        let pool = with_context!(route_pool, &Destination::url(url.as_str()))
+            .unwrap_or_else(|| with_context!(api_client_pool));
+
+        // Pin the checked address, so the download can not be rebound to another one
+        let (host, addr) = resolve_public(&pool, &url).await?;
+        let client = pool
+            .pinned(Some(account), &host, addr)
+            .map_err(ResponseError::InternalServerError)?;
+        let resp = client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(ResponseError::BadRequest)?;
+
+        // Every redirect target is checked again
+        if resp.status().is_redirection() {
+            let location = resp
+                .headers()
+                .get(LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .ok_or(ResponseError::BadRequest(ProxyError::InvalidImageUrl))?;
+            url = url
+                .join(location)
+                .map_err(|_| ResponseError::BadRequest(ProxyError::InvalidImageUrl))?;
+            continue;
+        }
+
+        let resp = resp.error_for_status().map_err(ResponseError::BadRequest)?;
+        return read_limited(resp).await;
+    }
+    Err(ResponseError::BadRequest(ProxyError::InvalidImageUrl))
+}
+
+/// Resolve the host of the http url with the pool resolver, every address must be public
+async fn resolve_public(
+    pool: &ClientRoundRobinBalancer,
+    url: &Url,
+) -> Result<(String, SocketAddr), ResponseError> {
+    let invalid = || ResponseError::BadRequest(ProxyError::InvalidImageUrl);
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid());
+    }
+    let host = url.host_str().ok_or_else(invalid)?.to_owned();
+    let port = url.port_or_known_default().ok_or_else(invalid)?;
+
+    let lookup = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs = pool
+        .lookup(lookup)
+        .await
+        .map_err(|_| invalid())?
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect::<Vec<_>>();
+
+    if addrs.is_empty() || addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err(ResponseError::Forbidden(ProxyError::ImageUrlNotPublic));
+    }
+    Ok((host, addrs[0]))
+}
+
+/// Check if the address is reachable on the public internet, loopback, private,
+/// link-local (cloud metadata), shared, documentation and reserved ranges are not
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared address space
+                || (a == 100 && (64..128).contains(&b))
+                // IETF protocol assignments
+                || (a == 192 && b == 0 && c == 0)
+                // Benchmarking
+                || (a == 198 && (18..20).contains(&b))
+                // Reserved
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(v4));
+            }
+            let segments = ip.segments();
+            // NAT64 embeds the IPv4 address
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let o = ip.octets();
+                return is_public(IpAddr::V4([o[12], o[13], o[14], o[15]].into()));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local
+                || (segments[0] & 0xfe00) == 0xfc00
+                // Link-local and site-local
+                || (segments[0] & 0xffc0) == 0xfe80
+                || (segments[0] & 0xffc0) == 0xfec0
+                // Documentation
+                || (segments[0] == 0x2001 && segments[1] == 0xdb8))
+        }
+    }
+}
+
+/// Read the response body, up to the image size limit
+async fn read_limited(mut resp: reqwest::Response) -> Result<Vec<u8>, ResponseError> {
+    let too_large = || ResponseError::PayloadTooLarge(ProxyError::ImageTooLarge(MAX_IMAGE_SIZE));
+    let length = resp
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if length.map_or(false, |length| length > MAX_IMAGE_SIZE) {
+        return Err(too_large());
+    }
+
+    let mut bytes = Vec::with_capacity(length.unwrap_or_default());
+    while let Some(chunk) = resp.chunk().await.map_err(ResponseError::BadRequest)? {
+        if bytes.len() + chunk.len() > MAX_IMAGE_SIZE {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Sniff the mime type and dimensions from the image header
+fn probe(bytes: &[u8]) -> Option<(&'static str, u32, u32)> {
+    let be16 = |i: usize| Some(u16::from_be_bytes(bytes.get(i..i + 2)?.try_into().ok()?) as u32);
+    let le16 = |i: usize| Some(u16::from_le_bytes(bytes.get(i..i + 2)?.try_into().ok()?) as u32);
+    let le24 = |i: usize| {
+        let b = bytes.get(i..i + 3)?;
+        Some(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16)
+    };
+
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        let width = u32::from_be_bytes(bytes.get(16..20)?.try_into().ok()?);
+        let height = u32::from_be_bytes(bytes.get(20..24)?.try_into().ok()?);
+        return Some(("image/png", width, height));
+    }
+
+    if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        return Some(("image/gif", le16(6)?, le16(8)?));
+    }
+
+    if bytes.starts_with(b"RIFF") && bytes.get(8..12)? == b"WEBP" {
+        return match bytes.get(12..16)? {
+            b"VP8 " => Some(("image/webp", le16(26)? & 0x3fff, le16(28)? & 0x3fff)),
+            b"VP8L" => {
+                let b = bytes.get(21..25)?;
+                let width = 1 + (((b[1] as u32 & 0x3f) << 8) | b[0] as u32);
+                let height = 1
+                    + (((b[3] as u32 & 0xf) << 10)
+                        | ((b[2] as u32) << 2)
+                        | ((b[1] as u32 & 0xc0) >> 6));
+                Some(("image/webp", width, height))
+            }
+            b"VP8X" => Some(("image/webp", 1 + le24(24)?, 1 + le24(27)?)),
+            _ => None,
+        };
+    }
+
+    if bytes.starts_with(&[0xff, 0xd8]) {
+        let mut i = 2;
+        while *bytes.get(i)? == 0xff {
+            let marker = *bytes.get(i + 1)?;
+            match marker {
+                // Fill byte
+                0xff => i += 1,
+                // Standalone markers
+                0x01 | 0xd0..=0xd9 => i += 2,
+                // Start of frame, except DHT, JPG and DAC
+                0xc0..=0xcf if !matches!(marker, 0xc4 | 0xc8 | 0xcc) => {
+                    return Some(("image/jpeg", be16(i + 7)?, be16(i + 5)?));
+                }
+                _ => i += 2 + be16(i + 2)? as usize,
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_public, probe};
+
+    #[test]
+    fn test_probe() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        png.extend(640u32.to_be_bytes());
+        png.extend(480u32.to_be_bytes());
+        assert_eq!(probe(&png), Some(("image/png", 640, 480)));
+
+        let gif = b"GIF89a\x20\x03\x58\x02";
+        assert_eq!(probe(gif), Some(("image/gif", 800, 600)));
+
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8X\x0a\0\0\0\0\0\0\0".to_vec();
+        webp.extend([0x7f, 0x02, 0x00, 0xdf, 0x01, 0x00]);
+        assert_eq!(probe(&webp), Some(("image/webp", 640, 480)));
+
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe0, 0x00, 0x04, 0x00, 0x00];
+        jpeg.extend([0xff, 0xc0, 0x00, 0x11, 0x08, 0x01, 0xe0, 0x02, 0x80]);
+        assert_eq!(probe(&jpeg), Some(("image/jpeg", 640, 480)));
+
+        assert_eq!(probe(b"not an image"), None);
+    }
+
+    #[test]
+    fn test_is_public() {
+        let public = |ip: &str| is_public(ip.parse().unwrap());
+        assert!(public("8.8.8.8"));
+        assert!(public("2606:4700::1111"));
+        assert!(public("::ffff:1.1.1.1"));
+
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "240.0.0.1",
+            "::1",
+            "::",
+            "fc00::1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a00:1",
+            "2001:db8::1",
+        ] {
+            assert!(!public(ip), "{ip}");
+        }
+    }
+}
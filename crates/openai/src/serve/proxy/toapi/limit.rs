@@ -0,0 +1,159 @@
+use crate::tokenizer;
+
+/// Client side `stop` and `max_tokens` enforcement, ChatGPT does not support either
+#[derive(Clone, Default)]
+pub struct Limits {
+    stop: Vec<String>,
+    max_tokens: Option<usize>,
+}
+
+/// Tokens of the stable prefix of a growing reply, each update only encodes the new text
+#[derive(Default)]
+pub struct TokenCount {
+    tokens: Vec<u32>,
+    len: usize,
+}
+
+impl TokenCount {
+    /// Encode the text, a cumulative reply of the previously counted texts,
+    /// returns the tokens after the stable prefix
+    fn update(&mut self, text: &str) -> Vec<u32> {
+        let bpe = tokenizer::cl100k_base();
+        if !text.is_char_boundary(self.len) {
+            *self = Self::default();
+        }
+        let (tokens, len) = bpe.encode_stable(&text[self.len..]);
+        self.tokens.extend(tokens);
+        self.len += len;
+        bpe.encode(&text[self.len..])
+    }
+}
+
+impl Limits {
+    pub(super) fn new(stop: Vec<String>, max_tokens: Option<usize>) -> Self {
+        Self {
+            stop: stop.into_iter().filter(|s| !s.is_empty()).collect(),
+            max_tokens,
+        }
+    }
+
+    /// Cut a cumulative reply at the first stop sequence or the token limit,
+    /// returns the text that can be sent and the finish reason if a limit is hit.
+    /// Until the reply is `finished`, a trailing partial stop sequence is held back.
+    pub(super) fn apply<'a>(
+        &self,
+        text: &'a str,
+        finished: bool,
+        count: &mut TokenCount,
+    ) -> (&'a str, Option<&'static str>) {
+        let mut text = text;
+        let mut reason = None;
+
+        if let Some(pos) = self.stop.iter().filter_map(|s| text.find(s.as_str())).min() {
+            text = &text[..pos];
+            reason = Some("stop");
+        }
+
+        // A token is at least one byte
+        if let Some(max_tokens) = self.max_tokens.filter(|&max| text.len() > max) {
+            let tail = count.update(text);
+            if count.tokens.len() + tail.len() > max_tokens {
+                // Decoded tokens are a prefix of the text, drop a trailing partial character
+                let tokens = count
+                    .tokens
+                    .iter()
+                    .chain(&tail)
+                    .take(max_tokens)
+                    .copied()
+                    .collect::<Vec<_>>();
+                let bytes = tokenizer::cl100k_base().decode(&tokens);
+                let len = match std::str::from_utf8(&bytes) {
+                    Ok(s) => s.len(),
+                    Err(err) => err.valid_up_to(),
+                };
+                text = &text[..len];
+                reason = Some("length");
+            }
+        }
+
+        if reason.is_none() && !finished {
+            text = &text[..text.len() - self.partial_stop_len(text)];
+        }
+
+        (text, reason)
+    }
+
+    /// Length of the longest text suffix that may begin a stop sequence
+    fn partial_stop_len(&self, text: &str) -> usize {
+        self.stop
+            .iter()
+            .filter_map(|stop| {
+                (1..stop.len())
+                    .rev()
+                    .filter(|&n| stop.is_char_boundary(n))
+                    .find(|&n| text.ends_with(&stop[..n]))
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Limits, TokenCount};
+
+    #[test]
+    fn test_stop() {
+        let limits = Limits::new(vec!["\n\n".to_owned(), "END".to_owned()], None);
+        assert_eq!(
+            limits.apply("Hello", false, &mut TokenCount::default()),
+            ("Hello", None)
+        );
+        assert_eq!(
+            limits.apply("Hello\n", false, &mut TokenCount::default()),
+            ("Hello", None)
+        );
+        assert_eq!(
+            limits.apply("Hello\n", true, &mut TokenCount::default()),
+            ("Hello\n", None)
+        );
+        assert_eq!(
+            limits.apply("Hello E", false, &mut TokenCount::default()),
+            ("Hello ", None)
+        );
+        assert_eq!(
+            limits.apply("Hello\n\nWorld END", false, &mut TokenCount::default()),
+            ("Hello", Some("stop"))
+        );
+    }
+
+    #[test]
+    fn test_max_tokens() {
+        let limits = Limits::new(vec![], Some(1));
+        assert_eq!(
+            limits.apply("hello", false, &mut TokenCount::default()),
+            ("hello", None)
+        );
+        assert_eq!(
+            limits.apply("hello world", false, &mut TokenCount::default()),
+            ("hello", Some("length"))
+        );
+    }
+
+    #[test]
+    fn test_max_tokens_growing() {
+        let text = "Hello world, the reply grows by a few bytes at a time";
+        let limits = Limits::new(vec![], Some(5));
+        let mut count = TokenCount::default();
+        for end in 1..=text.len() {
+            assert_eq!(
+                limits.apply(&text[..end], false, &mut count),
+                limits.apply(&text[..end], false, &mut TokenCount::default())
+            );
+        }
+        assert_eq!(
+            limits.apply(text, true, &mut count),
+            ("Hello world, the reply", Some("length"))
+        );
+    }
+}
@@ -1,29 +1,40 @@
+mod image;
+pub(super) mod limit;
 mod model;
+pub(super) mod models;
+pub(super) mod reuse;
 mod stream;
+pub(super) mod tool;
 
 use axum::http::header;
 use axum::http::Method;
+use axum::http::{HeaderMap, HeaderValue};
 use axum::{
     response::{IntoResponse, Sse},
     Json,
 };
-use eventsource_stream::Eventsource;
 use reqwest::StatusCode;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use crate::arkose::ArkoseContext;
 use crate::chatgpt::model::req::Metadata;
 use crate::chatgpt::model::Role;
+use crate::client::{ClientRoundRobinBalancer, InFlight, Lease};
+use crate::context::conversation::Conversation;
+use crate::debug;
 use crate::gpt_model::GPTModel;
 use crate::now_duration;
 use crate::serve::error::ProxyError;
 use crate::serve::ProxyResult;
 use crate::token;
+use crate::tokenizer;
 use crate::{
     arkose::ArkoseToken,
     chatgpt::model::req::{Content, ConversationMode, Messages, PostConvoRequest},
     serve::{
         error::ResponseError,
+        middleware::pool::origin_status,
         puid::{get_or_init, reduce_key},
     },
     with_context,
@@ -38,6 +49,7 @@ use crate::{
 
 use super::ext::{Context, RequestExt, ResponseExt};
 use super::header_convert;
+use super::msgcap;
 use crate::URL_CHATGPT_API;
 
 const SUGGESTIONS: [&'static str; 4] = [
@@ -57,8 +69,11 @@ pub(super) fn support(req: &RequestExt) -> bool {
     false
 }
 
-/// Send request to ChatGPT API
-pub(super) async fn send_request(req: RequestExt) -> Result<ResponseExt, ResponseError> {
+/// Send request to ChatGPT API through the client pool the proxy rules routed the request to
+pub(super) async fn send_request(
+    req: RequestExt,
+    pool: Arc<ClientRoundRobinBalancer>,
+) -> Result<ResponseExt, ResponseError> {
     // Exstract the token from the Authorization header
     let baerer = req
         .bearer_auth()
@@ -73,97 +88,373 @@ pub(super) async fn send_request(req: RequestExt) -> Result<ResponseExt, Respons
         .as_ref()
         .ok_or_else(|| ResponseError::BadRequest(ProxyError::BodyRequired))?;
     let body = serde_json::from_slice::<model::Req>(bytes)?;
+    validate(&body)?;
 
-    // Convert to ChatGPT API Message
-    let mut messages = Vec::with_capacity(body.messages.len());
+    // Resolve tool definitions
+    let tool = tool::ToolSpec::from_req(&body);
+
+    // Count prompt tokens before the messages are converted
+    let prompt_tokens = count_prompt_tokens(&body, tool.as_ref());
+
+    // Render messages, prepend the tool prompt scaffold
+    let offset = tool.is_some() as usize;
+    let mut texts = Vec::with_capacity(body.messages.len() + offset);
+    if let Some(ref tool) = tool {
+        texts.push(Rendered {
+            role: Role::System,
+            text: tool.prompt(),
+            images: vec![],
+        });
+    }
     for body_msg in body.messages.iter() {
-        let role = if body_msg.role.eq(&Role::System) {
-            Role::Critic
-        } else {
-            body_msg.role
-        };
-        let message = Messages::builder()
-            .id(uuid())
-            .author(Author { role })
-            .content(
-                Content::builder()
-                    .content_type(ContentText::Text)
-                    .parts(vec![&body_msg.content])
-                    .build(),
-            )
-            .metadata(Metadata {})
-            .build();
-        messages.push(message)
+        let (role, text) = tool::render_message(body_msg, &body.messages);
+        let images = body_msg
+            .content
+            .as_ref()
+            .map(|c| c.images())
+            .unwrap_or_default();
+        texts.push(Rendered { role, text, images });
     }
 
-    // Request client
-    let client = with_context!(api_client);
+    // Continue a known conversation instead of replaying the whole history
+    let reuse = with_context!(conversation_reuse);
+    let mut digest = reuse::PrefixDigest::new(&cache_id, tool.as_ref());
+    let hit = if reuse {
+        reuse::lookup(&mut digest, &texts[offset..])
+    } else {
+        None
+    };
 
     // OpenAI API to ChatGPT API model mapper
     let gpt_model = GPTModel::from_str(&body.model)?;
 
-    // check if arkose token is required
-    let arkose_token: Option<String> =
-        if (with_context!(arkose_gpt3_experiment) && gpt_model.is_gpt3()) || gpt_model.is_gpt4() {
-            let arkose_token = ArkoseToken::new_from_context(
-                ArkoseContext::builder()
-                    .client(client.clone())
-                    .typed(gpt_model.clone().into())
-                    .identifier(Some(baerer.to_owned()))
-                    .build(),
-            )
-            .await?;
-            Some(arkose_token.into())
-        } else {
-            None
-        };
-
-    // Create request
-    let parent_message_id = uuid();
-    let req_body = PostConvoRequest::builder()
-        .action(Action::Next)
-        .arkose_token(arkose_token.as_deref())
-        .conversation_mode(ConversationMode {
-            kind: "primary_assistant",
-        })
-        .force_paragen(false)
-        .force_rate_limit(false)
-        .history_and_training_disabled(true)
-        .messages(messages)
-        .model(gpt_model)
-        .parent_message_id(&parent_message_id)
-        .suggestions(SUGGESTIONS.to_vec())
-        .timezone_offset_min(-480)
-        .build();
-
-    let mut builder = client
-        .post(format!("{URL_CHATGPT_API}/backend-api/conversation"))
-        .headers(header_convert(&req.headers, &req.jar, URL_CHATGPT_API)?);
+    // Count the message against the account message cap, a capped message may be downgraded
+    let (gpt_model, slot) = msgcap::acquire(baerer, gpt_model, &pool).await?;
 
     // Try to get puid from cache
-    let puid = get_or_init(baerer, &body.model, cache_id).await?;
-    if let Some(puid) = puid {
-        builder = builder.header(header::COOKIE, format!("_puid={puid};"))
+    let mut headers = header_convert(&req.headers, &req.jar, URL_CHATGPT_API)?;
+    if let Some(puid) = get_or_init(baerer, &body.model, cache_id.clone(), &pool).await? {
+        headers.append(
+            header::COOKIE,
+            HeaderValue::from_str(&format!("_puid={puid};"))?,
+        );
     }
 
-    // Send request
-    let resp = builder
-        .json(&req_body)
-        .send()
-        .await
-        .map_err(ResponseError::InternalServerError)?;
+    let mut upstream = Upstream {
+        headers,
+        baerer: baerer.to_owned(),
+        account: cache_id,
+        pool,
+        model: gpt_model,
+        last: None,
+    };
+
+    let (resp, in_flight) = match hit {
+        Some((len, key, conversation)) => {
+            debug!(
+                "Reuse conversation {}, skip {len} messages",
+                conversation.conversation_id
+            );
+            let (resp, in_flight) = upstream
+                .send(&texts[offset + len..], Some(&conversation))
+                .await?;
+
+            // The conversation is gone upstream, replay the whole history
+            if resp.status().eq(&StatusCode::NOT_FOUND) {
+                with_context!(remove_conversation, &key);
+                upstream.send(&texts, None).await?
+            } else {
+                (resp, in_flight)
+            }
+        }
+        None => upstream.send(&texts, None).await?,
+    };
+
+    // The message cap slot is given back if ChatGPT did not accept the message
+    if let (Some(slot), true) = (slot, resp.status().is_success()) {
+        slot.keep();
+    }
+
+    // Further choices are regenerated from the same request
+    let n = body.n.unwrap_or(1);
+    let limits = limit::Limits::new(
+        body.stop.map(Into::into).unwrap_or_default(),
+        body.max_tokens,
+    );
 
     Ok(ResponseExt::builder()
         .inner(resp)
+        .in_flight(in_flight)
         .context(
             Context::builder()
                 .model(body.model)
                 .stream(body.stream)
+                .tool(tool)
+                .prompt_tokens(prompt_tokens)
+                .include_usage(body.stream_options.map_or(false, |o| o.include_usage))
+                .conversation(reuse.then_some(digest))
+                .limits(limits)
+                .n(n)
+                .upstream((n > 1).then_some(upstream))
                 .build(),
         )
         .build())
 }
 
+/// Reject parameters the official API would reject
+fn validate(body: &model::Req) -> Result<(), ResponseError> {
+    if body.n.is_some_and(|n| n == 0 || n > MAX_CHOICES) {
+        return Err(ResponseError::BadRequest(ProxyError::InvalidChoices(
+            MAX_CHOICES,
+        )));
+    }
+    if body.max_tokens.is_some_and(|max_tokens| max_tokens == 0) {
+        return Err(ResponseError::BadRequest(ProxyError::InvalidParameter(
+            "max_tokens must be at least 1",
+        )));
+    }
+    if matches!(&body.stop, Some(model::Stop::Many(stop)) if stop.len() > 4) {
+        return Err(ResponseError::BadRequest(ProxyError::InvalidParameter(
+            "stop must have at most 4 sequences",
+        )));
+    }
+    if body.temperature.is_some_and(|t| !(0.0..=2.0).contains(&t)) {
+        return Err(ResponseError::BadRequest(ProxyError::InvalidParameter(
+            "temperature must be between 0 and 2",
+        )));
+    }
+    if body.top_p.is_some_and(|p| !(0.0..=1.0).contains(&p)) {
+        return Err(ResponseError::BadRequest(ProxyError::InvalidParameter(
+            "top_p must be between 0 and 1",
+        )));
+    }
+    Ok(())
+}
+
+/// A request message rendered for ChatGPT
+struct Rendered<'a> {
+    role: Role,
+    text: String,
+    /// Image urls to upload with the message
+    images: Vec<&'a str>,
+}
+
+/// Convert a rendered message to ChatGPT API message
+fn convert_message<'a>(
+    id: String,
+    role: Role,
+    text: &'a str,
+    images: &[image::UploadedImage],
+) -> Messages<'a> {
+    let role = if role.eq(&Role::System) {
+        Role::Critic
+    } else {
+        role
+    };
+
+    let (content, metadata) = if images.is_empty() {
+        (
+            Content::builder()
+                .content_type(ContentText::Text)
+                .parts(vec![text.into()])
+                .build(),
+            Metadata::default(),
+        )
+    } else {
+        let mut parts = images
+            .iter()
+            .map(image::UploadedImage::part)
+            .collect::<Vec<_>>();
+        if !text.is_empty() {
+            parts.push(text.into());
+        }
+        (
+            Content::builder()
+                .content_type(ContentText::MultimodalText)
+                .parts(parts)
+                .build(),
+            Metadata::builder()
+                .attachments(images.iter().map(|i| i.attachment()).collect())
+                .build(),
+        )
+    };
+
+    Messages::builder()
+        .id(id)
+        .author(Author { role })
+        .content(content)
+        .metadata(metadata)
+        .build()
+}
+
+/// The last message of a request, resent to regenerate the reply
+struct LastMessage {
+    id: String,
+    parent_message_id: String,
+    role: Role,
+    text: String,
+    images: Vec<image::UploadedImage>,
+}
+
+/// ChatGPT conversation API of an account
+pub struct Upstream {
+    headers: HeaderMap,
+    baerer: String,
+    /// Email of the account, the client is pinned to it
+    account: String,
+    pool: Arc<ClientRoundRobinBalancer>,
+    model: GPTModel,
+    last: Option<LastMessage>,
+}
+
+impl Upstream {
+    /// Post messages to a new conversation, or continue an existing one
+    async fn send(
+        &mut self,
+        messages: &[Rendered<'_>],
+        conversation: Option<&Conversation>,
+    ) -> Result<(reqwest::Response, InFlight), ResponseError> {
+        // Upload images through the file service
+        let client: Lease<reqwest::Client> = self.pool.next_for(Some(self.account.as_str())).into();
+        let mut images = Vec::with_capacity(messages.len());
+        for message in messages {
+            let mut uploaded = Vec::with_capacity(message.images.len());
+            for url in message.images.iter() {
+                uploaded.push(image::upload(&client, &self.headers, url, &self.account).await?);
+            }
+            images.push(uploaded);
+        }
+
+        // Messages are chained in order
+        let ids = messages.iter().map(|_| uuid()).collect::<Vec<_>>();
+        let parent_message_id = match conversation {
+            Some(c) => c.parent_message_id.clone(),
+            None => uuid(),
+        };
+        let converted = messages
+            .iter()
+            .zip(ids.iter())
+            .zip(images.iter())
+            .map(|((message, id), images)| {
+                convert_message(id.clone(), message.role, &message.text, images)
+            })
+            .collect::<Vec<_>>();
+
+        self.last = messages.last().zip(images.pop()).map(|(message, images)| {
+            let len = ids.len();
+            LastMessage {
+                id: ids[len - 1].clone(),
+                parent_message_id: if len > 1 {
+                    ids[len - 2].clone()
+                } else {
+                    parent_message_id.clone()
+                },
+                role: message.role,
+                text: message.text.clone(),
+                images,
+            }
+        });
+
+        self.post(
+            self.model.clone(),
+            Action::Next,
+            converted,
+            conversation.map(|c| c.conversation_id.as_str()),
+            &parent_message_id,
+        )
+        .await
+    }
+
+    /// Regenerate the reply to the last message as a new variant,
+    /// each variant is a message of the account message cap
+    pub(super) async fn variant(
+        &self,
+        conversation_id: &str,
+    ) -> Result<(reqwest::Response, InFlight), ResponseError> {
+        let last = self
+            .last
+            .as_ref()
+            .ok_or(ResponseError::BadRequest(ProxyError::BodyMessageIsEmpty))?;
+        let (model, slot) = msgcap::acquire(&self.baerer, self.model.clone(), &self.pool).await?;
+        let message = convert_message(last.id.clone(), last.role, &last.text, &last.images);
+        let (resp, in_flight) = self
+            .post(
+                model,
+                Action::Variant,
+                vec![message],
+                Some(conversation_id),
+                &last.parent_message_id,
+            )
+            .await?;
+
+        if let (Some(slot), true) = (slot, resp.status().is_success()) {
+            slot.keep();
+        }
+        Ok((resp, in_flight))
+    }
+
+    async fn post(
+        &self,
+        model: GPTModel,
+        action: Action,
+        messages: Vec<Messages<'_>>,
+        conversation_id: Option<&str>,
+        parent_message_id: &str,
+    ) -> Result<(reqwest::Response, InFlight), ResponseError> {
+        // Request client, counted in flight until the reply body is read
+        let (client, in_flight) =
+            Lease::<reqwest::Client>::from(self.pool.next_for(Some(self.account.as_str())))
+                .into_parts();
+
+        // check if arkose token is required
+        let arkose_token: Option<String> =
+            if (with_context!(arkose_gpt3_experiment) && model.is_gpt3()) || model.is_gpt4() {
+                let arkose_token = ArkoseToken::new_from_context(
+                    ArkoseContext::builder()
+                        .client(client.clone())
+                        .typed(model.clone().into())
+                        .identifier(Some(self.baerer.clone()))
+                        .build(),
+                )
+                .await?;
+                Some(arkose_token.into())
+            } else {
+                None
+            };
+
+        // Create request
+        let req_body = PostConvoRequest::builder()
+            .action(action)
+            .arkose_token(arkose_token.as_deref())
+            .conversation_mode(ConversationMode {
+                kind: "primary_assistant",
+            })
+            .conversation_id(conversation_id)
+            .force_paragen(false)
+            .force_rate_limit(false)
+            // History and training stay disabled unless explicitly enabled
+            .history_and_training_disabled(!with_context!(conversation_history))
+            .messages(messages)
+            .model(model)
+            .parent_message_id(parent_message_id)
+            .suggestions(SUGGESTIONS.to_vec())
+            .timezone_offset_min(-480)
+            .build();
+
+        // Send request
+        let resp = client
+            .post(format!("{URL_CHATGPT_API}/backend-api/conversation"))
+            .headers(self.headers.clone())
+            .json(&req_body)
+            .send()
+            .await;
+        let resp = super::req::observe(URL_CHATGPT_API, resp)
+            .map_err(ResponseError::InternalServerError)?;
+        origin_status(resp.status());
+        Ok((resp, in_flight))
+    }
+}
+
 /// Convert response to ChatGPT API
 pub(super) async fn response_convert(
     resp_ext: ResponseExt,
@@ -175,16 +466,13 @@ pub(super) async fn response_convert(
                 ProxyError::RequestContentIsEmpty,
             ))?;
 
-            // Get response body event source
-            let event_source = resp.bytes_stream().eventsource();
-
             if config.stream {
                 // Create a  stream response
-                let stream = stream::stream_handler(event_source, config.model)?;
+                let stream = stream::stream_handler(resp, config)?;
                 Ok(Sse::new(stream).into_response())
             } else {
                 // Create a not stream response
-                let no_stream = stream::not_stream_handler(event_source, config.model).await?;
+                let no_stream = stream::not_stream_handler(resp, config).await?;
                 Ok(no_stream.into_response())
             }
         }
@@ -214,6 +502,52 @@ fn handle_error_response(err: reqwest::Error) -> Result<impl IntoResponse, Respo
     }
 }
 
+/// Most choices served by regenerating the reply
+const MAX_CHOICES: usize = 8;
+
+/// Images are counted as a single low detail tile
+const IMAGE_TOKENS: usize = 85;
+
+/// Count prompt tokens the way the official API does for chat models
+fn count_prompt_tokens(body: &model::Req, tool: Option<&tool::ToolSpec>) -> i64 {
+    let bpe = tokenizer::cl100k_base();
+
+    // Every reply is primed with <|start|>assistant<|message|>
+    let mut tokens = 3;
+    for message in body.messages.iter() {
+        // Every message follows <|start|>{role/name}\n{content}<|end|>\n
+        tokens += 3;
+        tokens += bpe.count(&message.role.to_string());
+        if let Some(ref content) = message.content {
+            tokens += bpe.count(&content.text());
+            tokens += content.images().len() * IMAGE_TOKENS;
+        }
+        if let Some(ref name) = message.name {
+            tokens += bpe.count(name) + 1;
+        }
+        for call in message
+            .tool_calls
+            .iter()
+            .flatten()
+            .map(|c| &c.function)
+            .chain(message.function_call.iter())
+        {
+            tokens += bpe.count(&call.name) + bpe.count(&call.arguments);
+        }
+    }
+
+    if let Some(tool) = tool {
+        tokens += bpe.count(&tool.definitions());
+    }
+
+    tokens as i64
+}
+
+/// Count completion tokens of the reply text
+fn count_completion_tokens(text: &str) -> i64 {
+    tokenizer::cl100k_base().count(text) as i64
+}
+
 fn generate_id(length: usize) -> String {
     let rand_str = crate::generate_random_string(length);
     format!("chatcmpl-{rand_str}")
@@ -2,6 +2,8 @@ use serde::Deserialize;
 
 use crate::chatgpt::model::Role;
 use serde::Serialize;
+use serde_json::Value;
+use std::borrow::Cow;
 use typed_builder::TypedBuilder;
 
 #[derive(Deserialize)]
@@ -10,6 +12,54 @@ pub struct Req {
     pub messages: Vec<Message>,
     #[serde(default)]
     pub stream: bool,
+    #[serde(default)]
+    pub stream_options: Option<StreamOptions>,
+    #[serde(default)]
+    pub stop: Option<Stop>,
+    #[serde(default, alias = "max_completion_tokens")]
+    pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub n: Option<usize>,
+    /// ChatGPT does not support sampling parameters, they are only validated
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(default)]
+    pub tool_choice: Option<ToolChoice>,
+    #[serde(default)]
+    pub parallel_tool_calls: Option<bool>,
+    /// Deprecated in favor of `tools`
+    #[serde(default)]
+    pub functions: Option<Vec<Function>>,
+    /// Deprecated in favor of `tool_choice`
+    #[serde(default)]
+    pub function_call: Option<FunctionChoice>,
+}
+
+/// Up to 4 sequences where the reply stops
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<Stop> for Vec<String> {
+    fn from(value: Stop) -> Self {
+        match value {
+            Stop::One(stop) => vec![stop],
+            Stop::Many(stop) => stop,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StreamOptions {
+    #[serde(default)]
+    pub include_usage: bool,
 }
 
 #[derive(Serialize, TypedBuilder, Clone)]
@@ -27,7 +77,137 @@ pub struct Resp<'a> {
 #[derive(Serialize, Deserialize, TypedBuilder, Clone)]
 pub struct Message {
     pub role: Role,
-    pub content: String,
+    #[builder(default)]
+    #[serde(default)]
+    pub content: Option<Content>,
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<FunctionCall>,
+}
+
+/// Plain text or an array of content parts
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ImageUrl {
+    /// Remote url or base64 data url
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Content {
+    /// Text parts joined by newlines
+    pub fn text(&self) -> Cow<'_, str> {
+        match self {
+            Content::Text(text) => Cow::Borrowed(text),
+            Content::Parts(parts) => Cow::Owned(
+                parts
+                    .iter()
+                    .filter_map(|p| match p {
+                        ContentPart::Text { text } => Some(text.as_str()),
+                        ContentPart::ImageUrl { .. } => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+        }
+    }
+
+    /// Image urls in order
+    pub fn images(&self) -> Vec<&str> {
+        match self {
+            Content::Text(_) => vec![],
+            Content::Parts(parts) => parts
+                .iter()
+                .filter_map(|p| match p {
+                    ContentPart::ImageUrl { image_url } => Some(image_url.url.as_str()),
+                    ContentPart::Text { .. } => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<String> for Content {
+    fn from(value: String) -> Self {
+        Content::Text(value)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: Function,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Function {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Value>,
+}
+
+/// `none`/`auto`/`required` or a specific function
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(String),
+    Named { function: FunctionName },
+}
+
+/// `none`/`auto` or a specific function
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum FunctionChoice {
+    Mode(String),
+    Named(FunctionName),
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FunctionName {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ToolCall {
+    /// Only present in stream chunks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String,
 }
 
 #[derive(Serialize, TypedBuilder, Clone)]
@@ -58,4 +238,27 @@ pub struct Delta<'a> {
     #[builder(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content: Option<&'a str>,
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<&'a [ToolCall]>,
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<&'a FunctionCall>,
+}
+
+#[derive(Serialize, TypedBuilder, Clone)]
+pub struct Model<'a> {
+    pub id: &'a str,
+    #[builder(default = "model")]
+    pub object: &'a str,
+    pub created: i64,
+    #[builder(default = "openai")]
+    pub owned_by: &'a str,
+}
+
+#[derive(Serialize, TypedBuilder)]
+pub struct ModelList<'a> {
+    #[builder(default = "list")]
+    pub object: &'a str,
+    pub data: Vec<Model<'a>>,
 }
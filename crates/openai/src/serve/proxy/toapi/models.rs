@@ -0,0 +1,132 @@
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use axum::http::Method;
+use axum::Json;
+use moka::sync::Cache;
+use serde_json::Value;
+
+use crate::chatgpt::api::{ApiError, ChatGPTBuilder};
+use crate::gpt_model::GPTModel;
+use crate::serve::error::{ProxyError, ResponseError};
+use crate::serve::proxy::ext::RequestExt;
+use crate::serve::puid::reduce_key;
+use crate::{token, with_context, URL_CHATGPT_API};
+
+use super::model::{Model, ModelList};
+
+const MODELS_PATH: &str = "/v1/models";
+/// Accounts whose models are cached at most
+const DEFAULT_MAX_CAPACITY: u64 = 10_000;
+
+/// Account email -> models available to the account
+static MODELS_CACHE: OnceLock<Cache<String, Vec<GPTModel>>> = OnceLock::new();
+
+/// Check if the request lists or retrieves models with an access token
+pub(crate) fn support(req: &RequestExt) -> bool {
+    let path = req.uri.path();
+    if req.method.eq(&Method::GET)
+        && (path.eq(MODELS_PATH) || path.starts_with(&format!("{MODELS_PATH}/")))
+    {
+        if let Some(ref token) = req.bearer_auth() {
+            return !token::check_sk_or_sess(token);
+        }
+    }
+    false
+}
+
+/// Answer `/v1/models` and `/v1/models/:id` from the ChatGPT models of the account
+pub(crate) async fn handle(req: RequestExt) -> Result<Json<Value>, ResponseError> {
+    let baerer = req
+        .bearer_auth()
+        .ok_or(ResponseError::Unauthorized(ProxyError::AccessTokenRequired))?;
+    let cache_id = reduce_key(baerer)?;
+    let models = account_models(baerer, cache_id).await?;
+    let allows = |model: &str| req.api_key.as_ref().map_or(true, |key| key.allows(model));
+
+    // Retrieve a model, any name the chat completions bridge maps to an
+    // available model is reported under the requested name
+    if let Some(id) = req
+        .uri
+        .path()
+        .strip_prefix(MODELS_PATH)
+        .and_then(|p| p.strip_prefix('/'))
+    {
+        let model = GPTModel::from_str(id)
+            .ok()
+            .filter(|m| models.contains(m) && allows(id))
+            .ok_or_else(|| ResponseError::NotFound(ProxyError::ModelNotFound(id.to_owned())))?;
+        let json = serde_json::to_value(Model::builder().id(id).created(created(&model)).build())?;
+        return Ok(Json(json));
+    }
+
+    let data = models
+        .iter()
+        .filter(|m| allows(name(m)))
+        .map(|m| Model::builder().id(name(m)).created(created(m)).build())
+        .collect();
+    let json = serde_json::to_value(ModelList::builder().data(data).build())?;
+    Ok(Json(json))
+}
+
+/// Get the ChatGPT models of the account, cached for an hour
+async fn account_models(token: &str, cache_id: String) -> Result<Vec<GPTModel>, ResponseError> {
+    let cache = MODELS_CACHE.get_or_init(|| {
+        Cache::builder()
+            .max_capacity(DEFAULT_MAX_CAPACITY)
+            .time_to_live(Duration::from_secs(3600))
+            .build()
+    });
+
+    if let Some(models) = cache.get(&cache_id) {
+        return Ok(models);
+    }
+
+    let client = with_context!(
+        api_client_for,
+        &format!("{URL_CHATGPT_API}/backend-api/models"),
+        Some(cache_id.as_str())
+    );
+    let api = ChatGPTBuilder::builder()
+        .client(client.clone())
+        .access_token(token.to_owned())
+        .build();
+    let resp = api.get_models().await.map_err(|err| match err {
+        ApiError::BadAuthenticationError(_) => ResponseError::Unauthorized(err),
+        ApiError::TooManyRequestsError(_) => ResponseError::TooManyRequests(err),
+        ApiError::BadRequestError(_) => ResponseError::BadRequest(err),
+        _ => ResponseError::InternalServerError(err),
+    })?;
+
+    let mut models = Vec::new();
+    for model in resp
+        .models
+        .iter()
+        .filter_map(|m| GPTModel::from_str(m.model_name()).ok())
+    {
+        if !models.contains(&model) {
+            models.push(model);
+        }
+    }
+
+    cache.insert(cache_id, models.clone());
+    Ok(models)
+}
+
+/// OpenAI API model name of the ChatGPT model
+fn name(model: &GPTModel) -> &'static str {
+    match model {
+        GPTModel::Gpt35 => "gpt-3.5-turbo",
+        GPTModel::Gpt4 => "gpt-4",
+        GPTModel::Gpt4Mobile => "gpt-4-mobile",
+    }
+}
+
+/// Creation time reported by the OpenAI API for the model
+fn created(model: &GPTModel) -> i64 {
+    match model {
+        GPTModel::Gpt35 => 1677610602,
+        GPTModel::Gpt4 | GPTModel::Gpt4Mobile => 1687882411,
+    }
+}
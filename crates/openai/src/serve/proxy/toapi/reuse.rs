@@ -0,0 +1,193 @@
+use base64::{engine::general_purpose, Engine};
+use sha2::{Digest, Sha256};
+
+use crate::chatgpt::model::Role;
+use crate::context::conversation::Conversation;
+use crate::with_context;
+
+use super::model::Message;
+use super::tool::{self, ToolSpec};
+use super::Rendered;
+
+/// Running digest over the rendered message history of an account.
+/// The digest of a history ending with an assistant reply keys the
+/// ChatGPT conversation that produced the reply.
+#[derive(Clone)]
+pub struct PrefixDigest(Sha256);
+
+impl PrefixDigest {
+    /// Conversations are never shared across accounts or tool definitions
+    pub(super) fn new(account: &str, tool: Option<&ToolSpec>) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(account.as_bytes());
+        hasher.update([0]);
+        if let Some(tool) = tool {
+            hasher.update(tool.prompt().as_bytes());
+        }
+        hasher.update([0]);
+        Self(hasher)
+    }
+
+    pub(super) fn update(&mut self, role: &Role, text: &str, images: &[&str]) {
+        self.0.update(role.to_string().as_bytes());
+        self.0.update([0]);
+        self.0.update(text.as_bytes());
+        self.0.update([0]);
+        for url in images {
+            self.0.update(url.as_bytes());
+            self.0.update([0]);
+        }
+    }
+
+    pub(super) fn key(&self) -> String {
+        general_purpose::URL_SAFE_NO_PAD.encode(self.0.clone().finalize())
+    }
+}
+
+/// Find the longest cached history prefix ending with an assistant reply,
+/// returns the number of messages it covers, its key and the conversation
+pub(super) fn lookup(
+    digest: &mut PrefixDigest,
+    texts: &[Rendered<'_>],
+) -> Option<(usize, String, Conversation)> {
+    lookup_with(digest, texts, |key| with_context!(get_conversation, key))
+}
+
+fn lookup_with(
+    digest: &mut PrefixDigest,
+    texts: &[Rendered<'_>],
+    get: impl Fn(&str) -> Option<Conversation>,
+) -> Option<(usize, String, Conversation)> {
+    let mut hit = None;
+    for (index, message) in texts.iter().enumerate() {
+        digest.update(&message.role, &message.text, &message.images);
+        // The history must continue after the reply
+        if message.role.ne(&Role::Assistant) || index + 1 == texts.len() {
+            continue;
+        }
+
+        let key = digest.key();
+        if let Some(conversation) = get(&key) {
+            hit = Some((index + 1, key, conversation));
+        }
+    }
+    hit
+}
+
+/// Remember the conversation of a finished reply, so the next request
+/// continuing this history only sends the new messages
+pub(super) fn remember(
+    mut digest: PrefixDigest,
+    tool: Option<&ToolSpec>,
+    reply: &str,
+    conversation_id: &str,
+    message_id: &str,
+) {
+    if reply.is_empty() || conversation_id.is_empty() || message_id.is_empty() {
+        return;
+    }
+
+    // Render the reply the way the client will send it back
+    let message = tool
+        .and_then(|t| t.parse(reply).map(|calls| t.message(calls)))
+        .unwrap_or_else(|| {
+            Message::builder()
+                .role(Role::Assistant)
+                .content(Some(reply.to_owned().into()))
+                .build()
+        });
+    let (role, text) = tool::render_message(&message, &[]);
+    digest.update(&role, &text, &[]);
+
+    with_context!(
+        put_conversation,
+        digest.key(),
+        Conversation {
+            conversation_id: conversation_id.to_owned(),
+            parent_message_id: message_id.to_owned(),
+        }
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn rendered(role: Role, text: &str) -> Rendered<'static> {
+        Rendered {
+            role,
+            text: text.to_owned(),
+            images: vec![],
+        }
+    }
+
+    fn conversation(id: &str) -> Conversation {
+        Conversation {
+            conversation_id: id.to_owned(),
+            parent_message_id: format!("{id}-parent"),
+        }
+    }
+
+    #[test]
+    fn test_prefix_digest() {
+        let key = |account: &str, text: &str| {
+            let mut digest = PrefixDigest::new(account, None);
+            digest.update(&Role::User, text, &[]);
+            digest.key()
+        };
+        assert_eq!(key("a@example.com", "hi"), key("a@example.com", "hi"));
+        assert_ne!(key("a@example.com", "hi"), key("b@example.com", "hi"));
+        assert_ne!(key("a@example.com", "hi"), key("a@example.com", "hello"));
+
+        // The message boundaries are part of the digest
+        let mut split = PrefixDigest::new("a@example.com", None);
+        split.update(&Role::User, "ab", &[]);
+        split.update(&Role::User, "c", &[]);
+        let mut joined = PrefixDigest::new("a@example.com", None);
+        joined.update(&Role::User, "a", &[]);
+        joined.update(&Role::User, "bc", &[]);
+        assert_ne!(split.key(), joined.key());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let texts = [
+            rendered(Role::User, "hi"),
+            rendered(Role::Assistant, "hello"),
+            rendered(Role::User, "how are you"),
+            rendered(Role::Assistant, "fine"),
+            rendered(Role::User, "bye"),
+        ];
+        let key_at = |len: usize| {
+            let mut digest = PrefixDigest::new("a@example.com", None);
+            for t in &texts[..len] {
+                digest.update(&t.role, &t.text, &t.images);
+            }
+            digest.key()
+        };
+
+        // The longest cached prefix ending with an assistant reply wins
+        let store = HashMap::from([
+            (key_at(2), conversation("first")),
+            (key_at(4), conversation("second")),
+        ]);
+        let mut digest = PrefixDigest::new("a@example.com", None);
+        let (covered, key, hit) =
+            lookup_with(&mut digest, &texts, |key| store.get(key).cloned()).unwrap();
+        assert_eq!(covered, 4);
+        assert_eq!(key, key_at(4));
+        assert_eq!(hit.conversation_id, "second");
+
+        // A history ending with the cached reply has nothing to continue
+        let mut digest = PrefixDigest::new("a@example.com", None);
+        assert!(lookup_with(&mut digest, &texts[..4], |key| {
+            (key == key_at(4)).then(|| conversation("second"))
+        })
+        .is_none());
+
+        // Another account never hits
+        let mut digest = PrefixDigest::new("b@example.com", None);
+        assert!(lookup_with(&mut digest, &texts, |key| store.get(key).cloned()).is_none());
+    }
+}
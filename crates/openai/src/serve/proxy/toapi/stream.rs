@@ -1,6 +1,6 @@
 use axum::response::sse::Event;
 use axum::Json;
-use eventsource_stream::EventStream;
+use eventsource_stream::Eventsource;
 use futures_core::Stream;
 use serde_json::Value;
 use std::convert::Infallible;
@@ -8,20 +8,74 @@ use tokio_stream::StreamExt;
 
 use crate::chatgpt::model::resp::{ConvoResponse, PostConvoResponse};
 use crate::chatgpt::model::Role;
+use crate::client::InFlight;
 use crate::serve::error::{ProxyError, ResponseError};
 use crate::serve::ProxyResult;
 use crate::warn;
 
+use super::limit::{Limits, TokenCount};
 use super::model;
+use super::reuse;
+use super::tool::{self, Probe, ToolSpec};
+use super::Upstream;
+use crate::serve::proxy::ext::Context;
 
 struct HandlerContext<'a> {
-    stop: &'a mut u8,
     id: &'a str,
     timestamp: &'a i64,
     model: &'a str,
-    previous_message: &'a mut String,
-    pin_message_id: &'a mut String,
-    set_role: &'a mut bool,
+    tool: Option<&'a ToolSpec>,
+    limits: &'a Limits,
+    // Tokens of the reply counted so far
+    tokens: TokenCount,
+    // Choice index
+    index: i64,
+    // Reply text sent to the client
+    previous_message: String,
+    // Latest reply text received from upstream
+    raw_message: String,
+    pin_message_id: String,
+    set_role: bool,
+    tool_probe: Probe,
+    // Finish reason sent to the client
+    finish_reason: Option<&'static str>,
+}
+
+impl<'a> HandlerContext<'a> {
+    fn new(
+        id: &'a str,
+        timestamp: &'a i64,
+        model: &'a str,
+        tool: Option<&'a ToolSpec>,
+        limits: &'a Limits,
+        index: usize,
+    ) -> Self {
+        Self {
+            id,
+            timestamp,
+            model,
+            tool,
+            limits,
+            tokens: TokenCount::default(),
+            index: index as i64,
+            previous_message: String::new(),
+            raw_message: String::new(),
+            pin_message_id: String::new(),
+            set_role: true,
+            // Without tools the reply is always plain text
+            tool_probe: if tool.is_some() {
+                Probe::Pending
+            } else {
+                Probe::Text
+            },
+            finish_reason: None,
+        }
+    }
+
+    /// Whether the client received the whole upstream reply
+    fn complete(&self) -> bool {
+        self.previous_message.eq(&self.raw_message)
+    }
 }
 
 /// Check if should skip conversion
@@ -43,70 +97,160 @@ fn should_skip_conversion(convo: &ConvoResponse, pin_message_id: &str) -> bool {
     role_check || metadata_check
 }
 
+/// OpenAI finish reason of a finished upstream reply
+fn finish_reason(convo: &ConvoResponse) -> Option<&'static str> {
+    match convo.metadata_finish_details_type() {
+        "max_tokens" => Some("length"),
+        _ if convo.end_turn().unwrap_or_default() => Some("stop"),
+        _ => None,
+    }
+}
+
+/// Request the reply of the next choice, the first one is already requested.
+/// A variant is in flight through its client until the guard is dropped
+async fn next_choice(
+    first: &mut Option<reqwest::Response>,
+    upstream: Option<&Upstream>,
+    conversation_id: &str,
+) -> Result<(reqwest::Response, Option<InFlight>), ResponseError> {
+    if let Some(resp) = first.take() {
+        return Ok((resp, None));
+    }
+
+    let upstream = upstream.ok_or(ResponseError::InternalServerError(
+        ProxyError::RequestContentIsEmpty,
+    ))?;
+    let (resp, in_flight) = upstream.variant(conversation_id).await?;
+    let resp = resp.error_for_status().map_err(|err| {
+        warn!("variant request failed: {err}");
+        ResponseError::BadGateway(err)
+    })?;
+    Ok((resp, Some(in_flight)))
+}
+
+/// Error event ending a stream whose choices can not all be served
+fn error_event(err: ResponseError) -> ProxyResult<Event> {
+    let data = format!(
+        " {}",
+        serde_json::to_string(&err).map_err(ProxyError::DeserializeError)?
+    );
+    Ok(Event::default().data(data))
+}
+
 pub(super) fn stream_handler(
-    mut event_soure: EventStream<
-        impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + std::marker::Unpin,
-    >,
-    model: String,
+    resp: reqwest::Response,
+    config: Context,
 ) -> Result<impl Stream<Item = Result<Event, Infallible>>, ResponseError> {
     let id = super::generate_id(29);
     let timestamp = super::current_timestamp()?;
+    let Context {
+        model,
+        tool,
+        prompt_tokens,
+        include_usage,
+        conversation,
+        limits,
+        n,
+        upstream,
+        ..
+    } = config;
     let stream = async_stream::stream! {
-        let mut previous_message = String::new();
-        let mut pin_message_id = String::new();
-        let mut set_role = true;
-        let mut stop: u8 = 0;
-
-        while let Some(event_result) = event_soure.next().await {
-            match event_result {
-                Ok(message) =>  {
-                    if message.data.eq("[DONE]") {
-                        yield Ok(Event::default().data(message.data));
-                        break;
+        let mut first = Some(resp);
+        let mut conversation_id = String::new();
+        let mut completion_tokens = 0;
+
+        for index in 0..n {
+            let (resp, _in_flight) = match next_choice(&mut first, upstream.as_ref(), &conversation_id).await {
+                Ok(choice) => choice,
+                Err(err) => {
+                    if let Ok(event) = error_event(err) {
+                        yield Ok(event);
                     }
-                    if let Ok(res) = serde_json::from_str::<PostConvoResponse>(&message.data) {
-                        if let PostConvoResponse::Conversation(convo) = res {
+                    return;
+                }
+            };
 
-                            // Skip if role is not assistant
-                            if should_skip_conversion(&convo, &pin_message_id) {
-                                continue;
-                            }
+            let mut event_soure = resp.bytes_stream().eventsource();
+            let mut context =
+                HandlerContext::new(&id, &timestamp, &model, tool.as_ref(), &limits, index);
 
-                            let mut context = HandlerContext {
-                                stop: &mut stop,
-                                id: &id,
-                                timestamp: &timestamp,
-                                model: &model,
-                                previous_message: &mut previous_message,
-                                pin_message_id: &mut pin_message_id,
-                                set_role: &mut set_role,
-                            };
-
-                            if let Ok(event) = event_convert_handler(&mut context, convo).await {
-                                if stop == 0 || stop <= 1 {
-                                    yield Ok(event);
+            while let Some(event_result) = event_soure.next().await {
+                match event_result {
+                    Ok(message) =>  {
+                        if message.data.eq("[DONE]") {
+                            break;
+                        }
+                        if let Ok(res) = serde_json::from_str::<PostConvoResponse>(&message.data) {
+                            if let PostConvoResponse::Conversation(convo) = res {
+                                if conversation_id.is_empty() {
+                                    conversation_id.push_str(convo.conversation_id());
+                                }
+
+                                // Skip if role is not assistant
+                                if should_skip_conversion(&convo, &context.pin_message_id) {
+                                    continue;
+                                }
+
+                                if let Ok(events) = event_convert_handler(&mut context, convo) {
+                                    for event in events {
+                                        yield Ok(event);
+                                    }
+                                }
+
+                                // Dropping the event source cancels the upstream request
+                                // when a client side limit is hit
+                                if context.finish_reason.is_some() {
+                                    break;
                                 }
                             }
                         }
+                    },
+                    Err(err) => {
+                        warn!("event-source stream error: {}", err);
+                        break;
+                    }
+                }
+            }
+
+            drop(event_soure);
+
+            // Upstream ended without finishing the reply
+            if context.finish_reason.is_none() {
+                if let Ok(events) = finish_handler(&mut context) {
+                    for event in events {
+                        yield Ok(event);
                     }
-                },
-                Err(err) => {
-                    warn!("event-source stream error: {}", err);
-                    break;
                 }
             }
 
+            if let Some(digest) = conversation.clone().filter(|_| context.complete()) {
+                reuse::remember(
+                    digest,
+                    tool.as_ref(),
+                    &context.previous_message,
+                    &conversation_id,
+                    &context.pin_message_id,
+                );
+            }
+
+            completion_tokens += super::count_completion_tokens(&context.previous_message);
         }
 
-        drop(event_soure)
+        if include_usage {
+            let usage = usage_event(&id, &timestamp, &model, prompt_tokens, completion_tokens);
+            if let Ok(event) = usage {
+                yield Ok(event);
+            }
+        }
+        yield Ok(Event::default().data("[DONE]"));
     };
     Ok(stream)
 }
 
-async fn event_convert_handler(
+fn event_convert_handler(
     context: &mut HandlerContext<'_>,
     convo: ConvoResponse,
-) -> ProxyResult<Event> {
+) -> ProxyResult<Vec<Event>> {
     // Set pin message id
     if context.pin_message_id.is_empty() {
         context.pin_message_id.push_str(convo.message_id())
@@ -117,40 +261,172 @@ async fn event_convert_handler(
         .first()
         .ok_or_else(|| ProxyError::BodyMessageIsEmpty)?;
 
-    let finish_reason = convo
-        .end_turn()
-        .filter(|&end| end)
-        .map(|_| convo.metadata_finish_details_type());
+    context.raw_message.clear();
+    context.raw_message.push_str(message);
 
-    let role = if *context.set_role {
-        *context.set_role = false;
-        Some(convo.role())
-    } else {
-        None
-    };
+    let finish_reason = finish_reason(&convo);
 
-    let return_message = if let Some("stop") = finish_reason.as_deref() {
-        *context.stop += 1;
-        None
-    } else {
-        Some(message.trim_start_matches(context.previous_message.as_str()))
-    };
+    // Hold back the reply while it may still turn into a tool call
+    if let Some(tool) = context.tool {
+        if context.tool_probe.ne(&Probe::Text) {
+            return tool_convert_handler(context, tool, message, finish_reason);
+        }
+    }
+
+    text_convert_handler(context, message, finish_reason)
+}
+
+/// Finish the reply with whatever was received
+fn finish_handler(context: &mut HandlerContext<'_>) -> ProxyResult<Vec<Event>> {
+    let message = context.raw_message.clone();
+    if let Some(tool) = context.tool {
+        if context.tool_probe.ne(&Probe::Text) {
+            return tool_convert_handler(context, tool, &message, Some("stop"));
+        }
+    }
+    text_convert_handler(context, &message, Some("stop"))
+}
+
+/// Send the new text of the reply, cut by the client side limits
+fn text_convert_handler(
+    context: &mut HandlerContext<'_>,
+    message: &str,
+    finish_reason: Option<&'static str>,
+) -> ProxyResult<Vec<Event>> {
+    let (visible, limit) =
+        context
+            .limits
+            .apply(message, finish_reason.is_some(), &mut context.tokens);
+    let finish_reason = limit.or(finish_reason);
+    let mut events = Vec::with_capacity(2);
+
+    if let Some(content) = visible
+        .strip_prefix(context.previous_message.as_str())
+        .filter(|c| !c.is_empty())
+    {
+        let delta = model::Delta::builder()
+            .role(take_role(context, &Role::Assistant))
+            .content(Some(content))
+            .build();
+        events.push(chunk_event(context, delta, None)?);
+        context.previous_message.push_str(content);
+    }
+
+    if let Some(finish_reason) = finish_reason {
+        let delta = model::Delta::builder()
+            .role(take_role(context, &Role::Assistant))
+            .build();
+        events.push(chunk_event(context, delta, Some(finish_reason))?);
+        context.finish_reason = Some(finish_reason);
+    }
+
+    Ok(events)
+}
+
+fn tool_convert_handler(
+    context: &mut HandlerContext<'_>,
+    tool: &ToolSpec,
+    message: &str,
+    finish_reason: Option<&'static str>,
+) -> ProxyResult<Vec<Event>> {
+    if finish_reason.is_none() {
+        context.tool_probe = tool::probe(message);
+        if context.tool_probe.eq(&Probe::Text) {
+            // Not a tool call, flush everything held back so far
+            return text_convert_handler(context, message, None);
+        }
+        return Ok(vec![]);
+    }
+
+    match tool.parse(message) {
+        Some(mut tool_calls) => {
+            tool_calls
+                .iter_mut()
+                .enumerate()
+                .for_each(|(index, call)| call.index = Some(index));
 
-    context.previous_message.clear();
-    context.previous_message.push_str(message);
+            let role = Role::Assistant;
+            let delta = if tool.legacy() {
+                model::Delta::builder()
+                    .role(take_role(context, &role))
+                    .function_call(tool_calls.first().map(|c| &c.function))
+                    .build()
+            } else {
+                model::Delta::builder()
+                    .role(take_role(context, &role))
+                    .tool_calls(Some(tool_calls.as_slice()))
+                    .build()
+            };
+            let mut events = vec![chunk_event(context, delta, None)?];
 
-    let delta = model::Delta::builder()
-        .role(role)
-        .content(return_message)
+            let delta = model::Delta::builder().build();
+            events.push(chunk_event(context, delta, Some(tool.finish_reason()))?);
+
+            context.previous_message.clear();
+            context.previous_message.push_str(message);
+            context.finish_reason = Some(tool.finish_reason());
+            Ok(events)
+        }
+        None => {
+            // Reply ended without a valid tool call, send it as text
+            context.tool_probe = Probe::Text;
+            text_convert_handler(context, message, finish_reason)
+        }
+    }
+}
+
+/// Final chunk carrying the usage of the whole request
+fn usage_event(
+    id: &str,
+    timestamp: &i64,
+    model: &str,
+    prompt_tokens: i64,
+    completion_tokens: i64,
+) -> ProxyResult<Event> {
+    let resp = model::Resp::builder()
+        .id(id)
+        .object("chat.completion.chunk")
+        .created(timestamp)
+        .model(model)
+        .choices(vec![])
+        .usage(Some(
+            model::Usage::builder()
+                .prompt_tokens(prompt_tokens)
+                .completion_tokens(completion_tokens)
+                .total_tokens(prompt_tokens + completion_tokens)
+                .build(),
+        ))
         .build();
 
+    let data = format!(
+        " {}",
+        serde_json::to_string(&resp).map_err(ProxyError::DeserializeError)?
+    );
+    Ok(Event::default().data(data))
+}
+
+/// Role is only sent with the first chunk
+fn take_role<'a>(context: &mut HandlerContext<'_>, role: &'a Role) -> Option<&'a Role> {
+    if context.set_role {
+        context.set_role = false;
+        Some(role)
+    } else {
+        None
+    }
+}
+
+fn chunk_event(
+    context: &HandlerContext<'_>,
+    delta: model::Delta<'_>,
+    finish_reason: Option<&str>,
+) -> ProxyResult<Event> {
     let resp = model::Resp::builder()
         .id(context.id)
         .object("chat.completion.chunk")
         .created(context.timestamp)
         .model(context.model)
         .choices(vec![model::Choice::builder()
-            .index(0)
+            .index(context.index)
             .delta(Some(delta))
             .finish_reason(finish_reason)
             .build()])
@@ -163,16 +439,30 @@ async fn event_convert_handler(
     Ok(Event::default().data(data))
 }
 
-pub(super) async fn not_stream_handler(
-    mut event_soure: EventStream<
-        impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + std::marker::Unpin,
-    >,
-    model: String,
-) -> ProxyResult<Json<Value>> {
-    let id = super::generate_id(29);
-    let timestamp = super::current_timestamp()?;
-    let mut previous_message = String::new();
-    let mut finish_reason = None;
+/// Reply of a choice collected from the upstream
+struct Reply {
+    text: String,
+    finish_reason: Option<&'static str>,
+    conversation_id: String,
+    message_id: String,
+    // The text was cut by a client side limit
+    truncated: bool,
+}
+
+async fn collect_reply(
+    resp: reqwest::Response,
+    limits: &Limits,
+    tool: Option<&ToolSpec>,
+) -> ProxyResult<Reply> {
+    let mut event_soure = resp.bytes_stream().eventsource();
+    let mut reply = Reply {
+        text: String::new(),
+        finish_reason: None,
+        conversation_id: String::new(),
+        message_id: String::new(),
+        truncated: false,
+    };
+    let mut tokens = TokenCount::default();
 
     while let Some(event_result) = event_soure.next().await {
         match event_result {
@@ -185,15 +475,22 @@ pub(super) async fn not_stream_handler(
                 // Parse event data
                 if let Ok(res) = serde_json::from_str::<PostConvoResponse>(&event.data) {
                     if let PostConvoResponse::Conversation(convo) = res {
-                        let finish = convo.metadata_finish_details_type();
-                        if !finish.is_empty() {
-                            finish_reason = Some(finish.to_owned())
+                        if let Some(finish_reason) = finish_reason(&convo) {
+                            reply.finish_reason = Some(finish_reason)
+                        }
+
+                        // Remember the conversation of the assistant reply
+                        if convo.role().eq(&Role::Assistant) {
+                            reply.conversation_id.clear();
+                            reply.conversation_id.push_str(convo.conversation_id());
+                            reply.message_id.clear();
+                            reply.message_id.push_str(convo.message_id());
                         }
 
                         // If message is not empty, set previous message
                         if let Some(message) = convo.messages().first() {
-                            previous_message.clear();
-                            previous_message.push_str(message);
+                            reply.text.clear();
+                            reply.text.push_str(message);
                         }
 
                         drop(convo)
@@ -206,30 +503,101 @@ pub(super) async fn not_stream_handler(
                 return Err(ProxyError::EventSourceStreamError(err));
             }
         }
+
+        // Tool calls are never cut
+        if tool.is_some() && tool::probe(&reply.text).ne(&Probe::Text) {
+            continue;
+        }
+
+        // Dropping the event source cancels the upstream request
+        let (text, limit) = limits.apply(&reply.text, true, &mut tokens);
+        if limit.is_some() {
+            reply.text = text.to_owned();
+            reply.finish_reason = limit;
+            reply.truncated = true;
+            break;
+        }
     }
 
     drop(event_soure);
+    Ok(reply)
+}
 
-    let message = model::Message::builder()
-        .role(Role::Assistant)
-        .content(previous_message)
-        .build();
+pub(super) async fn not_stream_handler(
+    resp: reqwest::Response,
+    config: Context,
+) -> Result<Json<Value>, ResponseError> {
+    let id = super::generate_id(29);
+    let timestamp = super::current_timestamp()?;
+    let Context {
+        model,
+        tool,
+        prompt_tokens,
+        conversation,
+        limits,
+        n,
+        upstream,
+        ..
+    } = config;
+
+    let mut first = Some(resp);
+    let mut conversation_id = String::new();
+    let mut completion_tokens = 0;
+    let mut choices = Vec::with_capacity(n);
+
+    for index in 0..n {
+        let (resp, _in_flight) =
+            next_choice(&mut first, upstream.as_ref(), &conversation_id).await?;
+        let reply = collect_reply(resp, &limits, tool.as_ref()).await?;
+        if conversation_id.is_empty() {
+            conversation_id.push_str(&reply.conversation_id);
+        }
+
+        if let Some(digest) = conversation.clone().filter(|_| !reply.truncated) {
+            reuse::remember(
+                digest,
+                tool.as_ref(),
+                &reply.text,
+                &reply.conversation_id,
+                &reply.message_id,
+            );
+        }
+
+        completion_tokens += super::count_completion_tokens(&reply.text);
+        let tool_calls = tool.as_ref().and_then(|t| t.parse(&reply.text));
+        let (message, finish_reason) = match (tool.as_ref(), tool_calls) {
+            (Some(tool), Some(tool_calls)) => {
+                (tool.message(tool_calls), Some(tool.finish_reason()))
+            }
+            _ => (
+                model::Message::builder()
+                    .role(Role::Assistant)
+                    .content(Some(reply.text.into()))
+                    .build(),
+                reply.finish_reason.or(Some("stop")),
+            ),
+        };
+
+        choices.push(
+            model::Choice::builder()
+                .index(index as i64)
+                .message(Some(message))
+                .finish_reason(finish_reason)
+                .build(),
+        );
+    }
 
     let resp = model::Resp::builder()
         .id(&id)
         .object("chat.completion.chunk")
         .created(&timestamp)
         .model(&model)
-        .choices(vec![model::Choice::builder()
-            .index(0)
-            .message(Some(message))
-            .finish_reason(finish_reason.as_deref())
-            .build()])
+        .choices(choices)
         .usage(Some(
             model::Usage::builder()
-                .prompt_tokens(0)
-                .completion_tokens(0)
-                .total_tokens(0)
+                .prompt_tokens(prompt_tokens)
+                .completion_tokens(completion_tokens)
+                .total_tokens(prompt_tokens + completion_tokens)
                 .build(),
         ))
         .build();
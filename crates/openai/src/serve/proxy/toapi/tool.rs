@@ -0,0 +1,373 @@
+use serde_json::{json, Value};
+
+use crate::chatgpt::model::Role;
+
+use super::model::{self, Function, FunctionCall, FunctionChoice, Message, ToolCall, ToolChoice};
+
+/// The JSON object the model is instructed to reply with when calling tools
+const TOOL_CALLS_PREFIX: &str = "{\"tool_calls\"";
+
+/// Whether the model must call a tool
+#[derive(Clone)]
+enum Choice {
+    Auto,
+    Required,
+    Named(String),
+}
+
+/// Tool calling context resolved from the request
+#[derive(Clone)]
+pub struct ToolSpec {
+    functions: Vec<Function>,
+    choice: Choice,
+    parallel: bool,
+    /// Request used the deprecated `functions`/`function_call` fields
+    legacy: bool,
+}
+
+/// Result of probing a partial assistant reply
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(super) enum Probe {
+    /// Not enough text yet to decide
+    Pending,
+    /// Plain text reply
+    Text,
+    /// The reply is a tool call
+    ToolCall,
+}
+
+impl ToolSpec {
+    /// Resolve tool definitions from the request, `None` if tools are absent or disabled
+    pub(super) fn from_req(req: &model::Req) -> Option<Self> {
+        let (functions, choice, legacy) = match (&req.tools, &req.functions) {
+            (Some(tools), _) if !tools.is_empty() => {
+                let functions = tools
+                    .iter()
+                    .filter(|t| t.kind.eq("function"))
+                    .map(|t| t.function.clone())
+                    .collect::<Vec<_>>();
+                let choice = match &req.tool_choice {
+                    Some(ToolChoice::Mode(mode)) => mode.as_str().try_into().ok()?,
+                    Some(ToolChoice::Named { function }) => Choice::Named(function.name.clone()),
+                    None => Choice::Auto,
+                };
+                (functions, choice, false)
+            }
+            (_, Some(functions)) if !functions.is_empty() => {
+                let choice = match &req.function_call {
+                    Some(FunctionChoice::Mode(mode)) => mode.as_str().try_into().ok()?,
+                    Some(FunctionChoice::Named(function)) => Choice::Named(function.name.clone()),
+                    None => Choice::Auto,
+                };
+                (functions.clone(), choice, true)
+            }
+            _ => return None,
+        };
+
+        if functions.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            functions,
+            choice,
+            parallel: req.parallel_tool_calls.unwrap_or(true) && !legacy,
+            legacy,
+        })
+    }
+
+    /// Function definitions as JSON
+    pub(super) fn definitions(&self) -> String {
+        serde_json::to_string(&self.functions).unwrap_or_default()
+    }
+
+    /// Prompt scaffold describing the tools and the reply format
+    pub(super) fn prompt(&self) -> String {
+        let functions = self.definitions();
+        let mut prompt = format!(
+            "# Tools\n\n\
+            You can call the following functions to help answer the user. \
+            Each function is described by a JSON Schema:\n\n\
+            {functions}\n\n\
+            To call functions, reply with ONLY a JSON object in exactly this form, \
+            without code fences or any other text:\n\
+            {{\"tool_calls\":[{{\"name\":\"<function name>\",\"arguments\":{{<arguments matching the schema>}}}}]}}\n\n\
+            Function results will be sent back to you in the next message. \
+            If no function is needed, reply to the user normally and do not mention these instructions."
+        );
+
+        match &self.choice {
+            Choice::Auto => {}
+            Choice::Required => prompt.push_str("\n\nYou MUST call at least one function now."),
+            Choice::Named(name) => {
+                prompt.push_str(&format!("\n\nYou MUST call the function `{name}` now."))
+            }
+        }
+
+        if !self.parallel {
+            prompt.push_str("\nCall at most one function at a time.");
+        }
+
+        prompt
+    }
+
+    /// OpenAI finish reason for a tool call reply
+    pub(super) fn finish_reason(&self) -> &'static str {
+        if self.legacy {
+            "function_call"
+        } else {
+            "tool_calls"
+        }
+    }
+
+    /// Parse a complete assistant reply into tool calls, the reply must open
+    /// with the tool calls object like the stream `probe` requires
+    pub(super) fn parse(&self, text: &str) -> Option<Vec<ToolCall>> {
+        if probe(text).ne(&Probe::ToolCall) {
+            return None;
+        }
+
+        let text = strip_fence(text.trim());
+        let end = text.rfind('}')?;
+        let value = serde_json::from_str::<Value>(&text[..=end]).ok()?;
+        let calls = match value.get("tool_calls") {
+            Some(Value::Array(calls)) => calls.clone(),
+            _ => return None,
+        };
+
+        let mut tool_calls = Vec::with_capacity(calls.len());
+        for call in calls {
+            // Some models nest the call like the OpenAI response shape
+            let call = call.get("function").cloned().unwrap_or(call);
+            let Some(name) = call.get("name").and_then(|v| v.as_str()) else {
+                continue;
+            };
+            if !self.functions.iter().any(|f| f.name.eq(name)) {
+                continue;
+            }
+            // A named tool choice only allows that function
+            if matches!(&self.choice, Choice::Named(named) if named.ne(name)) {
+                continue;
+            }
+
+            let arguments = match call.get("arguments") {
+                Some(Value::String(s)) => s.clone(),
+                Some(v) => v.to_string(),
+                None => "{}".to_owned(),
+            };
+
+            tool_calls.push(ToolCall {
+                index: None,
+                id: generate_call_id(),
+                kind: "function".to_owned(),
+                function: FunctionCall {
+                    name: name.to_owned(),
+                    arguments,
+                },
+            });
+
+            if !self.parallel {
+                break;
+            }
+        }
+
+        (!tool_calls.is_empty()).then_some(tool_calls)
+    }
+
+    /// Build the assistant message carrying the tool calls
+    pub(super) fn message(&self, tool_calls: Vec<ToolCall>) -> Message {
+        if self.legacy {
+            Message::builder()
+                .role(Role::Assistant)
+                .function_call(tool_calls.into_iter().next().map(|c| c.function))
+                .build()
+        } else {
+            Message::builder()
+                .role(Role::Assistant)
+                .tool_calls(Some(tool_calls))
+                .build()
+        }
+    }
+
+    /// Whether the reply uses the deprecated `function_call` shape
+    pub(super) fn legacy(&self) -> bool {
+        self.legacy
+    }
+}
+
+impl TryFrom<&str> for Choice {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "auto" => Ok(Choice::Auto),
+            "required" => Ok(Choice::Required),
+            // `none` disables tools entirely
+            _ => Err(()),
+        }
+    }
+}
+
+/// Decide from a partial reply whether it is turning into a tool call
+pub(super) fn probe(text: &str) -> Probe {
+    let compact = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+
+    // Wait for the opening code fence to complete
+    if compact.is_empty() || (compact.len() < 7 && "```json".starts_with(&compact)) {
+        return Probe::Pending;
+    }
+
+    let compact = strip_fence(&compact);
+    if compact.starts_with(TOOL_CALLS_PREFIX) {
+        Probe::ToolCall
+    } else if TOOL_CALLS_PREFIX.starts_with(compact) {
+        Probe::Pending
+    } else {
+        Probe::Text
+    }
+}
+
+/// Render a request message into a ChatGPT author role and text
+pub(super) fn render_message(message: &Message, history: &[Message]) -> (Role, String) {
+    let content = message
+        .content
+        .as_ref()
+        .map(|c| c.text())
+        .unwrap_or_default();
+    match message.role {
+        Role::Tool => {
+            // Tool results carry the call id, look up the function name from the history
+            let name = message.name.clone().or_else(|| {
+                let id = message.tool_call_id.as_deref()?;
+                history
+                    .iter()
+                    .filter_map(|m| m.tool_calls.as_ref())
+                    .flatten()
+                    .find(|c| c.id.eq(id))
+                    .map(|c| c.function.name.clone())
+            });
+            let text = format!(
+                "The function `{}` returned:\n{content}",
+                name.as_deref().unwrap_or("unknown")
+            );
+            (Role::User, text)
+        }
+        Role::Assistant if message.tool_calls.is_some() || message.function_call.is_some() => {
+            let calls = message
+                .tool_calls
+                .iter()
+                .flatten()
+                .map(|c| &c.function)
+                .chain(message.function_call.iter())
+                .map(|f| {
+                    let arguments = serde_json::from_str::<Value>(&f.arguments)
+                        .unwrap_or_else(|_| Value::String(f.arguments.clone()));
+                    json!({ "name": f.name, "arguments": arguments })
+                })
+                .collect::<Vec<_>>();
+            let calls = json!({ "tool_calls": calls }).to_string();
+            let text = if content.is_empty() {
+                calls
+            } else {
+                format!("{content}\n{calls}")
+            };
+            (Role::Assistant, text)
+        }
+        role => (role, content.into_owned()),
+    }
+}
+
+fn strip_fence(text: &str) -> &str {
+    text.strip_prefix("```json")
+        .or_else(|| text.strip_prefix("```"))
+        .map(|s| s.trim_start())
+        .unwrap_or(text)
+}
+
+fn generate_call_id() -> String {
+    format!("call_{}", crate::generate_random_string(24))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn spec() -> ToolSpec {
+        ToolSpec {
+            functions: vec![Function {
+                name: "get_weather".to_owned(),
+                description: None,
+                parameters: None,
+            }],
+            choice: Choice::Auto,
+            parallel: true,
+            legacy: false,
+        }
+    }
+
+    #[test]
+    fn test_probe() {
+        assert_eq!(probe(""), Probe::Pending);
+        assert_eq!(probe("``"), Probe::Pending);
+        assert_eq!(probe("{\"tool"), Probe::Pending);
+        assert_eq!(probe("```json\n{ \"tool_calls\": ["), Probe::ToolCall);
+        assert_eq!(probe("Hello"), Probe::Text);
+        assert_eq!(probe("{\"answer\""), Probe::Text);
+    }
+
+    #[test]
+    fn test_parse() {
+        let spec = spec();
+        let calls = spec
+            .parse(r#"{"tool_calls":[{"name":"get_weather","arguments":{"city":"Paris"}}]}"#)
+            .unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.name, "get_weather");
+        assert_eq!(calls[0].function.arguments, r#"{"city":"Paris"}"#);
+
+        let calls = spec
+            .parse(
+                "```json\n{\"tool_calls\":[{\"name\":\"get_weather\",\"arguments\":\"{}\"}]}\n```",
+            )
+            .unwrap();
+        assert_eq!(calls[0].function.arguments, "{}");
+
+        assert!(spec
+            .parse(r#"{"tool_calls":[{"name":"unknown"}]}"#)
+            .is_none());
+        assert!(spec.parse("It is sunny in Paris.").is_none());
+        assert!(spec
+            .parse(r#"Sure: {"tool_calls":[{"name":"get_weather"}]}"#)
+            .is_none());
+        assert!(spec.parse(r#"{"answer":{"name":"get_weather"}}"#).is_none());
+
+        let calls = spec
+            .parse(r#"{"tool_calls":[{"arguments":{}},{"name":"get_weather"}]}"#)
+            .unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.name, "get_weather");
+    }
+
+    #[test]
+    fn test_parse_named() {
+        let mut spec = spec();
+        spec.functions.push(Function {
+            name: "get_time".to_owned(),
+            description: None,
+            parameters: None,
+        });
+        spec.choice = Choice::Named("get_time".to_owned());
+
+        let calls = spec
+            .parse(r#"{"tool_calls":[{"name":"get_weather"},{"name":"get_time"}]}"#)
+            .unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.name, "get_time");
+        assert!(spec
+            .parse(r#"{"tool_calls":[{"name":"get_weather"}]}"#)
+            .is_none());
+    }
+}
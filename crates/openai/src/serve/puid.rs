@@ -1,6 +1,12 @@
 use super::error::{ProxyError, ResponseError};
-use crate::{gpt_model::GPTModel, with_context, URL_CHATGPT_API};
+use crate::{
+    client::{ClientRoundRobinBalancer, Lease},
+    gpt_model::GPTModel,
+    metrics, URL_CHATGPT_API,
+};
+use base64::{engine::general_purpose, Engine};
 use moka::sync::Cache;
+use sha2::{Digest, Sha256};
 use std::str::FromStr;
 use tokio::sync::OnceCell;
 
@@ -13,6 +19,20 @@ pub(super) fn reduce_key(token: &str) -> Result<String, ResponseError> {
     Ok(token_profile.email().to_owned())
 }
 
+/// Client affinity identity of a token, the email of an access token or the hash of any other token
+pub(super) fn token_identity(token: &str) -> String {
+    match crate::token::check(token) {
+        Ok(Some(profile)) => profile.email().to_owned(),
+        _ => key_hash(token),
+    }
+}
+
+/// Client affinity identity of a ninja issued api key or a token
+pub(super) fn key_hash(key: &str) -> String {
+    let hash = Sha256::digest(key.as_bytes());
+    format!("key:{}", general_purpose::URL_SAFE_NO_PAD.encode(hash))
+}
+
 async fn cache() -> &'static Cache<String, String> {
     PUID_CACHE
         .get_or_init(|| async {
@@ -27,16 +47,20 @@ pub(super) async fn get_or_init(
     token: &str,
     model: &str,
     cache_id: String,
+    pool: &ClientRoundRobinBalancer,
 ) -> Result<Option<String>, ResponseError> {
     let token = token.trim_start_matches("Bearer ");
     let puid_cache = cache().await;
 
     if let Some(p) = puid_cache.get(&cache_id) {
+        metrics::puid_cache(true);
         return Ok(Some(p.clone()));
     }
 
     if GPTModel::from_str(model)?.is_gpt4() {
-        let resp = with_context!(api_client)
+        metrics::puid_cache(false);
+        let client: Lease<reqwest::Client> = pool.next_for(Some(cache_id.as_str())).into();
+        let resp = client
             .get(format!("{URL_CHATGPT_API}/backend-api/models"))
             .bearer_auth(token)
             .send()
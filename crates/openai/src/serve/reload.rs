@@ -0,0 +1,233 @@
+use hotwatch::{Event, EventKind, Hotwatch};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use super::health::config_view;
+use super::middleware::limitkey::Limiter;
+use super::middleware::tokenbucket::{Strategy, TokenBucketProvider};
+use crate::context::args::Args;
+use crate::context::reload::Reloadable;
+use crate::{info, warn, with_context};
+
+/// Read the serve config from the config file
+pub type ConfigLoader = fn(&Path) -> anyhow::Result<Args>;
+
+/// Effective config, the reloadable settings follow the config file
+static CONFIG: OnceLock<Reloadable<Args>> = OnceLock::new();
+
+static RELOADER: OnceLock<Reloader> = OnceLock::new();
+
+/// Token bucket limiter of the requests, swapped on reload
+static LIMITER: OnceLock<Arc<Reloadable<Limiter>>> = OnceLock::new();
+
+static NULL: Value = Value::Null;
+
+/// Editors write a file with a burst of events, the reload runs once the burst settles
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+struct Reloader {
+    path: PathBuf,
+    loader: ConfigLoader,
+    limiter: Arc<Reloadable<Limiter>>,
+    /// Reloads of the watcher, SIGHUP and the admin endpoint run one at a time
+    lock: Mutex<()>,
+    _hotwatch: Option<Hotwatch>,
+}
+
+/// Changed settings of a reload
+#[derive(Serialize, Default, Debug)]
+pub struct ReloadReport {
+    /// Settings swapped in place
+    pub reloaded: Vec<String>,
+    /// Settings that only take effect after a restart
+    pub restart_required: Vec<String>,
+}
+
+impl ReloadReport {
+    fn new(current: &Args, new: &Args, reloaded: &Args) -> Self {
+        let mut report = Self::default();
+        report.compare(
+            String::new(),
+            &config_view(current, false),
+            &config_view(new, false),
+            &config_view(reloaded, false),
+        );
+        report
+    }
+
+    fn compare(&mut self, name: String, current: &Value, new: &Value, reloaded: &Value) {
+        match (current, new) {
+            (Value::Object(current), Value::Object(new)) => {
+                for (key, value) in new {
+                    let name = match name.is_empty() {
+                        true => key.to_owned(),
+                        false => format!("{name}.{key}"),
+                    };
+                    self.compare(
+                        name,
+                        current.get(key).unwrap_or(&NULL),
+                        value,
+                        reloaded.get(key).unwrap_or(&NULL),
+                    );
+                }
+            }
+            _ if current == new => {}
+            _ if reloaded == new => self.reloaded.push(name),
+            _ => self.restart_required.push(name),
+        }
+    }
+}
+
+/// Keep the startup config, and watch the config file if the server was started with one
+pub(super) fn init(
+    args: Args,
+    config: Option<(PathBuf, ConfigLoader)>,
+    limiter: Arc<Reloadable<Limiter>>,
+) {
+    let _ = CONFIG.set(Reloadable::new(args));
+    let _ = LIMITER.set(limiter.clone());
+    if let Some((path, loader)) = config {
+        let _ = RELOADER.set(Reloader {
+            _hotwatch: watch(&path),
+            path,
+            loader,
+            limiter,
+            lock: Mutex::new(()),
+        });
+    }
+}
+
+/// Current effective config
+pub(super) fn current() -> Arc<Args> {
+    CONFIG.get().map(Reloadable::load).unwrap_or_default()
+}
+
+/// Reload the config file, the reloadable settings are swapped together or not at all
+pub(super) fn reload() -> anyhow::Result<ReloadReport> {
+    let reloader = RELOADER
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("The server was not started with a config file"))?;
+    let _lock = reloader.lock.lock().expect("reload lock poisoned");
+
+    let new = (reloader.loader)(&reloader.path)?;
+    let current = current();
+    let args = current.reloaded(&new);
+
+    let limiter = limiter(&args, Some((&current, &reloader.limiter.load())))?;
+    with_context!().reload(&args)?;
+    reloader.limiter.store(limiter);
+
+    let report = ReloadReport::new(&current, &new, &args);
+    if let Some(config) = CONFIG.get() {
+        config.store(args);
+    }
+    Ok(report)
+}
+
+/// Reload the config file and log the changed settings
+pub(super) fn reload_and_log(trigger: &str) {
+    match reload() {
+        Ok(report) => {
+            info!("Config reloaded ({trigger}): {:?}", report.reloaded);
+            if !report.restart_required.is_empty() {
+                warn!(
+                    "Config changes not applied until restart: {:?}",
+                    report.restart_required
+                );
+            }
+        }
+        Err(err) => warn!("Config reload ({trigger}) failed: {err}"),
+    }
+}
+
+/// Token bucket limiter of the config, the buckets of the current limiter are kept if their settings are unchanged
+pub(super) fn limiter(args: &Args, current: Option<(&Args, &Limiter)>) -> anyhow::Result<Limiter> {
+    let bucket = match current {
+        Some((current, limiter)) if bucket_settings(current) == bucket_settings(args) => {
+            limiter.bucket()
+        }
+        _ => Arc::new(TokenBucketProvider::try_from((
+            Strategy::from_str(args.tb_strategy.as_str())?,
+            args.tb_enable,
+            args.tb_capacity,
+            args.tb_fill_rate,
+            args.tb_expired,
+            args.tb_redis_url.as_deref(),
+        ))?),
+    };
+    Ok(Limiter::new(
+        bucket,
+        &args.tb_keys,
+        &args.tb_routes,
+        args.tb_trusted_proxies.clone(),
+    )?
+    .with_egress(args.tb_egress))
+}
+
+/// Current token bucket limiter
+pub(super) fn current_limiter() -> Option<Arc<Limiter>> {
+    LIMITER.get().map(|limiter| limiter.load())
+}
+
+fn bucket_settings(args: &Args) -> (&str, bool, u32, u32, u32, Option<&str>) {
+    (
+        args.tb_strategy.as_str(),
+        args.tb_enable,
+        args.tb_capacity,
+        args.tb_fill_rate,
+        args.tb_expired,
+        args.tb_redis_url.as_deref(),
+    )
+}
+
+/// Watch the directory of the config file, editors often replace the file instead of writing it
+fn watch(path: &Path) -> Option<Hotwatch> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = path.file_name()?.to_owned();
+
+    let mut hotwatch = match Hotwatch::new() {
+        Ok(hotwatch) => hotwatch,
+        Err(err) => {
+            warn!("Config file watcher failed to initialize: {err:?}");
+            return None;
+        }
+    };
+    // Each event bumps the generation, only the last event of a burst reloads
+    let generation = Arc::new(AtomicU64::new(0));
+    let result = hotwatch.watch(dir, move |event: Event| match event.kind {
+        EventKind::Create(_) | EventKind::Modify(_)
+            if event
+                .paths
+                .iter()
+                .any(|p| p.file_name() == Some(file_name.as_os_str())) =>
+        {
+            let current = generation.fetch_add(1, Ordering::SeqCst) + 1;
+            let generation = generation.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(DEBOUNCE);
+                if generation.load(Ordering::SeqCst) == current {
+                    reload_and_log("config file changed")
+                }
+            });
+        }
+        _ => {}
+    });
+    match result {
+        Ok(_) => {
+            info!("Start watching config file: {}", path.display());
+            Some(hotwatch)
+        }
+        Err(err) => {
+            warn!("Config file watch error: {err:?}");
+            None
+        }
+    }
+}
@@ -33,6 +33,7 @@ use std::sync::OnceLock;
 use tower::ServiceBuilder;
 use tower_http::ServiceBuilderExt;
 
+use crate::auth::{OPENAI_OAUTH_TOKEN_URL, OPENAI_OAUTH_URL};
 use crate::constant::ARKOSE_ENDPOINT;
 use crate::constant::AUTH_KEY;
 use crate::constant::CSRF_TOKEN;
@@ -47,6 +48,7 @@ use crate::serve::error::ProxyError;
 use crate::serve::error::ResponseError;
 use crate::serve::middleware::csrf;
 use crate::serve::proxy::header_convert;
+use crate::serve::puid::token_identity;
 use crate::serve::turnstile;
 use crate::serve::whitelist;
 use crate::with_context;
@@ -198,7 +200,10 @@ async fn login(
         return Ok(err.into_response());
     };
 
-    match with_context!(auth_client).do_access_token(&account).await {
+    match with_context!(auth_client_for, OPENAI_OAUTH_URL, &account.username)
+        .do_access_token(&account)
+        .await
+    {
         Ok(access_token) => {
             // Build session
             let session = Session::from(
@@ -250,10 +255,14 @@ async fn login_token(
         }
         // Refresh token
         s if s.len() > 40 && s.len() < 100 => {
-            let refresh_token = with_context!(auth_client)
-                .do_refresh_token(access_token)
-                .await
-                .map_err(ResponseError::BadRequest)?;
+            let refresh_token = with_context!(
+                auth_client_for,
+                OPENAI_OAUTH_TOKEN_URL,
+                &token_identity(access_token)
+            )
+            .do_refresh_token(access_token)
+            .await
+            .map_err(ResponseError::BadRequest)?;
             let authentication_token =
                 Token::try_from(refresh_token).map_err(ResponseError::InternalServerError)?;
             Session::from(authentication_token)
@@ -264,10 +273,14 @@ async fn login_token(
             if bearer.token().is_empty() {
                 return Err(ResponseError::TempporaryRedirect(LOGIN_INDEX));
             }
-            let access_token = with_context!(auth_client)
-                .refresh_session(access_token)
-                .await
-                .map_err(ResponseError::BadRequest)?;
+            let access_token = with_context!(
+                auth_client_for,
+                URL_CHATGPT_API,
+                &token_identity(access_token)
+            )
+            .refresh_session(access_token)
+            .await
+            .map_err(ResponseError::BadRequest)?;
             let authentication_token =
                 Token::try_from(access_token).map_err(ResponseError::InternalServerError)?;
             Session::from(authentication_token)
@@ -321,7 +334,7 @@ async fn logout() -> Result<Response<Body>, ResponseError> {
 async fn session(mut s: SessionExt) -> Result<Response<Body>, ResponseError> {
     // Refresh session
     let new_session = if let Some(session_token) = s.session_token.as_ref() {
-        match with_context!(auth_client)
+        match with_context!(auth_client_for, URL_CHATGPT_API, &s.session.email)
             .refresh_session(session_token)
             .await
         {
@@ -329,7 +342,7 @@ async fn session(mut s: SessionExt) -> Result<Response<Body>, ResponseError> {
             Err(_) => None,
         }
     } else if let Some(refresh_token) = s.session.refresh_token.as_ref() {
-        match with_context!(auth_client)
+        match with_context!(auth_client_for, OPENAI_OAUTH_TOKEN_URL, &s.session.email)
             .do_refresh_token(&refresh_token)
             .await
         {
@@ -380,8 +393,14 @@ fn create_response_from_session(s: Session) -> Result<Response<Body>, ResponseEr
 
 /// Get auth me
 async fn auth_me(headers: HeaderMap, jar: CookieJar) -> Result<impl IntoResponse, ResponseError> {
-    let resp = with_context!(api_client)
-        .get(format!("{URL_CHATGPT_API}/backend-api/me"))
+    let email = jar
+        .get(SESSION_ID)
+        .and_then(|cookie| cookie.value().parse::<Session>().ok())
+        .map(|session| session.email);
+    let url = format!("{URL_CHATGPT_API}/backend-api/me");
+    let client = with_context!(api_client_for, &url, email.as_deref());
+    let resp = client
+        .get(url)
         .headers(header_convert(&headers, &jar, URL_CHATGPT_API)?)
         .send()
         .await
@@ -448,8 +467,10 @@ async fn share_chat(
     extract: SessionExt,
 ) -> Result<Response<Body>, ResponseError> {
     let share_id = share_id.0;
-    let resp = with_context!(api_client)
-        .get(format!("{URL_CHATGPT_API}/backend-api/share/{share_id}"))
+    let url = format!("{URL_CHATGPT_API}/backend-api/share/{share_id}");
+    let client = with_context!(api_client_for, &url, Some(extract.session.email.as_str()));
+    let resp = client
+        .get(url)
         .headers(header_convert(
             &extract.headers,
             &extract.jar,
@@ -493,8 +514,10 @@ async fn share_chat_info(
     extract: SessionExt,
 ) -> Result<Response<Body>, ResponseError> {
     let share_id = share_id.0.replace(".json", EMPTY);
-    let resp = with_context!(api_client)
-        .get(format!("{URL_CHATGPT_API}/backend-api/share/{share_id}"))
+    let url = format!("{URL_CHATGPT_API}/backend-api/share/{share_id}");
+    let client = with_context!(api_client_for, &url, Some(extract.session.email.as_str()));
+    let resp = client
+        .get(url)
         .headers(header_convert(
             &extract.headers,
             &extract.jar,
@@ -547,11 +570,10 @@ async fn share_chat_continue_info(
     share_id: Path<String>,
     s: SessionExt,
 ) -> Result<Response<Body>, ResponseError> {
-    let resp = with_context!(api_client)
-        .get(format!(
-            "{URL_CHATGPT_API}/backend-api/share/{}",
-            share_id.0
-        ))
+    let url = format!("{URL_CHATGPT_API}/backend-api/share/{}", share_id.0);
+    let client = with_context!(api_client_for, &url, Some(s.session.email.as_str()));
+    let resp = client
+        .get(url)
         .headers(header_convert(&s.headers, &s.jar, URL_CHATGPT_API)?)
         .send()
         .await
@@ -22,7 +22,7 @@ pub(super) fn config(router: Router, args: &Args) -> Router {
 async fn proxy(mut req: RequestExt) -> Result<impl IntoResponse, ResponseError> {
     req.trim_start_path("/files")?;
     req.append_haeder(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")?;
-    let resp = with_context!(api_client)
+    let resp = with_context!(api_client_pool)
         .send_request("https://files.oaiusercontent.com", req)
         .await?;
     response_convert(resp).await
@@ -100,7 +100,7 @@ async fn post_login(
     password: Option<Form<AuthenticateKey>>,
 ) -> Result<impl IntoResponse, ResponseError> {
     if let Some(upload_key) = with_context!(auth_key) {
-        if password.as_ref().map(|p| p.0.password.as_ref()) == Some(upload_key) {
+        if password.as_ref().map(|p| p.0.password.as_ref()) == Some(upload_key.as_str()) {
             return Ok(generate_success_response().await.into_response());
         }
     } else {
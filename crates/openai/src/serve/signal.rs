@@ -1,3 +1,5 @@
+#[cfg(target_family = "unix")]
+use super::reload;
 use crate::info;
 use axum_server::Handle;
 use std::time::Duration;
@@ -20,23 +22,30 @@ pub(super) async fn graceful_shutdown(handle: Handle) {
         let mut sigquit = signal(SignalKind::quit()).expect("SIGQUIT signal hanlde error");
         let mut sigchld = signal(SignalKind::child()).expect("SIGCHLD signal hanlde error");
         let mut sighup = signal(SignalKind::hangup()).expect("SIGHUP signal hanlde error");
-        tokio::select! {
-            _ = sigterm.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGTERM").await;
-            },
-            _ = sigquit.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGQUIT").await;
-            },
-            _ = sigchld.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGCHLD").await;
-            },
-            _ = sighup.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGHUP").await;
-            },
-            _ = tokio::signal::ctrl_c() => {
-                sending_graceful_shutdown_signal(handle, "SIGINT").await;
-            }
-        };
+        loop {
+            tokio::select! {
+                _ = sigterm.recv() => {
+                    sending_graceful_shutdown_signal(handle, "SIGTERM").await;
+                    break;
+                },
+                _ = sigquit.recv() => {
+                    sending_graceful_shutdown_signal(handle, "SIGQUIT").await;
+                    break;
+                },
+                _ = sigchld.recv() => {
+                    sending_graceful_shutdown_signal(handle, "SIGCHLD").await;
+                    break;
+                },
+                _ = sighup.recv() => {
+                    // SIGHUP reloads the config file, the reload blocks on file IO and the reload lock
+                    tokio::task::spawn_blocking(|| reload::reload_and_log("SIGHUP"));
+                },
+                _ = tokio::signal::ctrl_c() => {
+                    sending_graceful_shutdown_signal(handle, "SIGINT").await;
+                    break;
+                }
+            };
+        }
     }
 }
 
@@ -0,0 +1,222 @@
+//! Byte pair encoding tokenizer compatible with OpenAI `cl100k_base`,
+//! the encoding used by `gpt-3.5-turbo`, `gpt-4` and `text-embedding-ada-002`.
+
+use base64::{engine::general_purpose, Engine};
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+const CL100K_BASE: &str = include_str!("cl100k_base.tiktoken");
+
+/// The `\s+(?!\S)` branch of the original pattern is emulated in `split`,
+/// because the regex crate does not support look-around.
+const CL100K_BASE_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+";
+
+static CL100K: OnceLock<CoreBPE> = OnceLock::new();
+
+/// Get the `cl100k_base` tokenizer, ranks are loaded on first use
+pub fn cl100k_base() -> &'static CoreBPE {
+    CL100K.get_or_init(|| {
+        CoreBPE::new(CL100K_BASE, CL100K_BASE_PATTERN).expect("Failed to load cl100k_base ranks")
+    })
+}
+
+pub struct CoreBPE {
+    encoder: HashMap<Vec<u8>, u32>,
+    decoder: HashMap<u32, Vec<u8>>,
+    regex: Regex,
+}
+
+impl CoreBPE {
+    /// Load ranks in the tiktoken format, one `base64(token) rank` pair per line
+    fn new(ranks: &str, pattern: &str) -> anyhow::Result<Self> {
+        let mut encoder = HashMap::with_capacity(100_256);
+        for line in ranks.lines().filter(|l| !l.is_empty()) {
+            let (token, rank) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow::anyhow!("Invalid rank line: {line}"))?;
+            encoder.insert(general_purpose::STANDARD.decode(token)?, rank.parse()?);
+        }
+
+        let decoder = encoder.iter().map(|(k, v)| (*v, k.clone())).collect();
+
+        Ok(Self {
+            encoder,
+            decoder,
+            regex: Regex::new(pattern)?,
+        })
+    }
+
+    /// Encode text into token ranks
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        let mut tokens = Vec::new();
+        self.split(text)
+            .for_each(|piece| self.encode_piece(piece, &mut tokens));
+        tokens
+    }
+
+    /// Encode the prefix of a text that may still grow, returns its tokens and byte length.
+    /// The last pieces are left out, appended text can merge into them.
+    pub fn encode_stable(&self, text: &str) -> (Vec<u32>, usize) {
+        let pieces = self.split(text).collect::<Vec<_>>();
+        let stable = &pieces[..pieces.len().saturating_sub(2)];
+        let mut tokens = Vec::new();
+        stable
+            .iter()
+            .for_each(|piece| self.encode_piece(piece, &mut tokens));
+        (tokens, stable.iter().map(|piece| piece.len()).sum())
+    }
+
+    /// Count the tokens of text
+    pub fn count(&self, text: &str) -> usize {
+        self.split(text)
+            .map(|piece| match self.encoder.contains_key(piece.as_bytes()) {
+                true => 1,
+                false => self.byte_pair_merge(piece.as_bytes()).len() - 1,
+            })
+            .sum()
+    }
+
+    /// Decode token ranks into bytes, unknown ranks are skipped
+    pub fn decode(&self, tokens: &[u32]) -> Vec<u8> {
+        tokens
+            .iter()
+            .filter_map(|t| self.decoder.get(t))
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// Split text into pieces that are encoded independently
+    fn split<'a>(&'a self, text: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        let mut start = 0;
+        std::iter::from_fn(move || {
+            let m = self.regex.find_at(text, start)?;
+            let mut end = m.end();
+            let piece = m.as_str();
+
+            // `\s+(?!\S)`: a whitespace run followed by a non-space character
+            // leaves its last character to the next piece
+            if piece.chars().all(char::is_whitespace)
+                && !piece.ends_with(['\r', '\n'])
+                && text[end..]
+                    .chars()
+                    .next()
+                    .is_some_and(|c| !c.is_whitespace())
+            {
+                if let Some((last, _)) = piece.char_indices().last().filter(|(i, _)| *i > 0) {
+                    end = m.start() + last;
+                }
+            }
+
+            start = end;
+            Some(&text[m.start()..end])
+        })
+    }
+
+    fn encode_piece(&self, piece: &str, tokens: &mut Vec<u32>) {
+        let piece = piece.as_bytes();
+        match self.encoder.get(piece) {
+            Some(rank) => tokens.push(*rank),
+            None => tokens.extend(self.byte_pair_encode(piece)),
+        }
+    }
+
+    fn byte_pair_encode(&self, piece: &[u8]) -> Vec<u32> {
+        if piece.len() == 1 {
+            return vec![self.encoder[piece]];
+        }
+        self.byte_pair_merge(piece)
+            .windows(2)
+            .map(|part| self.encoder[&piece[part[0].0..part[1].0]])
+            .collect()
+    }
+
+    /// Merge the lowest ranked adjacent pair until no pair is in the vocabulary,
+    /// returns the start offsets of the remaining parts followed by the end offset
+    fn byte_pair_merge(&self, piece: &[u8]) -> Vec<(usize, u32)> {
+        let rank_of = |parts: &[(usize, u32)], i: usize| -> u32 {
+            if i + 3 < parts.len() {
+                self.encoder
+                    .get(&piece[parts[i].0..parts[i + 3].0])
+                    .copied()
+                    .unwrap_or(u32::MAX)
+            } else {
+                u32::MAX
+            }
+        };
+
+        let mut parts = Vec::with_capacity(piece.len() + 1);
+        for i in 0..piece.len() - 1 {
+            let rank = self
+                .encoder
+                .get(&piece[i..i + 2])
+                .copied()
+                .unwrap_or(u32::MAX);
+            parts.push((i, rank));
+        }
+        parts.push((piece.len() - 1, u32::MAX));
+        parts.push((piece.len(), u32::MAX));
+
+        loop {
+            if parts.len() == 1 {
+                break;
+            }
+
+            let (i, rank) = parts[..parts.len() - 1]
+                .iter()
+                .enumerate()
+                .map(|(i, part)| (i, part.1))
+                .min_by_key(|&(_, rank)| rank)
+                .unwrap_or((0, u32::MAX));
+
+            if rank == u32::MAX {
+                break;
+            }
+
+            parts[i].1 = rank_of(&parts, i);
+            if i > 0 {
+                parts[i - 1].1 = rank_of(&parts, i - 1);
+            }
+            parts.remove(i + 1);
+        }
+
+        parts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::cl100k_base;
+
+    #[test]
+    fn test_encode() {
+        let bpe = cl100k_base();
+        assert_eq!(bpe.encode("hello world"), vec![15339, 1917]);
+        assert_eq!(
+            bpe.encode("tiktoken is great!"),
+            vec![83, 1609, 5963, 374, 2294, 0]
+        );
+    }
+
+    #[test]
+    fn test_count_and_decode() {
+        let bpe = cl100k_base();
+        let text = "a   b\n\n  indented\tline 12345 你好，世界!";
+        let tokens = bpe.encode(text);
+        assert_eq!(bpe.count(text), tokens.len());
+        assert_eq!(bpe.decode(&tokens), text.as_bytes());
+    }
+
+    #[test]
+    fn test_encode_stable() {
+        let bpe = cl100k_base();
+        let text = "Hello world, it's 12345 tokens\n\n  indented";
+        for end in (0..=text.len()).filter(|&end| text.is_char_boundary(end)) {
+            let (mut tokens, len) = bpe.encode_stable(&text[..end]);
+            assert!(len <= end);
+            tokens.extend(bpe.encode(&text[len..]));
+            assert_eq!(tokens, bpe.encode(text));
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// How the api key is sent to the upstream backend
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthStyle {
+    /// `Authorization: Bearer <key>`, OpenAI and OpenAI-compatible servers
+    #[default]
+    Bearer,
+    /// `api-key: <key>`, Azure OpenAI
+    ApiKey,
+    /// No auth header
+    None,
+}
+
+/// Upstream backend of the `/v1` API requests of matching models
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Upstream {
+    /// Model name patterns, `*` matches any characters, e.g. `gpt-4*`
+    pub models: Vec<String>,
+    /// Base url replacing `https://api.openai.com/v1`,
+    /// e.g. `https://{resource}.openai.azure.com/openai/deployments/{deployment}`
+    pub base_url: Url,
+    /// Auth header style
+    #[serde(default)]
+    pub auth: AuthStyle,
+    /// Api key of the upstream, required unless the auth style is `none`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    /// `api-version` query parameter, required by Azure OpenAI
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_version: Option<String>,
+}
+
+impl Upstream {
+    /// Check if the upstream serves the model
+    pub fn matches(&self, model: &str) -> bool {
+        self.models.iter().any(|pattern| glob_match(pattern, model))
+    }
+
+    /// Check the upstream config, the caller's bearer token is never forwarded
+    /// so an upstream sending an auth header needs its own api key
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.auth != AuthStyle::None && self.api_key.is_none() {
+            anyhow::bail!(
+                "Upstream {} requires an api_key, or auth = \"none\"",
+                self.base_url
+            )
+        }
+        Ok(())
+    }
+
+    /// Build the upstream url of a `/v1` request path and query
+    pub fn url(&self, path: &str, query: Option<&str>) -> anyhow::Result<Url> {
+        let path = path.strip_prefix("/v1").unwrap_or(path);
+        let mut url = Url::parse(&format!(
+            "{}{path}",
+            self.base_url.as_str().trim_end_matches('/')
+        ))?;
+        url.set_query(query);
+        if let Some(ref api_version) = self.api_version {
+            url.query_pairs_mut()
+                .append_pair("api-version", api_version);
+        }
+        Ok(url)
+    }
+}
+
+/// Match a pattern where `*` matches any characters
+pub(crate) fn glob_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let mut rest = match value.strip_prefix(parts.next().unwrap_or_default()) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let mut parts = parts.collect::<Vec<_>>();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // No wildcard, exact match
+        None => return rest.is_empty(),
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("gpt-4", "gpt-4"));
+        assert!(!glob_match("gpt-4", "gpt-4-32k"));
+        assert!(glob_match("gpt-4*", "gpt-4-32k"));
+        assert!(glob_match("*", "llama-2-70b-chat"));
+        assert!(glob_match("llama-*-chat", "llama-2-70b-chat"));
+        assert!(!glob_match("llama-*-chat", "llama-2-70b"));
+        assert!(glob_match("a*b*a", "aba"));
+        assert!(!glob_match("a*a", "a"));
+    }
+
+    #[test]
+    fn test_url() {
+        let upstream = Upstream {
+            models: vec!["gpt-4*".to_owned()],
+            base_url: Url::parse("https://example.openai.azure.com/openai/deployments/gpt4/")
+                .unwrap(),
+            auth: AuthStyle::ApiKey,
+            api_key: None,
+            api_version: Some("2023-05-15".to_owned()),
+        };
+        assert_eq!(
+            upstream.url("/v1/chat/completions", None).unwrap().as_str(),
+            "https://example.openai.azure.com/openai/deployments/gpt4/chat/completions?api-version=2023-05-15"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut upstream = Upstream {
+            models: vec!["*".to_owned()],
+            base_url: Url::parse("http://127.0.0.1:8000/v1").unwrap(),
+            auth: AuthStyle::Bearer,
+            api_key: None,
+            api_version: None,
+        };
+        assert!(upstream.validate().is_err());
+        upstream.auth = AuthStyle::None;
+        assert!(upstream.validate().is_ok());
+        upstream.auth = AuthStyle::ApiKey;
+        upstream.api_key = Some("key".to_owned());
+        assert!(upstream.validate().is_ok());
+    }
+}
@@ -20,7 +20,7 @@ async fn main() -> anyhow::Result<()> {
     let ctx = openai::context::args::Args::builder()
         .arkose_solver(ArkoseSolver::new(solver, client_key, None, 1))
         .build();
-    openai::context::init(ctx);
+    openai::context::init(ctx)?;
 
     let email = std::env::var("EMAIL")?;
     let password = std::env::var("PASSWORD")?;
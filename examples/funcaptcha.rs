@@ -24,7 +24,7 @@ async fn main() -> anyhow::Result<()> {
             .arkose_solver_tguess_endpoint(Some("https://example.com/tguess".to_owned()))
             .arkose_solver(ArkoseSolver::new(solver, client_key, None, 1))
             .build(),
-    );
+    )?;
 
     let typed = match solver_type.as_str() {
         "auth" => arkose::Type::Auth,
@@ -40,7 +40,7 @@ async fn main() -> anyhow::Result<()> {
 
     let arkose_token = ArkoseToken::new_from_context(
         ArkoseContext::builder()
-            .client(with_context!(arkose_client))
+            .client(with_context!(arkose_client).clone())
             .typed(typed)
             .build(),
     )
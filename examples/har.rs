@@ -7,11 +7,11 @@ use openai::{
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    context::init(Args::builder().build());
+    context::init(Args::builder().build()).expect("Failed to initialize the context");
     for _ in 0..100 {
         match ArkoseToken::new_from_har(
             &mut ArkoseContext::builder()
-                .client(with_context!(arkose_client))
+                .client(with_context!(arkose_client).clone())
                 .typed(Type::GPT4)
                 .build(),
         )
@@ -1,6 +1,6 @@
 use crate::parse;
 use clap::{Args, Subcommand};
-use openai::{arkose::funcaptcha::solver::Solver, proxy};
+use openai::{arkose::funcaptcha::solver::Solver, proxy, upstream};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -113,10 +113,25 @@ pub struct ServeArgs {
     /// Client proxy, support multiple proxy, use ',' to separate, Format: proto|type
     /// Proto: all/api/auth/arkose, default: all
     /// Type: interface/proxy/ipv6 subnet，proxy type only support: socks5/http/https
+    /// An ipv6 subnet is ignored when combined with other proxies of the same client type
     /// e.g. all|socks5://192.168.1.1:1080, api|10.0.0.1, auth|2001:db8::/32, http://192.168.1.1:1081
     #[clap(short = 'x',long, env = "PROXIES", value_parser = parse::parse_proxies_url, verbatim_doc_comment)]
     pub(super) proxies: Option<std::vec::Vec<proxy::Proxy>>,
 
+    /// Named proxy group the proxy rules route to, Format: name=type, type as in --proxies without the proto, or direct
+    /// e.g. residential=socks5://192.168.1.1:1080,http://192.168.1.2:8080
+    #[clap(long, value_parser = parse::parse_proxy_group, verbatim_doc_comment)]
+    #[serde(default)]
+    pub(super) proxy_group: Vec<proxy::ProxyGroup>,
+
+    /// Proxy rules, the first matching rule picks the proxy group of a request, separate multiple ones with ","
+    /// Format: host:<glob>=group, path:<prefix>=group or model:<glob>=group
+    /// The group direct connects directly, default keeps the pool of the request type
+    /// e.g. host:files.oaiusercontent.com=direct,host:tcr9i.chat.openai.com=residential
+    #[clap(long, value_delimiter = ',', verbatim_doc_comment)]
+    #[serde(default)]
+    pub(super) proxy_rule: Vec<String>,
+
     /// Enable direct connection
     #[clap(long, env = "ENABLE_DIRECT")]
     pub(super) enable_direct: bool,
@@ -125,6 +140,48 @@ pub struct ServeArgs {
     #[clap(short = 'I',long, env = "IMPERSONATE_UA", value_parser = parse::parse_impersonate_uas, verbatim_doc_comment)]
     pub(super) impersonate_uas: Option<std::vec::Vec<String>>,
 
+    /// Client pool selection strategy (round-robin/weighted/least-in-flight/latency)
+    #[clap(long, default_value = "round-robin")]
+    pub(super) proxy_strategy: Option<String>,
+
+    /// Client pool weights, separate multiple ones with ",", Format: proxy=weight
+    /// The proxy is a proxy url, an interface address or direct, the default weight is 1
+    /// e.g. socks5://192.168.1.1:1080=3,192.168.1.2=1
+    #[clap(long, value_delimiter = ',', verbatim_doc_comment)]
+    #[serde(default)]
+    pub(super) proxy_weight: Vec<String>,
+
+    /// Url the client pool clients are probed with in the background
+    #[clap(long)]
+    pub(super) proxy_probe_url: Option<String>,
+
+    /// Seconds between the background probes of the client pool
+    #[clap(long, default_value = "60", requires = "proxy_probe_url")]
+    pub(super) proxy_probe_interval: Option<u64>,
+
+    /// Pin each account to a pool client, so the account keeps its proxy,
+    /// impersonation profile and IPv6 across requests and restarts
+    #[clap(long)]
+    #[serde(default)]
+    pub(super) proxy_affinity: bool,
+
+    /// Retries of idempotent and conversation requests through the next pool client,
+    /// on connect errors, Cloudflare challenge pages and 5xx responses
+    #[clap(long, env = "RETRY", default_value = "0")]
+    #[serde(default)]
+    pub(super) retry: usize,
+
+    /// Consecutive connect errors or Cloudflare challenges after which a pool client
+    /// is taken out of rotation until a background probe succeeds (0 disables it)
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(super) breaker_threshold: u32,
+
+    /// Seconds between the probes of a pool client taken out of rotation
+    #[clap(long, default_value = "30")]
+    #[serde(default)]
+    pub(super) breaker_probe_interval: u64,
+
     /// Enabled Cookie Store
     #[clap(long, env = "COOKIE_STORE")]
     pub(super) cookie_store: bool,
@@ -133,6 +190,45 @@ pub struct ServeArgs {
     #[clap(long, env = "FASTEST_DNS")]
     pub(super) fastest_dns: bool,
 
+    /// Seconds between the fastest DNS re-benchmarks, benchmark once at startup if unset
+    #[clap(long, requires = "fastest_dns")]
+    pub(super) fastest_dns_interval: Option<u64>,
+
+    /// Upstream DNS servers, separate multiple ones with ","
+    /// Format: [udp|tcp|tls|https]://ip[:port][#server name], a bare ip is plain udp
+    /// e.g. tls://1.1.1.1#cloudflare-dns.com,https://8.8.8.8#dns.google
+    #[clap(long, value_delimiter = ',', verbatim_doc_comment)]
+    #[serde(default)]
+    pub(super) dns: Vec<String>,
+
+    /// Static DNS host overrides, separate multiple ones with ",", Format: host=ip
+    /// e.g. chat.openai.com=104.18.37.228,*.oaiusercontent.com=104.18.32.47
+    #[clap(long, value_delimiter = ',', verbatim_doc_comment)]
+    #[serde(default)]
+    pub(super) dns_host: Vec<String>,
+
+    /// DNS servers of the host patterns, the first matching rule wins, Format: host=server
+    /// e.g. *.openai.com=tls://1.1.1.1#cloudflare-dns.com
+    #[clap(long, value_delimiter = ',', verbatim_doc_comment)]
+    #[serde(default)]
+    pub(super) dns_rule: Vec<String>,
+
+    /// DNS cache size
+    #[clap(long)]
+    pub(super) dns_cache_size: Option<usize>,
+
+    /// DNS cache minimum ttl (seconds)
+    #[clap(long)]
+    pub(super) dns_min_ttl: Option<u64>,
+
+    /// DNS cache maximum ttl (seconds)
+    #[clap(long)]
+    pub(super) dns_max_ttl: Option<u64>,
+
+    /// DNS cache ttl of failed lookups (seconds)
+    #[clap(long)]
+    pub(super) dns_negative_ttl: Option<u64>,
+
     /// TLS certificate file path
     #[clap(long, env = "TLS_CERT", requires = "tls_key")]
     pub(super) tls_cert: Option<PathBuf>,
@@ -149,6 +245,20 @@ pub struct ServeArgs {
     #[clap(long, env = "CF_SITE_KEY", requires = "cf_site_key")]
     pub(super) cf_secret_key: Option<String>,
 
+    /// Cloudflare challenge solver endpoint speaking the FlareSolverr api, the challenged
+    /// ChatGPT requests get a cf_clearance from it and are sent again
+    /// e.g. http://127.0.0.1:8191/v1
+    #[clap(long, value_parser = parse::parse_url, verbatim_doc_comment)]
+    pub(super) cf_solver_endpoint: Option<String>,
+
+    /// Cloudflare challenge solver timeout (seconds), default: 60
+    #[clap(long, requires = "cf_solver_endpoint")]
+    pub(super) cf_solver_timeout: Option<u64>,
+
+    /// Cloudflare clearance cache expiration (seconds), default: 1800
+    #[clap(long, requires = "cf_solver_endpoint")]
+    pub(super) cf_clearance_ttl: Option<u64>,
+
     /// Login/Arkose/HAR Authentication Key
     #[clap(short = 'A', long, env = "AUTH_KEY")]
     pub(super) auth_key: Option<String>,
@@ -214,16 +324,101 @@ pub struct ServeArgs {
     #[clap(long, value_parser = parse::parse_dir_path)]
     pub(super) arkose_solver_image_dir: Option<PathBuf>,
 
+    /// Enable conversation reuse, continue the ChatGPT conversation of a known
+    /// message history instead of replaying it (chat completions API with access token),
+    /// requires --conv-history
+    #[clap(long, env = "CONV_REUSE", requires = "conv_history")]
+    #[serde(default)]
+    pub(super) conv_reuse: bool,
+
+    /// Keep the chat completions API conversations in the ChatGPT history, which also
+    /// allows OpenAI to train on them. Conversations can only be reused with history kept
+    #[clap(long, env = "CONV_HISTORY")]
+    #[serde(default)]
+    pub(super) conv_history: bool,
+
+    /// Conversation reuse store strategy (mem/redb)
+    #[clap(long, default_value = "mem", requires = "conv_reuse")]
+    #[serde(default = "default_conv_store")]
+    pub(super) conv_store: String,
+
+    /// Conversation reuse expired (seconds)
+    #[clap(long, default_value = "3600", requires = "conv_reuse")]
+    #[serde(default = "default_conv_ttl")]
+    pub(super) conv_ttl: u32,
+
+    /// Enable the ChatGPT account pool, requests authenticated with the auth key
+    /// are dispatched to a pool account (manage accounts with /admin/accounts)
+    #[clap(long, env = "POOL_ENABLE", requires = "auth_key")]
+    #[serde(default)]
+    pub(super) pool_enable: bool,
+
+    /// Account pool selection strategy (round-robin/least-used)
+    #[clap(long, default_value = "round-robin", requires = "pool_enable")]
+    #[serde(default = "default_pool_strategy")]
+    pub(super) pool_strategy: String,
+
+    /// Account pool quarantine cool-down after a 401 or 429 (seconds)
+    #[clap(long, default_value = "300", requires = "pool_enable")]
+    #[serde(default = "default_pool_cooldown")]
+    pub(super) pool_cooldown: u32,
+
+    /// Enable the GPT-4 message cap, messages are counted per account over the
+    /// window reported by ChatGPT and capped messages are rejected locally
+    #[clap(long, env = "GPT4_CAP")]
+    #[serde(default)]
+    pub(super) gpt4_cap: bool,
+
+    /// Seconds a capped message may wait for a free slot before it is rejected
+    #[clap(long, default_value = "0", requires = "gpt4_cap")]
+    #[serde(default)]
+    pub(super) gpt4_cap_wait: u32,
+
+    /// Send capped GPT-4 messages with GPT-3.5 instead of rejecting them
+    #[clap(long, requires = "gpt4_cap")]
+    #[serde(default)]
+    pub(super) gpt4_cap_downgrade: bool,
+
+    /// Write a JSON access log line per request, the path defaults to next to the daemon log
+    #[clap(
+        long,
+        env = "ACCESS_LOG",
+        num_args = 0..=1,
+        default_missing_value = "/var/run/ninja.access.log"
+    )]
+    #[serde(default)]
+    pub(super) access_log: Option<PathBuf>,
+
+    /// Access log size before it is rotated (MiB)
+    #[clap(long, default_value = "100", requires = "access_log")]
+    #[serde(default = "default_access_log_max_size")]
+    pub(super) access_log_max_size: u64,
+
+    /// Rotated access log files to keep
+    #[clap(long, default_value = "5", requires = "access_log")]
+    #[serde(default = "default_access_log_keep")]
+    pub(super) access_log_keep: usize,
+
+    /// Write the HMAC-SHA256 of the account email, keyed with a per-install secret, to the access log instead of the email
+    #[clap(long, requires = "access_log")]
+    #[serde(default)]
+    pub(super) access_log_hash_email: bool,
+
     /// Enable token bucket flow limitation
     #[clap(short = 'T', long)]
     #[cfg(feature = "limit")]
     pub(super) tb_enable: bool,
 
-    /// Token bucket store strategy (mem/redb)
+    /// Token bucket store strategy (mem/redb/redis)
     #[clap(long, default_value = "mem", requires = "tb_enable")]
     #[cfg(feature = "limit")]
     pub(super) tb_strategy: String,
 
+    /// Token bucket redis url, required by the redis strategy, e.g. redis://127.0.0.1:6379
+    #[clap(long, env = "TB_REDIS_URL", requires = "tb_enable")]
+    #[cfg(feature = "limit")]
+    pub(super) tb_redis_url: Option<String>,
+
     /// Token bucket capacity
     #[clap(long, default_value = "60", requires = "tb_enable")]
     #[cfg(feature = "limit")]
@@ -235,10 +430,40 @@ pub struct ServeArgs {
     pub(super) tb_fill_rate: u32,
 
     /// Token bucket expired (seconds)
-    #[clap(long, default_value = "86400", requires = "tb_enable")]
+    #[clap(long, default_value = "86400", requires = "tb_enable", value_parser = clap::value_parser!(u32).range(1..))]
     #[cfg(feature = "limit")]
     pub(super) tb_expired: u32,
 
+    /// Token bucket limit keys, every key has its own bucket (ip/email/bearer/header:<name>)
+    #[clap(
+        long,
+        default_value = "ip",
+        value_delimiter = ',',
+        requires = "tb_enable"
+    )]
+    #[cfg(feature = "limit")]
+    #[serde(default)]
+    pub(super) tb_key: Vec<String>,
+
+    /// Token bucket limit keys of a path prefix, overrides --tb-key, e.g. /v1=bearer,ip
+    #[clap(long, requires = "tb_enable")]
+    #[cfg(feature = "limit")]
+    #[serde(default)]
+    pub(super) tb_route: Vec<String>,
+
+    /// Token bucket trusted proxies (CIDR), X-Forwarded-For and X-Real-IP are only read from them
+    #[clap(long, value_delimiter = ',', requires = "tb_enable")]
+    #[cfg(feature = "limit")]
+    #[serde(default)]
+    pub(super) tb_trusted_proxy: Vec<cidr::IpCidr>,
+
+    /// Token bucket of each IPv6 derived from an IPv6 subnet proxy for an account or api key,
+    /// the requests of an account are limited by the address they are sent from
+    #[clap(long, requires = "tb_enable")]
+    #[cfg(feature = "limit")]
+    #[serde(default)]
+    pub(super) tb_egress: bool,
+
     /// Preauth MITM server bind address
     #[clap(
     short = 'B',
@@ -267,4 +492,38 @@ pub struct ServeArgs {
     /// Preauth MITM server CA private key file path
     #[clap(long, default_value = "ca/key.pem", requires = "pbind")]
     pub(super) pkey: PathBuf,
+
+    /// Arkose types /readyz requires HAR files for, e.g. gpt4,auth
+    #[clap(long, value_delimiter = ',', value_parser = parse::parse_arkose_type)]
+    #[serde(default)]
+    pub(super) readyz_har: Vec<String>,
+
+    /// Upstream backends of the `/v1` API routed by model name (configuration file only)
+    #[clap(skip)]
+    #[serde(default)]
+    pub(super) upstreams: Vec<upstream::Upstream>,
+}
+
+fn default_conv_store() -> String {
+    "mem".to_owned()
+}
+
+fn default_conv_ttl() -> u32 {
+    3600
+}
+
+fn default_pool_strategy() -> String {
+    "round-robin".to_owned()
+}
+
+fn default_pool_cooldown() -> u32 {
+    300
+}
+
+fn default_access_log_max_size() -> u64 {
+    100
+}
+
+fn default_access_log_keep() -> usize {
+    5
 }
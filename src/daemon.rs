@@ -2,12 +2,24 @@
 use crate::utils;
 use crate::{
     args::{self, ServeArgs},
+    parse,
     utils::unix::fix_relative_path,
 };
 use clap::CommandFactory;
-use openai::{arkose::funcaptcha::solver::ArkoseSolver, context::args::Args, proxy, serve::Serve};
+use openai::{
+    arkose::funcaptcha::solver::ArkoseSolver,
+    context::{args::Args, clearance::HttpSolver},
+    proxy,
+    serve::Serve,
+    upstream,
+};
 use reqwest::impersonate::Impersonate;
-use std::{net::IpAddr, ops::Not, path::PathBuf, str::FromStr};
+use std::{
+    net::IpAddr,
+    ops::Not,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use url::Url;
 
 pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<()> {
@@ -15,10 +27,52 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         fix_relative_path(&mut args);
     }
 
-    if let Some(config_path) = args.config {
-        let bytes = std::fs::read(config_path)?;
-        let data = String::from_utf8(bytes)?;
-        args = toml::from_str::<ServeArgs>(&data)?;
+    let config_path = args.config.clone();
+    if let Some(config_path) = config_path.as_ref() {
+        args = read_config(config_path)?;
+    }
+
+    // Set the log level
+    std::env::set_var("RUST_LOG", &args.level);
+
+    let args = match build_args(args) {
+        Ok(args) => args,
+        Err(err) => {
+            let mut cmd = args::cmd::Opt::command();
+            cmd.error(clap::error::ErrorKind::ArgumentConflict, err)
+                .exit();
+        }
+    };
+
+    // Watch the config file for reloads
+    match config_path {
+        Some(config_path) => Serve::new(args).config(config_path, load_config).run(),
+        None => Serve::new(args).run(),
+    }
+}
+
+fn read_config(config_path: &Path) -> anyhow::Result<ServeArgs> {
+    let bytes = std::fs::read(config_path)?;
+    let data = String::from_utf8(bytes)?;
+    Ok(toml::from_str::<ServeArgs>(&data)?)
+}
+
+/// Reload the serve args from the config file
+fn load_config(config_path: &Path) -> anyhow::Result<Args> {
+    let mut args = read_config(config_path)?;
+    // The daemon runs in its work directory, relative paths are fixed like at startup
+    fix_relative_path(&mut args);
+    build_args(args)
+}
+
+fn build_args(args: ServeArgs) -> anyhow::Result<Args> {
+    for upstream in &args.upstreams {
+        upstream.validate()?;
+    }
+
+    // The configuration file bypasses the command line parser
+    for typed in &args.readyz_har {
+        parse::parse_arkose_type(typed)?;
     }
 
     let arkose_solver = match args.arkose_solver_key.as_ref() {
@@ -31,6 +85,14 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         None => None,
     };
 
+    let cf_solver = match args.cf_solver_endpoint.as_ref() {
+        Some(endpoint) => Some(HttpSolver::new(
+            endpoint.clone(),
+            args.cf_solver_timeout.unwrap_or(60),
+        )?),
+        None => None,
+    };
+
     #[cfg(target_os = "linux")]
     if let Some(ref proxies) = args.proxies {
         proxies.iter().for_each(|p| {
@@ -48,14 +110,32 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         });
     }
 
-    // Set the log level
-    std::env::set_var("RUST_LOG", args.level);
-
     let builder = Args::builder()
         .bind(args.bind)
         .fastest_dns(args.fastest_dns)
+        .fastest_dns_interval(args.fastest_dns_interval)
+        .dns_servers(args.dns)
+        .dns_hosts(args.dns_host)
+        .dns_rules(args.dns_rule)
+        .dns_cache_size(args.dns_cache_size)
+        .dns_min_ttl(args.dns_min_ttl)
+        .dns_max_ttl(args.dns_max_ttl)
+        .dns_negative_ttl(args.dns_negative_ttl)
         .proxies(args.proxies.unwrap_or_default())
         .enable_direct(args.enable_direct)
+        .proxy_strategy(
+            args.proxy_strategy
+                .unwrap_or_else(|| "round-robin".to_owned()),
+        )
+        .proxy_weights(args.proxy_weight)
+        .proxy_probe_url(args.proxy_probe_url)
+        .proxy_probe_interval(args.proxy_probe_interval.unwrap_or(60))
+        .proxy_affinity(args.proxy_affinity)
+        .proxy_groups(args.proxy_group)
+        .proxy_rules(args.proxy_rule)
+        .retry(args.retry)
+        .breaker_threshold(args.breaker_threshold)
+        .breaker_probe_interval(args.breaker_probe_interval)
         .cookie_store(args.cookie_store)
         .tcp_keepalive(args.tcp_keepalive)
         .no_keepalive(args.no_keepalive)
@@ -69,6 +149,10 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         .visitor_email_whitelist(args.visitor_email_whitelist)
         .cf_site_key(args.cf_site_key)
         .cf_secret_key(args.cf_secret_key)
+        .cf_solver_endpoint(args.cf_solver_endpoint)
+        .cf_solver_timeout(args.cf_solver_timeout)
+        .cf_solver(cf_solver)
+        .cf_clearance_ttl(args.cf_clearance_ttl)
         .enable_webui(args.enable_webui)
         .arkose_endpoint(args.arkose_endpoint)
         .arkose_gpt3_experiment(args.arkose_gpt3_experiment)
@@ -78,6 +162,22 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
         .arkose_solver_image_dir(args.arkose_solver_image_dir)
         .enable_file_proxy(args.enable_file_proxy)
         .enable_arkose_proxy(args.enable_arkose_proxy)
+        .conv_reuse(args.conv_reuse)
+        .conv_history(args.conv_history)
+        .conv_store(args.conv_store)
+        .conv_ttl(args.conv_ttl)
+        .pool_enable(args.pool_enable)
+        .pool_strategy(args.pool_strategy)
+        .pool_cooldown(args.pool_cooldown)
+        .gpt4_cap(args.gpt4_cap)
+        .gpt4_cap_wait(args.gpt4_cap_wait)
+        .gpt4_cap_downgrade(args.gpt4_cap_downgrade)
+        .access_log(args.access_log)
+        .access_log_max_size(args.access_log_max_size)
+        .access_log_keep(args.access_log_keep)
+        .access_log_hash_email(args.access_log_hash_email)
+        .readyz_har(args.readyz_har)
+        .upstreams(args.upstreams)
         .pbind(args.pbind)
         .pupstream(args.pupstream)
         .pcert(args.pcert)
@@ -87,9 +187,14 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
     let builder = builder
         .tb_enable(args.tb_enable)
         .tb_strategy(args.tb_strategy)
+        .tb_redis_url(args.tb_redis_url)
         .tb_capacity(args.tb_capacity)
         .tb_fill_rate(args.tb_fill_rate)
-        .tb_expired(args.tb_expired);
+        .tb_expired(args.tb_expired)
+        .tb_keys(args.tb_key)
+        .tb_routes(args.tb_route)
+        .tb_trusted_proxies(args.tb_trusted_proxy)
+        .tb_egress(args.tb_egress);
 
     // Parse the impersonate user agents
     if let Some(impersonate_list) = args.impersonate_uas {
@@ -100,20 +205,14 @@ pub(super) fn serve(mut args: ServeArgs, relative_path: bool) -> anyhow::Result<
                     impersonate_uas.push(impersonate);
                 }
                 Err(_) => {
-                    let mut cmd = args::cmd::Opt::command();
-                    cmd.error(
-                        clap::error::ErrorKind::ArgumentConflict,
-                        &format!("Unsupport impersonate user agent: {}", ua),
-                    )
-                    .exit();
+                    anyhow::bail!("Unsupport impersonate user agent: {}", ua)
                 }
             }
         }
 
-        let args = builder.impersonate_uas(impersonate_uas).build();
-        Serve::new(args).run()
+        Ok(builder.impersonate_uas(impersonate_uas).build())
     } else {
-        Serve::new(builder.build()).run()
+        Ok(builder.build())
     }
 }
 
@@ -278,12 +377,33 @@ pub(super) fn generate_template(out: Option<PathBuf>) -> anyhow::Result<()> {
         timeout: 600,
         connect_timeout: 60,
         tcp_keepalive: 60,
+        proxy_strategy: Some("round-robin".to_owned()),
+        proxy_probe_interval: Some(60),
+        retry: 2,
+        breaker_threshold: 5,
+        breaker_probe_interval: 30,
+        cf_solver_timeout: Some(60),
+        cf_clearance_ttl: Some(1800),
+        conv_store: "mem".to_string(),
+        conv_ttl: 3600,
+        pool_strategy: "round-robin".to_string(),
+        pool_cooldown: 300,
+        access_log_max_size: 100,
+        access_log_keep: 5,
         tb_strategy: "mem".to_string(),
         tb_enable: false,
         tb_capacity: 60,
         tb_fill_rate: 1,
         tb_expired: 86400,
+        tb_key: vec!["ip".to_owned()],
+        tb_route: vec!["/v1=bearer,ip".to_owned()],
+        tb_trusted_proxy: vec![cidr::IpCidr::from_str("127.0.0.1/32")?],
         cookie_store: true,
+        dns: vec![
+            "tls://1.1.1.1#cloudflare-dns.com".to_owned(),
+            "https://8.8.8.8#dns.google".to_owned(),
+        ],
+        dns_negative_ttl: Some(30),
         pool_idle_timeout: 90,
         arkose_solver_limit: 3,
         level: "info".to_owned(),
@@ -292,11 +412,38 @@ pub(super) fn generate_template(out: Option<PathBuf>) -> anyhow::Result<()> {
         arkose_gpt3_experiment: false,
         enable_file_proxy: false,
         proxies: Some(vec![
-            proxy::Proxy::try_from(("all", "socks5://127.0.0.1:8888".parse::<Url>()?))?,
-            proxy::Proxy::try_from(("all", "http://127.0.0.1:8889".parse::<Url>()?))?,
-            proxy::Proxy::try_from(("api", "192.168.1.1".parse::<IpAddr>()?))?,
-            proxy::Proxy::try_from(("api", cidr::Ipv6Cidr::from_str("2001:db8::/32")?))?,
+            proxy::Proxy::try_from(("api", "socks5://127.0.0.1:8888".parse::<Url>()?))?,
+            proxy::Proxy::try_from(("arkose", "http://127.0.0.1:8889".parse::<Url>()?))?,
+            proxy::Proxy::try_from(("auth", "192.168.1.1".parse::<IpAddr>()?))?,
+            proxy::Proxy::try_from(("auth", cidr::Ipv6Cidr::from_str("2001:db8::/32")?))?,
         ]),
+        proxy_group: vec![proxy::ProxyGroup {
+            name: "residential".to_owned(),
+            proxies: vec![proxy::InnerProxy::Proxy(
+                "socks5://127.0.0.1:1080".parse::<Url>()?,
+            )],
+            direct: false,
+        }],
+        proxy_rule: vec![
+            "host:files.oaiusercontent.com=direct".to_owned(),
+            "host:*.arkoselabs.com=residential".to_owned(),
+        ],
+        upstreams: vec![
+            upstream::Upstream {
+                models: vec!["gpt-4*".to_owned()],
+                base_url: "https://example.openai.azure.com/openai/deployments/gpt-4".parse()?,
+                auth: upstream::AuthStyle::ApiKey,
+                api_key: Some("azure-api-key".to_owned()),
+                api_version: Some("2023-05-15".to_owned()),
+            },
+            upstream::Upstream {
+                models: vec!["llama-*".to_owned(), "mistral-*".to_owned()],
+                base_url: "http://127.0.0.1:8000/v1".parse()?,
+                auth: upstream::AuthStyle::None,
+                api_key: None,
+                api_version: None,
+            },
+        ],
         ..args::ServeArgs::default()
     };
 
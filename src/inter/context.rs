@@ -50,8 +50,7 @@ impl Context {
             .tcp_keepalive(conf.tcp_keepalive)
             .proxies(proxies)
             .build();
-        openai::context::init(args);
-        Ok(())
+        openai::context::init(args)
     }
 
     // Get current context user
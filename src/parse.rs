@@ -12,6 +12,12 @@ pub fn parse_socket_addr(s: &str) -> anyhow::Result<std::net::SocketAddr> {
     Ok(addr)
 }
 
+// arkose type parse, support type: gpt3/gpt4/auth/platform
+pub fn parse_arkose_type(s: &str) -> anyhow::Result<String> {
+    openai::arkose::Type::from_str(s)?;
+    Ok(s.to_lowercase())
+}
+
 // url parse
 pub fn parse_url(s: &str) -> anyhow::Result<String> {
     let url = url::Url::parse(s)
@@ -50,6 +56,36 @@ pub fn parse_proxies_url(s: &str) -> anyhow::Result<Vec<proxy::Proxy>> {
     Ok(proxies)
 }
 
+/// parse proxy group, format: name=type, type is a proxy of the `-x` format without the proto, or direct
+pub fn parse_proxy_group(s: &str) -> anyhow::Result<proxy::ProxyGroup> {
+    let (name, members) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Invalid proxy group format: {}", s))?;
+    let name = name.trim();
+    if name.is_empty() || name.eq("default") {
+        anyhow::bail!("Invalid proxy group name: {}", name)
+    }
+
+    let (direct, members): (Vec<_>, Vec<_>) = members
+        .split(',')
+        .map(str::trim)
+        .filter(|m| !m.is_empty())
+        .partition(|m| m.eq(&"direct"));
+    let proxies = match members.is_empty() {
+        true => vec![],
+        false => parse_proxies_url(&members.join(","))?,
+    };
+    if proxies.iter().any(|p| !matches!(p, proxy::Proxy::All(_))) {
+        anyhow::bail!("Proxy group {} members take no proto: {}", name, s)
+    }
+
+    Ok(proxy::ProxyGroup {
+        name: name.to_owned(),
+        proxies: proxies.iter().map(|p| p.inner().clone()).collect(),
+        direct: !direct.is_empty(),
+    })
+}
+
 /// parse file path
 pub fn parse_file_path(s: &str) -> anyhow::Result<PathBuf> {
     let path =